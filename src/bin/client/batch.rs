@@ -0,0 +1,198 @@
+// Using the client from scripts and pipes ("batch mode").
+//
+//   client --send "deploy finished"                   send one line
+//   make test 2>&1 | client --room '#builds' --stdin  send every line piped in
+//   client --listen --until count:1                   wait for the next message
+//   client --room '#ops' --send "who's on call?" --listen --until idle:30
+//
+// Nobody is watching the screen, so the client can't simply quit when its
+// input ends: the last lines may still be on their way, and the server may
+// still answer one of them with an error. So once everything is sent we send
+// `/ping`, and we only stop when the PONG comes back - the server handles a
+// client's lines in order, so by then it has handled every one of them.
+//
+// Errors from the server are printed to stderr, and if there were any the
+// client exits with status 1. That way a script can check whether it worked.
+//
+// `--listen` starts printing once the PONG is in (so our own lines aren't
+// printed back to us). Every event becomes one JSON object per line:
+//
+//   {"type":"msg","id":17,"time":"2026-10-18T09:41:07Z","room":"#general","sender":"alice",...}
+//   {"type":"join","nick":"bob","room":"#general"}
+
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedWriteHalf;
+
+use chatty_rusty::protocol::Frame;
+
+use crate::config::Config;
+use crate::log::Log;
+
+// The token we send with `/ping`, so we recognise our PONG.
+const PING_TOKEN: &str = "batch-flush";
+
+// Send what the options ask for, listen if asked to, and return the exit
+// status: 0 if all went well, 1 if it didn't.
+pub async fn run(config: Config, socket: TcpStream) -> i32 {
+    let (reader, mut writer) = socket.into_split();
+    let mut reader = BufReader::new(reader);
+
+    // Everything to send before the piped lines.
+    let mut lines = Vec::new();
+    if let Some(nick) = &config.nick {
+        lines.push(format!("/nick {}", nick));
+    }
+    if let Some(room) = &config.room {
+        lines.push(format!("/join {}", room));
+    }
+    lines.extend(config.send.iter().cloned());
+    let stdin = config.stdin;
+    // `--log` keeps a transcript here too.
+    let mut log = config.log.as_ref().map(|dir| Log::new(dir, config.log_format));
+
+    // Sending gets a task of its own, so we keep reading what the server says
+    // while a long pipe is still being sent. The task hands the writer back
+    // when it's done: dropping it would close the connection.
+    let mut send_task = tokio::spawn(async move {
+        let result = send_all(&mut writer, lines, stdin).await;
+        if let Err(e) = &result {
+            eprintln!("error: {}", e);
+        }
+        // Ping even if something went wrong, so the loop below isn't left
+        // waiting. If the connection itself broke, it sees that instead.
+        let _ = writer
+            .write_all(format!("/ping {}\n", PING_TOKEN).as_bytes())
+            .await;
+        (writer, result.is_ok())
+    });
+    // Where the writer is kept once the task is done, for `--listen`.
+    let mut _writer = None;
+
+    // The shortest `idle:` condition, if there is one.
+    let idle = config.until.iter().filter_map(|until| until.idle()).min();
+    let mut errors = 0;
+    let mut flushed = false;
+    let mut messages = 0;
+    let status = |errors| if errors > 0 { 1 } else { 0 };
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line);
+        let result = match idle {
+            Some(idle) if flushed => match tokio::time::timeout(idle, read).await {
+                Ok(result) => result,
+                // Quiet for long enough.
+                Err(_) => return status(errors),
+            },
+            _ => read.await,
+        };
+        if !matches!(result, Ok(n) if n > 0) {
+            // Listening until the server goes away is fine; anything else
+            // means we stopped before we were done.
+            if flushed && config.listen && config.until.is_empty() {
+                return status(errors);
+            }
+            eprintln!("error: the server disconnected");
+            return 1;
+        }
+
+        let Some(frame) = Frame::parse(&line) else {
+            continue;
+        };
+        if let Some(log) = &mut log {
+            log.record(&frame).await;
+        }
+        match frame {
+            Frame::Pong(token) if token == PING_TOKEN && !flushed => {
+                flushed = true;
+                // The task sent the ping last, so it's finished or about to.
+                if let Ok((writer, ok)) = (&mut send_task).await {
+                    _writer = Some(writer);
+                    if !ok {
+                        errors += 1;
+                    }
+                }
+                if !config.listen {
+                    return status(errors);
+                }
+            }
+            Frame::Error(text) if !flushed => {
+                eprintln!("error: {}", text);
+                errors += 1;
+            }
+            frame if flushed && config.listen => {
+                if let Some(json) = to_json(&frame) {
+                    println!("{}", json);
+                }
+                if let Frame::Msg(msg) = &frame {
+                    messages += 1;
+                    if config.until.iter().any(|until| until.met(msg, messages)) {
+                        return status(errors);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Send `lines`, then every line piped in if `stdin` is set.
+async fn send_all(
+    writer: &mut OwnedWriteHalf,
+    lines: Vec<String>,
+    stdin: bool,
+) -> std::io::Result<()> {
+    for line in lines {
+        writer.write_all(format!("{}\n", line).as_bytes()).await?;
+    }
+    if stdin {
+        // `lines()` reads one line at a time, without the line ending.
+        let mut input = BufReader::new(tokio::io::stdin()).lines();
+        while let Some(line) = input.next_line().await? {
+            writer.write_all(format!("{}\n", line).as_bytes()).await?;
+        }
+    }
+    Ok(())
+}
+
+// The JSON we print for a frame with `--listen`. File transfers and the
+// frames only the interactive client uses give None.
+// `log.rs` writes the same objects with `--log-format json`.
+pub fn to_json(frame: &Frame) -> Option<Value> {
+    Some(match frame {
+        Frame::Msg(msg) => json!({
+            "type": "msg",
+            "id": msg.id,
+            "time": msg.time,
+            "room": msg.room,
+            "sender": msg.sender,
+            "origin": msg.origin,
+            "text": msg.text,
+            "reply_to": msg.reply_to,
+            "edited": msg.edited,
+            "mentions": msg.mentions,
+        }),
+        Frame::Edit { id, text } => json!({ "type": "edit", "id": id, "text": text }),
+        Frame::Delete { id } => json!({ "type": "delete", "id": id }),
+        Frame::React { id, counts } => {
+            let counts: serde_json::Map<String, Value> = counts
+                .iter()
+                .map(|(emoji, count)| (emoji.clone(), json!(count)))
+                .collect();
+            json!({ "type": "react", "id": id, "counts": counts })
+        }
+        Frame::Join { nick, room } => json!({ "type": "join", "nick": nick, "room": room }),
+        Frame::Part { nick, room, reason } => {
+            json!({ "type": "part", "nick": nick, "room": room, "reason": reason })
+        }
+        Frame::Nick { old, new } => json!({ "type": "nick", "old": old, "new": new }),
+        Frame::Private { time, from, to, text } => {
+            json!({ "type": "private", "time": time, "from": from, "to": to, "text": text })
+        }
+        Frame::Info(text) => json!({ "type": "info", "text": text }),
+        Frame::Error(text) => json!({ "type": "error", "text": text }),
+        _ => return None,
+    })
+}
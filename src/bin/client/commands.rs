@@ -0,0 +1,161 @@
+// The slash commands the user types.
+//
+// A few are about the client itself - `/quit`, `/clear`, `/connect` - and
+// never reach the server. `/msg` to a nick is encrypted first (see `e2e.rs`). The rest are the server's: we check they have
+// what they need (so `/join` on its own gets a usage line straight away)
+// and send them on as typed. A command we don't know is sent on too, since
+// the server's bots can add commands of their own (`/remind`, ...).
+//
+// `/help` lists the client's commands, then asks the server for its list.
+
+// One command we know about.
+// - `args`: what it takes; required arguments are in `<>`, optional in `[]`
+// - `help`: one line about it, for the client's own commands
+// - `local`: true if the client handles it itself
+pub struct Command {
+    pub name: &'static str,
+    pub args: &'static str,
+    pub help: &'static str,
+    pub local: bool,
+}
+
+// Every command we know, for `/help`, usage lines and Tab completion.
+// The server's commands are described by the server's own `/help`.
+pub const COMMANDS: &[Command] = &[
+    local("/help", "", "show this list and the server's"),
+    local("/quit", "", "leave (Ctrl+D works too)"),
+    local("/clear", "", "clear the screen"),
+    local("/connect", "<address>", "switch to another server"),
+    local("/threads", "<collapsed|expanded>", "how replies are shown"),
+    local("/send", "<nick|#room> <path>", "offer someone a file"),
+    local("/msg", "<#room|nick> <text>", "talk in a room, or privately (encrypted)"),
+    local("/plain", "<nick> <text>", "a private message without encryption"),
+    local("/verify", "<nick>", "compare fingerprints, to check a key"),
+    local("/trust", "<nick>", "accept someone's new key"),
+    server("/nick", "<name> [password]"),
+    server("/register", "<password>"),
+    server("/join", "<#room> [password]"),
+    server("/part", "[#room]"),
+    server("/topic", "<#room> [text|-]"),
+    server("/mode", "<#room> [+|-][i|k|m|t|o|v|b] [password|nick]"),
+    server("/invite", "<nick> <#room>"),
+    server("/rooms", ""),
+    server("/unread", ""),
+    server("/read", "<#room> <id>"),
+    server("/since", "<id>"),
+    server("/edit", "<id> <text>"),
+    server("/delete", "<id>"),
+    server("/react", "<id> <emoji>"),
+    server("/reply", "<id> <text>"),
+    server("/thread", "<id>"),
+    server("/mentions", ""),
+    server("/search", "<words> [#room] [from:nick] [since:date] [page:N]"),
+    server("/accept", "<id>"),
+    server("/decline", "<id>"),
+    server("/motd", "[reload]"),
+    server("/oper", "<password>"),
+    server("/reload", ""),
+    server("/restart", ""),
+    server("/ping", "[token]"),
+];
+
+const fn local(name: &'static str, args: &'static str, help: &'static str) -> Command {
+    Command {
+        name,
+        args,
+        help,
+        local: true,
+    }
+}
+
+const fn server(name: &'static str, args: &'static str) -> Command {
+    Command {
+        name,
+        args,
+        help: "",
+        local: false,
+    }
+}
+
+// What to do with a line the user typed.
+pub enum Action {
+    Quit,
+    Help,
+    Clear,
+    Connect(String),
+    // `/threads collapsed` is `Threads(true)`.
+    Threads(bool),
+    SendFile { target: String, path: String },
+    // `/msg <nick> <text>`, to be encrypted.
+    Private { to: String, text: String },
+    Verify(String),
+    Trust(String),
+    // Send this line to the server: chat text or one of its commands.
+    Server(String),
+    // The command was used wrong - show this.
+    Usage(String),
+}
+
+pub fn parse(line: &str) -> Action {
+    let line = line.trim_end();
+    if !line.starts_with('/') {
+        return Action::Server(line.to_string());
+    }
+    // Split the command name from its arguments, e.g. "/join #rust" -> ("/join", "#rust").
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    let args = args.trim();
+    let wrong = || Action::Usage(usage(name));
+    match name {
+        "/quit" | "/exit" => Action::Quit,
+        "/help" => Action::Help,
+        "/clear" => Action::Clear,
+        "/connect" if !args.is_empty() && !args.contains(' ') => Action::Connect(args.to_string()),
+        "/threads" if args == "collapsed" => Action::Threads(true),
+        "/threads" if args == "expanded" => Action::Threads(false),
+        "/send" => match args.split_once(' ') {
+            Some((target, path)) => Action::SendFile {
+                target: target.to_string(),
+                path: path.trim().to_string(),
+            },
+            None => wrong(),
+        },
+        "/msg" => match args.split_once(' ') {
+            Some((room, _)) if room.starts_with('#') => Action::Server(line.to_string()),
+            Some((to, text)) => Action::Private {
+                to: to.to_string(),
+                text: text.trim().to_string(),
+            },
+            None => wrong(),
+        },
+        "/plain" => match args.split_once(' ') {
+            Some((to, text)) if !to.starts_with('#') => Action::Server(format!("/msg {} {}", to, text)),
+            _ => wrong(),
+        },
+        "/verify" if !args.is_empty() && !args.contains(' ') => Action::Verify(args.to_string()),
+        "/trust" if !args.is_empty() && !args.contains(' ') => Action::Trust(args.to_string()),
+        _ => match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) if command.local => wrong(),
+            // Every required argument is missing.
+            Some(command) if command.args.starts_with('<') && args.is_empty() => wrong(),
+            _ => Action::Server(line.to_string()),
+        },
+    }
+}
+
+// "usage: /join <#room>"
+fn usage(name: &str) -> String {
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => format!("usage: {} {}", command.name, command.args),
+        None => format!("unknown command {}", name),
+    }
+}
+
+// Print the client's own commands. The server's list follows once it arrives.
+pub fn print_help() {
+    println!("* client commands:");
+    for command in COMMANDS.iter().filter(|command| command.local) {
+        let name = format!("{} {}", command.name, command.args);
+        println!("*   {} - {}", name.trim_end(), command.help);
+    }
+    println!("* Tab completes commands, #rooms and nicks");
+}
@@ -0,0 +1,256 @@
+// Command line options for the client.
+//
+//   client [SERVER] [--nick NICK] [--room #ROOM] [--log DIR [--log-format text|json]]
+//          [--bell] [--notify COMMAND] [--no-typing] [--identity FILE]
+//          [--send TEXT]... [--stdin] [--listen [--until CONDITION]...]
+//   client log search [--log DIR] [--room #ROOM] TEXT
+//
+// - `SERVER`: the address to connect to (default 127.0.0.1:8080)
+// - `--nick`: pick a nick right after connecting
+// - `--room`: join a room right after connecting, and talk in it
+// - `--send`: send one line and quit once the server has handled it - can be repeated
+// - `--stdin`: send every line piped in, then quit once the server has handled them
+// - `--listen`: print what arrives as JSON, one object per line, until the
+//   server goes away or an `--until` condition is met
+// - `--until`: when `--listen` stops (see `Until`) - with several, the first one met
+// - `--log`: keep a transcript in this folder (see `log.rs`)
+// - `--log-format`: write it as text (the default) or JSON lines
+// - `--bell`: ring the terminal bell when someone mentions us (see `notify.rs`)
+// - `--notify`: run this command when someone mentions us
+// - `--no-typing`: don't tell others we're typing, or show who is (see `typing.rs`)
+// - `--identity`: where our key for encrypted private messages is kept
+//   (IDENTITY if left out) - see `e2e.rs`
+//
+// With none of `--send`, `--stdin` and `--listen` the client is interactive,
+// the way it always was.
+//
+// `client log search` doesn't connect anywhere: it looks through the logs
+// in `--log` (LOG_DIR if left out), or just one room's with `--room`.
+
+use std::time::Duration;
+
+use chatty_rusty::protocol::ChatMessage;
+
+// The server we connect to unless another address is given on the command line.
+const DEFAULT_SERVER: &str = "127.0.0.1:8080";
+
+// Where `client log search` looks unless `--log` says otherwise.
+const LOG_DIR: &str = "chat-logs";
+
+// Where our identity for encrypted messages is kept unless `--identity`
+// says otherwise. Made the first time the client runs.
+const IDENTITY: &str = "chatty-identity";
+
+// When `--listen` stops:
+// - `count:N`: after N chat messages
+// - `contains:TEXT`: after a chat message containing TEXT
+// - `from:NICK`: after a chat message from NICK
+// - `idle:SECONDS`: once nothing has arrived for that long
+#[derive(Debug)]
+pub enum Until {
+    Count(usize),
+    Contains(String),
+    From(String),
+    Idle(Duration),
+}
+
+impl Until {
+    pub fn parse(text: &str) -> Result<Until, String> {
+        let bad = || {
+            format!(
+                "unknown --until {} - use count:N, contains:TEXT, from:NICK or idle:SECONDS",
+                text
+            )
+        };
+        let (kind, value) = text.split_once(':').ok_or_else(bad)?;
+        match kind {
+            "count" => value.parse().map(Until::Count).map_err(|_| bad()),
+            "contains" => Ok(Until::Contains(value.to_string())),
+            "from" => Ok(Until::From(value.to_string())),
+            "idle" => value
+                .parse()
+                .map(|secs| Until::Idle(Duration::from_secs(secs)))
+                .map_err(|_| bad()),
+            _ => Err(bad()),
+        }
+    }
+
+    // Has `msg`, the `count`th message we've printed, met this condition?
+    // `idle:` is about time rather than messages, so it never is.
+    pub fn met(&self, msg: &ChatMessage, count: usize) -> bool {
+        match self {
+            Until::Count(n) => count >= *n,
+            Until::Contains(text) => msg.text.contains(text.as_str()),
+            Until::From(nick) => msg.sender == *nick,
+            Until::Idle(_) => false,
+        }
+    }
+
+    // How long `idle:` waits, or None for the other conditions.
+    pub fn idle(&self) -> Option<Duration> {
+        match self {
+            Until::Idle(duration) => Some(*duration),
+            _ => None,
+        }
+    }
+}
+
+// How the transcript is written - see `log.rs`.
+#[derive(Debug, Clone, Copy)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    fn parse(text: &str) -> Result<LogFormat, String> {
+        match text {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown --log-format {} - use text or json", text)),
+        }
+    }
+
+    // The end of the log files' names.
+    pub fn extension(self) -> &'static str {
+        match self {
+            LogFormat::Text => "log",
+            LogFormat::Json => "jsonl",
+        }
+    }
+}
+
+// `client log search`: what to look for, and where.
+#[derive(Debug)]
+pub struct Search {
+    pub dir: String,
+    pub room: Option<String>,
+    pub text: String,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub server: String,
+    pub nick: Option<String>,
+    pub room: Option<String>,
+    pub send: Vec<String>,
+    pub stdin: bool,
+    pub listen: bool,
+    pub until: Vec<Until>,
+    pub log: Option<String>,
+    pub log_format: LogFormat,
+    pub bell: bool,
+    pub notify: Option<String>,
+    pub typing: bool,
+    pub identity: String,
+    // Set for `client log search`, which ignores everything else.
+    pub search: Option<Search>,
+}
+
+impl Config {
+    // Read the options from the command line.
+    // On a mistake we return a message explaining it, and `main` prints it.
+    pub fn from_args() -> Result<Config, String> {
+        let mut args = std::env::args().skip(1).peekable();
+
+        let mut config = Config {
+            server: DEFAULT_SERVER.to_string(),
+            nick: None,
+            room: None,
+            send: Vec::new(),
+            stdin: false,
+            listen: false,
+            until: Vec::new(),
+            log: None,
+            log_format: LogFormat::Text,
+            bell: false,
+            notify: None,
+            typing: true,
+            identity: IDENTITY.to_string(),
+            search: None,
+        };
+        // `client log search ...`
+        if args.peek().is_some_and(|arg| arg == "log") {
+            args.next();
+            if args.next().as_deref() != Some("search") {
+                return Err("the only log command is: log search TEXT".to_string());
+            }
+            config.search = Some(Search::from_args(args)?);
+            return Ok(config);
+        }
+        let mut server = None;
+        let mut log_format = None;
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--nick" => config.nick = Some(value()?),
+                "--room" => config.room = Some(value()?),
+                "--send" => config.send.push(value()?),
+                "--stdin" => config.stdin = true,
+                "--listen" => config.listen = true,
+                "--until" => config.until.push(Until::parse(&value()?)?),
+                "--log" => config.log = Some(value()?),
+                "--log-format" => log_format = Some(LogFormat::parse(&value()?)?),
+                "--bell" => config.bell = true,
+                "--notify" => config.notify = Some(value()?),
+                "--no-typing" => config.typing = false,
+                "--identity" => config.identity = value()?,
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+                // The one word that isn't an option is the server address.
+                _ if server.is_none() => server = Some(arg),
+                _ => return Err(format!("unexpected {}", arg)),
+            }
+        }
+
+        if let Some(server) = server {
+            config.server = server;
+        }
+        if !config.until.is_empty() && !config.listen {
+            return Err("--until only makes sense with --listen".to_string());
+        }
+        if let Some(format) = log_format {
+            if config.log.is_none() {
+                return Err("--log-format only makes sense with --log".to_string());
+            }
+            config.log_format = format;
+        }
+        // Our protocol is one line per message.
+        if config.send.iter().any(|text| text.contains(['\r', '\n'])) {
+            return Err("--send takes one line - use --stdin for several".to_string());
+        }
+        Ok(config)
+    }
+
+    // Is the client being used by a script rather than a person?
+    pub fn batch(&self) -> bool {
+        !self.send.is_empty() || self.stdin || self.listen
+    }
+}
+
+impl Search {
+    // Read what follows `log search` on the command line.
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Search, String> {
+        let mut dir = LOG_DIR.to_string();
+        let mut room = None;
+        let mut words = Vec::new();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--log" => dir = value()?,
+                "--room" => room = Some(value()?),
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+                // Everything else is what to look for, so quotes are optional.
+                _ => words.push(arg),
+            }
+        }
+        if words.is_empty() {
+            return Err("usage: log search [--log DIR] [--room #ROOM] TEXT".to_string());
+        }
+        Ok(Search {
+            dir,
+            room,
+            text: words.join(" "),
+        })
+    }
+}
@@ -0,0 +1,468 @@
+// End-to-end encrypted private messages.
+//
+// The server passes private messages on, so it could read every one of them.
+// With end-to-end encryption only the two people talking can: the client
+// locks the message before sending it, and only the other person's client
+// has what it takes to unlock it. The server just relays the scrambled text.
+//
+// How it works:
+// - The first time the client runs it makes an "identity": a secret key that
+//   never leaves this machine (kept in `--identity FILE`) and the public key
+//   that goes with it, which it publishes with `/setkey` on every connection.
+// - To talk to bob, we ask the server for his public key (`/key bob`). Our
+//   secret key and his public key give a shared key ("X25519"), and his
+//   secret key and our public key give him the very same one. Nobody else
+//   can work it out, not even the server.
+// - Each message is encrypted with that key (XChaCha20-Poly1305), which also
+//   makes sure nobody changed it on the way, and sent as ordinary `/msg`
+//   text: `e2e:<our public key>:<nonce and encrypted text>`, in base64.
+//
+// The server hands out the keys, so it could hand out one of its own instead
+// of bob's and read everything ("a man in the middle"). Two things stop that:
+// - The first key we see for a nick is remembered (in FILE.known) and we
+//   refuse to use a different one later until the user says so with `/trust`.
+// - `/verify bob` shows a fingerprint of bob's key and of ours. Compare them
+//   with bob some other way - in person, on the phone. If they match, nobody
+//   is in the middle.
+//
+// What it doesn't do: the same key is used for every message between two
+// people, so someone who later steals a secret key could read old messages
+// they recorded. And messages to people whose client has no key (IRC users,
+// older clients) need `/plain`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use chatty_rusty::protocol::{base64_decode, base64_encode};
+
+// What an encrypted message starts with.
+const PREFIX: &str = "e2e:";
+
+// Mixed into the shared key, so it's only ever used for this.
+const LABEL: &[u8] = b"chatty-rusty e2e v1";
+
+// How long the nonce is: a random number sent with each message, so the same
+// text never encrypts to the same thing. 24 bytes is long enough to pick at
+// random without ever picking the same one twice.
+const NONCE_LEN: usize = 24;
+
+// A public key: 32 bytes.
+type Key = [u8; 32];
+
+// Something the client should do after one of the calls below.
+// - `Show`: print this line for the user
+// - `Send`: send this line to the server
+pub enum Step {
+    Show(String),
+    Send(String),
+}
+
+// What an incoming private message turned out to be.
+// - `Plain`: not encrypted
+// - `Read`: encrypted, and this is what it says
+// - `Unreadable`: encrypted, but we can't read it, for this reason
+pub enum Opened {
+    Plain,
+    Read(String),
+    Unreadable(String),
+}
+
+// What's waiting for a nick's key to arrive.
+enum Waiting {
+    Send(String),
+    Verify,
+}
+
+// Our identity and what we know about other people's keys.
+// - `known`: the key we first saw for each nick on each server, as
+//   (server, nick) -> key. Saved in `known_path`.
+// - `server`: the server we're connected to
+// - `fetched`: the keys we've checked on this connection - they match `known`
+// - `changed`: nicks whose key no longer matches `known`, with the new key,
+//   until the user `/trust`s it
+// - `waiting`: messages to send, or fingerprints to show, once a key arrives
+pub struct E2e {
+    secret: StaticSecret,
+    public: PublicKey,
+    known_path: PathBuf,
+    known: BTreeMap<(String, String), Key>,
+    server: String,
+    fetched: HashMap<String, Key>,
+    changed: HashMap<String, Key>,
+    waiting: HashMap<String, Vec<Waiting>>,
+}
+
+pub type SharedE2e = std::sync::Arc<std::sync::Mutex<E2e>>;
+
+impl E2e {
+    // Load our identity from `path`, or make a new one there if it doesn't
+    // exist yet, and the keys we've seen from `path.known`.
+    pub fn load(path: &str) -> Result<E2e, String> {
+        let secret = match std::fs::read_to_string(path) {
+            Ok(text) => {
+                let bytes: Key = base64_decode(text.trim())
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .ok_or(format!("{} isn't an identity file", path))?;
+                StaticSecret::from(bytes)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let secret = StaticSecret::random_from_rng(OsRng);
+                write_secret(Path::new(path), &base64_encode(secret.as_bytes()))
+                    .map_err(|e| format!("can't save a new identity in {}: {}", path, e))?;
+                println!("* made a new identity for encrypted messages in {}", path);
+                secret
+            }
+            Err(e) => return Err(format!("can't read {}: {}", path, e)),
+        };
+        let known_path = PathBuf::from(format!("{}.known", path));
+        let mut known = BTreeMap::new();
+        // No file just means we haven't seen anybody's key yet.
+        let text = std::fs::read_to_string(&known_path).unwrap_or_default();
+        for (n, line) in text.lines().enumerate() {
+            let mut fields = line.split_whitespace();
+            let (Some(server), Some(nick), Some(key)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let key: Key = base64_decode(key)
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or(format!("{} line {}: bad key", known_path.display(), n + 1))?;
+            known.insert((server.to_string(), nick.to_string()), key);
+        }
+        Ok(E2e {
+            public: PublicKey::from(&secret),
+            secret,
+            known_path,
+            known,
+            server: String::new(),
+            fetched: HashMap::new(),
+            changed: HashMap::new(),
+            waiting: HashMap::new(),
+        })
+    }
+
+    // The line publishing our public key, sent on every new connection.
+    pub fn setup_line(&self) -> String {
+        format!("/setkey {}\n", base64_encode(self.public.as_bytes()))
+    }
+
+    // We've connected to `server`: what we checked on the last one is
+    // worth nothing here.
+    pub fn connected(&mut self, server: &str) {
+        self.server = server.to_string();
+        self.fetched.clear();
+        self.changed.clear();
+        self.waiting.clear();
+    }
+
+    // Send `text` to `to`, encrypted. If we don't have their key yet we ask
+    // the server for it and send the message once it arrives (see `learn`).
+    pub fn private(&mut self, to: &str, text: &str) -> Vec<Step> {
+        if self.changed.contains_key(to) {
+            return vec![changed_warning(to)];
+        }
+        if let Some(key) = self.fetched.get(to) {
+            return vec![match self.seal(key, text) {
+                Ok(sealed) => Step::Send(format!("/msg {} {}", to, sealed)),
+                Err(e) => Step::Show(format!("! {}", e)),
+            }];
+        }
+        self.wait_for(to, Waiting::Send(text.to_string()))
+    }
+
+    // `/verify <nick>`: show their fingerprint and ours, fetching their key first.
+    pub fn verify(&mut self, nick: &str) -> Vec<Step> {
+        if let Some(key) = self.changed.get(nick) {
+            let mut steps = self.fingerprints(nick, key);
+            steps.push(Step::Show(format!(
+                "* this is {}'s NEW key - if it matches, /trust {}",
+                nick, nick
+            )));
+            return steps;
+        }
+        match self.fetched.get(nick) {
+            Some(key) => self.fingerprints(nick, key),
+            None => self.wait_for(nick, Waiting::Verify),
+        }
+    }
+
+    // `/trust <nick>`: use their new key from now on.
+    pub fn trust(&mut self, nick: &str) -> Vec<Step> {
+        let Some(key) = self.changed.remove(nick) else {
+            return vec![Step::Show(format!("! {}'s key hasn't changed", nick))];
+        };
+        let mut steps = vec![Step::Show(format!("* you now trust {}'s new key", nick))];
+        if let Err(e) = self.remember(nick, key) {
+            steps.push(Step::Show(format!("! {}", e)));
+        }
+        steps
+    }
+
+    // The server answered `/key <nick>` with `key` (None if they have none).
+    pub fn learn(&mut self, nick: &str, key: Option<&str>) -> Vec<Step> {
+        let waiting = self.waiting.remove(nick).unwrap_or_default();
+        let key: Option<Key> = key
+            .and_then(base64_decode)
+            .and_then(|bytes| bytes.try_into().ok());
+        let Some(key) = key else {
+            return vec![Step::Show(format!(
+                "! {} has no encryption key (their client can't do it) - /plain {} <text> sends it unencrypted",
+                nick, nick
+            ))];
+        };
+        match self.known.get(&(self.server.clone(), nick.to_string())) {
+            Some(pinned) if *pinned != key => {
+                self.changed.insert(nick.to_string(), key);
+                let mut steps = vec![changed_warning(nick)];
+                let unsent = waiting
+                    .iter()
+                    .filter(|w| matches!(w, Waiting::Send(_)))
+                    .count();
+                if unsent > 0 {
+                    let noun = if unsent == 1 { "message" } else { "messages" };
+                    steps.push(Step::Show(format!("! {} private {} not sent", unsent, noun)));
+                }
+                return steps;
+            }
+            _ => {}
+        }
+        let mut steps = Vec::new();
+        if let Err(e) = self.remember(nick, key) {
+            steps.push(Step::Show(format!("! {}", e)));
+        }
+        if waiting.is_empty() {
+            // Someone typed `/key <nick>` themselves.
+            steps.extend(self.fingerprints(nick, &key));
+        }
+        for waiting in waiting {
+            match waiting {
+                Waiting::Send(text) => steps.extend(self.private(nick, &text)),
+                Waiting::Verify => steps.extend(self.fingerprints(nick, &key)),
+            }
+        }
+        steps
+    }
+
+    // Someone changed nick: the key we checked goes with them.
+    pub fn renamed(&mut self, old: &str, new: &str) {
+        if let Some(key) = self.fetched.remove(old) {
+            self.fetched.insert(new.to_string(), key);
+        }
+    }
+
+    // Open a private message, if it's encrypted.
+    pub fn open(&mut self, from: &str, to: &str, text: &str) -> Opened {
+        let Some(payload) = text.strip_prefix(PREFIX) else {
+            return Opened::Plain;
+        };
+        let parsed = payload.split_once(':').and_then(|(sender, sealed)| {
+            let sender: Key = base64_decode(sender)?.try_into().ok()?;
+            Some((sender, base64_decode(sealed)?))
+        });
+        let Some((sender, sealed)) = parsed else {
+            return Opened::Unreadable("it's garbled".to_string());
+        };
+
+        // One we sent, coming back from the server: the key is `to`'s.
+        let peer = if sender == *self.public.as_bytes() {
+            match self.fetched.get(to) {
+                Some(key) => *key,
+                None => return Opened::Unreadable(format!("we don't have {}'s key", to)),
+            }
+        } else {
+            // The key in the message must be the one we know for `from`.
+            match self.known.get(&(self.server.clone(), from.to_string())) {
+                Some(pinned) if *pinned != sender => {
+                    self.changed.insert(from.to_string(), sender);
+                    return Opened::Unreadable(format!(
+                        "{}'s key has changed - /verify {}, then /trust {} to read their new messages",
+                        from, from, from
+                    ));
+                }
+                _ => {
+                    if let Err(e) = self.remember(from, sender) {
+                        println!("! {}", e);
+                    }
+                }
+            }
+            sender
+        };
+
+        if sealed.len() < NONCE_LEN {
+            return Opened::Unreadable("it's garbled".to_string());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let Some(cipher) = self.cipher(&peer) else {
+            return Opened::Unreadable("the sender's key is no good".to_string());
+        };
+        let recipient = if peer == sender {
+            *self.public.as_bytes()
+        } else {
+            peer
+        };
+        let aad = [sender, recipient].concat();
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &aad,
+        };
+        match cipher.decrypt(XNonce::from_slice(nonce), payload) {
+            Ok(plain) => Opened::Read(String::from_utf8_lossy(&plain).into_owned()),
+            Err(_) => Opened::Unreadable("it was changed on the way, or isn't for us".to_string()),
+        }
+    }
+
+    // Encrypt `text` for the owner of `peer`, as `/msg` text.
+    fn seal(&self, peer: &Key, text: &str) -> Result<String, String> {
+        let cipher = self
+            .cipher(peer)
+            .ok_or("that key is no good - nothing was sent".to_string())?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        // The "associated data" isn't encrypted but is checked along with the
+        // text: who it's from and who it's for, so the server can't turn our
+        // message around and deliver it back to us as if bob had sent it.
+        let sender = *self.public.as_bytes();
+        let aad = [sender, *peer].concat();
+        let payload = Payload {
+            msg: text.as_bytes(),
+            aad: &aad,
+        };
+        let ciphertext = cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| "can't encrypt the message".to_string())?;
+        let sealed = [nonce.as_slice(), &ciphertext].concat();
+        Ok(format!(
+            "{}{}:{}",
+            PREFIX,
+            base64_encode(&sender),
+            base64_encode(&sealed)
+        ))
+    }
+
+    // The cipher shared with the owner of `peer`, or None for a key that
+    // would give a shared key anybody could work out.
+    fn cipher(&self, peer: &Key) -> Option<XChaCha20Poly1305> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*peer));
+        if !shared.was_contributory() {
+            return None;
+        }
+        // Hash the shared secret together with both public keys (smaller one
+        // first, so both sides get the same) into the key we really use.
+        let ours = *self.public.as_bytes();
+        let (first, second) = if ours < *peer {
+            (ours, *peer)
+        } else {
+            (*peer, ours)
+        };
+        let key = Sha256::new()
+            .chain_update(LABEL)
+            .chain_update(shared.as_bytes())
+            .chain_update(first)
+            .chain_update(second)
+            .finalize();
+        XChaCha20Poly1305::new_from_slice(&key).ok()
+    }
+
+    // Ask for `nick`'s key, and do `then` once it's here. Asks only once
+    // however much piles up.
+    fn wait_for(&mut self, nick: &str, then: Waiting) -> Vec<Step> {
+        let waiting = self.waiting.entry(nick.to_string()).or_default();
+        waiting.push(then);
+        if waiting.len() == 1 {
+            vec![Step::Send(format!("/key {}", nick))]
+        } else {
+            Vec::new()
+        }
+    }
+
+    // `nick`'s key checks out: use it on this connection, and remember it
+    // from now on if it's the first time we've seen it.
+    fn remember(&mut self, nick: &str, key: Key) -> Result<(), String> {
+        self.fetched.insert(nick.to_string(), key);
+        let previous = self
+            .known
+            .insert((self.server.clone(), nick.to_string()), key);
+        if previous == Some(key) {
+            return Ok(());
+        }
+        let mut text = String::new();
+        for ((server, nick), key) in &self.known {
+            text.push_str(&format!("{} {} {}\n", server, nick, base64_encode(key)));
+        }
+        write_known(&self.known_path, &text)
+            .map_err(|e| format!("can't save {}: {}", self.known_path.display(), e))
+    }
+
+    // `/verify`'s answer.
+    fn fingerprints(&self, nick: &str, key: &Key) -> Vec<Step> {
+        vec![
+            Step::Show(format!("* {}'s fingerprint: {}", nick, fingerprint(key))),
+            Step::Show(format!(
+                "* yours:        {}",
+                fingerprint(self.public.as_bytes())
+            )),
+            Step::Show(format!(
+                "* compare them with {} some other way (in person, on the phone) - if they match, nobody can read your messages in between",
+                nick
+            )),
+        ]
+    }
+}
+
+fn changed_warning(nick: &str) -> Step {
+    Step::Show(format!(
+        "! {}'s key has changed since you last talked - someone may be listening in. Check it with /verify {}, then /trust {}",
+        nick, nick, nick
+    ))
+}
+
+// A short summary of a key that's easy to read out loud: the first 16 bytes
+// of its SHA-256, in groups of 4 hex digits - "3f2a 91c0 ...".
+fn fingerprint(key: &Key) -> String {
+    let hash = Sha256::digest(key);
+    hash[..16]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Save the secret key where only we can read it.
+fn write_secret(path: &Path, text: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // On Unix, make the file readable by us alone (`chmod 600`).
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", text)
+}
+
+// Save the known keys the way the server saves rooms.json: write them all to
+// "<file>.new" first, then rename that over the old file. A rename is all or
+// nothing, so if we crash halfway the old list is still there, whole. The new
+// file is readable by us alone, like the secret key - the list says who we
+// talk to.
+fn write_known(path: &Path, text: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".new");
+    let temporary = PathBuf::from(temporary);
+    // Left over from a crash, maybe. `create_new` below refuses to open a
+    // file that's already there, so it can't be a trap someone set for us.
+    let _ = std::fs::remove_file(&temporary);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temporary)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)
+}
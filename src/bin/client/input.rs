@@ -0,0 +1,257 @@
+// Reading what the user types, with Tab completion.
+//
+// Reading the terminal line by line is fine until you want Tab to do
+// something: the terminal only hands a line over once Enter is pressed.
+// `rustyline` puts the terminal in "raw mode", sees every key as it's typed,
+// and does the editing itself - cursor keys, history with Up and Down, and
+// completion with Tab:
+// - `/jo<Tab>` completes a command
+// - `#ge<Tab>` completes a room we've seen
+// - `al<Tab>` or `@al<Tab>` completes a nick we've seen
+// When stdin isn't a terminal (e.g. a pipe), it simply reads lines.
+//
+// Because the editor sees every key, it's also what notices we're typing, and
+// shows who else is after our own text (see `typing.rs`).
+//
+// `readline` blocks the thread it runs on until a line is complete, so it
+// gets a thread of its own and hands each line over through a channel. It
+// also waits for a go-ahead before reading the next line: if we quit while
+// the editor was waiting for keys, the terminal would be left in raw mode.
+
+use std::collections::BTreeSet;
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{CompletionType, Context, Editor, Helper};
+use tokio::sync::mpsc;
+
+use chatty_rusty::protocol::Frame;
+
+use crate::commands::COMMANDS;
+use crate::typing::{self, SharedTyping};
+
+// The nicks and rooms we've seen, for completion. The read task adds to it
+// and the editor thread reads it, so it's shared behind an `Arc<Mutex<...>>`.
+// A plain `std` Mutex because the editor thread isn't async.
+#[derive(Default)]
+pub struct Names {
+    nicks: BTreeSet<String>,
+    rooms: BTreeSet<String>,
+}
+
+pub type SharedNames = Arc<Mutex<Names>>;
+
+impl Names {
+    // Pick up any nicks and rooms mentioned in a frame from the server.
+    pub fn learn(&mut self, frame: &Frame) {
+        match frame {
+            // People on linked servers can't be completed to anything useful,
+            // since `/msg` only reaches people on our server.
+            Frame::Msg(msg) => {
+                if msg.origin.is_none() {
+                    self.nicks.insert(msg.sender.clone());
+                }
+                self.rooms.insert(msg.room.clone());
+            }
+            Frame::Join { nick, room } => {
+                self.nicks.insert(nick.clone());
+                self.rooms.insert(room.clone());
+            }
+            Frame::Nick { old, new } => {
+                self.nicks.remove(old);
+                self.nicks.insert(new.clone());
+            }
+            Frame::Private { from, .. } => {
+                self.nicks.insert(from.clone());
+            }
+            _ => {}
+        }
+    }
+
+    // Forget everything, e.g. after connecting to another server.
+    pub fn clear(&mut self) {
+        self.nicks.clear();
+        self.rooms.clear();
+    }
+}
+
+// What `rustyline` calls to complete a word, and to show a "hint" after the
+// line. The other traits a `Helper` needs have default methods that do
+// nothing, which is what we want.
+// - `typing`: who's typing, and where to send our own signal - None with
+//   `--no-typing`
+// - `last_sent`: when we last sent a signal, to send one every SEND_EVERY
+struct Completion {
+    names: SharedNames,
+    typing: Option<(SharedTyping, mpsc::UnboundedSender<()>)>,
+    last_sent: Mutex<Option<Instant>>,
+}
+
+impl Completer for Completion {
+    type Candidate = Pair;
+
+    // Complete the word before the cursor at `pos`. We return where the word
+    // starts and what it could be.
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let start = before.rfind(' ').map_or(0, |space| space + 1);
+        let word = &before[start..];
+        let names = self.names.lock().unwrap();
+        let candidates: Vec<String> = if start == 0 && word.starts_with('/') {
+            COMMANDS.iter().map(|c| c.name.to_string()).collect()
+        } else if word.starts_with('#') {
+            names.rooms.iter().cloned().collect()
+        } else if word.starts_with('@') {
+            names
+                .nicks
+                .iter()
+                .map(|nick| format!("@{}", nick))
+                .collect()
+        } else if !word.is_empty() {
+            names.nicks.iter().cloned().collect()
+        } else {
+            Vec::new()
+        };
+        let pairs = candidates
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(word))
+            .map(|candidate| Pair {
+                display: candidate.clone(),
+                replacement: candidate,
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for Completion {
+    type Hint = String;
+
+    // The editor asks for a hint every time the line changes, i.e. every
+    // time a key is pressed - so this is where we notice the user typing.
+    fn hint(&self, line: &str, _pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        let (typing, signal) = self.typing.as_ref()?;
+        // A message, not a command - nobody needs to know we're typing `/rooms`.
+        if !line.trim().is_empty() && !line.starts_with('/') {
+            let mut last_sent = self.last_sent.lock().unwrap();
+            if last_sent.is_none_or(|sent| sent.elapsed() >= typing::SEND_EVERY) {
+                let _ = signal.send(());
+                *last_sent = Some(Instant::now());
+            }
+        }
+        let status = typing.lock().unwrap().status()?;
+        Some(format!("  ({})", status))
+    }
+}
+
+impl Highlighter for Completion {
+    // Show the hint dimmed, so it doesn't look like part of the line.
+    // The editor only calls this on a terminal.
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(format!("\x1b[2m{}\x1b[0m", hint))
+    }
+}
+impl Validator for Completion {}
+impl Helper for Completion {}
+
+// The main task's end of the editor thread.
+// - `lines`: each line typed. Closed when the user presses Ctrl+D or Ctrl+C.
+// - `go`: tells the thread it may read the next line.
+// - `asked`: whether we've already told it to - `line` may be called again
+//   after a `select!` dropped the previous call.
+pub struct Input {
+    lines: mpsc::UnboundedReceiver<String>,
+    go: std::sync::mpsc::Sender<()>,
+    asked: bool,
+}
+
+// A signal from the editor thread each time we should tell the server we're
+// typing. Separate from `Input` so `select!` can wait for both at once.
+pub struct Typed(mpsc::UnboundedReceiver<()>);
+
+impl Input {
+    // Start the editor thread. `typing` is None with `--no-typing`.
+    pub fn start(names: SharedNames, typing: Option<SharedTyping>) -> (Input, Typed) {
+        let (tx, lines) = mpsc::unbounded_channel();
+        let (signal, typed) = mpsc::unbounded_channel();
+        let helper = Completion {
+            names,
+            typing: typing.map(|typing| (typing, signal)),
+            last_sent: Mutex::new(None),
+        };
+        let (go, wait) = std::sync::mpsc::channel::<()>();
+        // A plain thread rather than a Tokio task: the runtime waits for its
+        // own blocking tasks before the program can end, and this one may be
+        // waiting for keys forever.
+        std::thread::spawn(move || {
+            let config = rustyline::Config::builder()
+                .completion_type(CompletionType::List)
+                .auto_add_history(true)
+                .build();
+            let mut editor = match Editor::<Completion, DefaultHistory>::with_config(config) {
+                Ok(editor) => editor,
+                Err(e) => {
+                    println!("Error reading from stdin: {}", e);
+                    return;
+                }
+            };
+            editor.set_helper(Some(helper));
+            // Each go-ahead lets us read one line.
+            while wait.recv().is_ok() {
+                match editor.readline("") {
+                    Ok(line) => {
+                        if tx.send(line).is_err() {
+                            return;
+                        }
+                    }
+                    // Ctrl+C or Ctrl+D: the user wants to quit.
+                    Err(ReadlineError::Interrupted | ReadlineError::Eof) => return,
+                    Err(e) => {
+                        println!("Error reading from stdin: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+        let input = Input {
+            lines,
+            go,
+            asked: false,
+        };
+        (input, Typed(typed))
+    }
+
+    // The next line the user types, or None once they've quit.
+    pub async fn line(&mut self) -> Option<String> {
+        if !self.asked {
+            // Fails only if the thread has stopped, and then `recv` says so.
+            let _ = self.go.send(());
+            self.asked = true;
+        }
+        let line = self.lines.recv().await;
+        self.asked = false;
+        line
+    }
+}
+
+impl Typed {
+    // Wait until the user has typed enough that the server should hear about
+    // it again. Never returns with `--no-typing`.
+    pub async fn next(&mut self) {
+        if self.0.recv().await.is_none() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
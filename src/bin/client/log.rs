@@ -0,0 +1,252 @@
+// Keeping a transcript of the conversation on disk.
+//
+// Start the client with `--log DIR` and every message is written to a file per
+// room and per day (UTC), so the files "rotate" at midnight on their own:
+//
+//   chat-logs/general/2026-10-18.log
+//   chat-logs/general/2026-10-19.log
+//   chat-logs/rust/2026-10-18.log
+//   chat-logs/@private/2026-10-18.log   private messages, to us and from us
+//
+// Our own messages are logged when the server sends them back, so they have
+// their ID and the server's timestamp like everyone else's. Edits and
+// deletions are logged too, as new lines - a log is never rewritten.
+//
+// `--log-format text` (the default) writes lines for people to read:
+//
+//   [2026-10-18T09:41:07Z] #17 alice: hello!
+//   [2026-10-18T09:42:30Z] #17 edited: hello everyone!
+//
+// and `--log-format json` one JSON object per line, the same ones `--listen`
+// prints (see `batch.rs`), with `.jsonl` files.
+//
+// `client log search TEXT` finds lines in the logs - see `search`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use chatty_rusty::protocol::{Frame, now_rfc3339};
+
+use crate::batch::to_json;
+use crate::config::{LogFormat, Search};
+
+// The folder private messages go in. Room folders are the room name without
+// its `#`, and a room name can't contain `@`, so they never clash.
+const PRIVATE: &str = "@private";
+
+// How many message IDs we remember the room of, to log edits and deletions
+// in the right file.
+const ROOMS_LIMIT: usize = 1000;
+
+// The log of one connection.
+// - `files`: the file open for each room folder, with the day it's for
+// - `rooms`: which room each recent message was in
+// - `last_id`: the highest message ID logged, so a message the server sends
+//   again (e.g. after `/since`) isn't logged twice
+pub struct Log {
+    dir: PathBuf,
+    format: LogFormat,
+    files: HashMap<String, (String, File)>,
+    rooms: BTreeMap<u64, String>,
+    last_id: u64,
+}
+
+impl Log {
+    pub fn new(dir: &str, format: LogFormat) -> Log {
+        Log {
+            dir: PathBuf::from(dir),
+            format,
+            files: HashMap::new(),
+            rooms: BTreeMap::new(),
+            last_id: 0,
+        }
+    }
+
+    // Log a frame from the server, if it's one worth keeping.
+    // A problem with the disk is shown to the user but doesn't stop the chat.
+    pub async fn record(&mut self, frame: &Frame) {
+        // (room folder, timestamp, text line)
+        let (folder, time, text) = match frame {
+            Frame::Msg(msg) if msg.id <= self.last_id => return,
+            Frame::Msg(msg) => {
+                self.last_id = msg.id;
+                let folder = folder_for(&msg.room);
+                self.rooms.insert(msg.id, folder.clone());
+                if self.rooms.len() > ROOMS_LIMIT {
+                    self.rooms.pop_first();
+                }
+                let sender = match &msg.origin {
+                    Some(origin) => format!("{}@{}", msg.sender, origin),
+                    None => msg.sender.clone(),
+                };
+                let parent = match msg.reply_to {
+                    Some(parent) => format!(" (re #{})", parent),
+                    None => String::new(),
+                };
+                let text = format!("#{} {}{}: {}", msg.id, sender, parent, msg.text);
+                (folder, msg.time.clone(), text)
+            }
+            // Edits and deletions of messages we never logged have no room
+            // to go in, and were never in our log anyway.
+            Frame::Edit { id, text } => match self.rooms.get(id) {
+                Some(folder) => (
+                    folder.clone(),
+                    now_rfc3339(),
+                    format!("#{} edited: {}", id, text),
+                ),
+                None => return,
+            },
+            Frame::Delete { id } => match self.rooms.get(id) {
+                Some(folder) => (folder.clone(), now_rfc3339(), format!("#{} deleted", id)),
+                None => return,
+            },
+            Frame::Private {
+                time,
+                from,
+                to,
+                text,
+            } => (
+                PRIVATE.to_string(),
+                time.clone(),
+                format!("{} -> {}: {}", from, to, text),
+            ),
+            _ => return,
+        };
+
+        let line = match self.format {
+            LogFormat::Text => format!("[{}] {}\n", time, text),
+            LogFormat::Json => {
+                // `to_json` knows every frame we get here.
+                let mut json = to_json(frame).unwrap_or_default();
+                // Edits and deletions don't say when or where on their own.
+                if matches!(frame, Frame::Edit { .. } | Frame::Delete { .. }) {
+                    json["time"] = time.clone().into();
+                    json["room"] = format!("#{}", folder).into();
+                }
+                format!("{}\n", json)
+            }
+        };
+        if let Err(e) = self.write(&folder, &time, &line).await {
+            println!("! can't write the chat log: {}", e);
+        }
+    }
+
+    // Append `line` to the file for `folder` on the day of `time`.
+    async fn write(&mut self, folder: &str, time: &str, line: &str) -> std::io::Result<()> {
+        let day = day_of(time);
+        // Open a new file the first time, and when the day changes.
+        let stale = match self.files.get(folder) {
+            Some((open_day, _)) => *open_day != day,
+            None => true,
+        };
+        if stale {
+            let dir = self.dir.join(folder);
+            tokio::fs::create_dir_all(&dir).await?;
+            let name = format!("{}.{}", day, self.format.extension());
+            // `append` adds to the end of the file if it already exists,
+            // e.g. when the client is restarted on the same day.
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(name))
+                .await?;
+            self.files.insert(folder.to_string(), (day, file));
+        }
+        let (_, file) = self.files.get_mut(folder).expect("opened above");
+        file.write_all(line.as_bytes()).await?;
+        // Tokio hands file writes to another thread; `flush` waits until
+        // the line has really been written.
+        file.flush().await
+    }
+}
+
+// The day a message was sent, for its file name: "2026-10-18T09:41:07Z" ->
+// "2026-10-18". The time comes from the server, so we check it really starts
+// with a date - something like "../../../x" must never become a file name.
+// If it doesn't, we use today's date instead.
+fn day_of(time: &str) -> String {
+    let is_day = |day: &str| {
+        day.len() == 10
+            && day.char_indices().all(|(i, c)| match i {
+                4 | 7 => c == '-',
+                _ => c.is_ascii_digit(),
+            })
+    };
+    match time.get(..10) {
+        Some(day) if is_day(day) => day.to_string(),
+        _ => now_rfc3339()[..10].to_string(),
+    }
+}
+
+// The folder for a room: its name without the `#`. Letters, digits, `-` and
+// `_` are kept as they are; every other byte is written as `%` and its
+// number in hex, the way web addresses do it, so "#café" becomes
+// "caf%C3%A9". That way no two rooms share a folder, a name with no plain
+// letters still gets one, and a name like "../x" (which the server doesn't
+// allow anyway) can never take us out of the log folder.
+fn folder_for(room: &str) -> String {
+    let name = room.strip_prefix('#').unwrap_or(room);
+    let mut folder = String::new();
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            folder.push(byte as char);
+        } else {
+            folder.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    // Just "#": a folder needs a name, so we encode the `#` too.
+    if folder.is_empty() {
+        folder.push_str("%23");
+    }
+    folder
+}
+
+// `client log search TEXT`: print every logged line containing TEXT (ignoring
+// upper and lower case), oldest first, each with the file it came from.
+// Returns the exit status like `grep`: 0 if something was found, 1 if not.
+pub fn search(search: &Search) -> i32 {
+    let dir = Path::new(&search.dir);
+    let folders = match &search.room {
+        Some(room) if room == "private" => vec![PRIVATE.to_string()],
+        Some(room) => vec![folder_for(room)],
+        None => match list(dir) {
+            Ok(folders) => folders,
+            Err(e) => {
+                eprintln!("error: can't read {}: {}", dir.display(), e);
+                return 2;
+            }
+        },
+    };
+    let needle = search.text.to_lowercase();
+    let mut found = false;
+    for folder in folders {
+        // A room we never logged simply has no matches.
+        let files = list(&dir.join(&folder)).unwrap_or_default();
+        for name in files {
+            let path = dir.join(&folder).join(&name);
+            let Ok(text) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            for line in text.lines() {
+                if line.to_lowercase().contains(&needle) {
+                    println!("{}/{}: {}", folder, name, line);
+                    found = true;
+                }
+            }
+        }
+    }
+    if found { 0 } else { 1 }
+}
+
+// The names in a folder, sorted - so days come out in order.
+fn list(dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    names.sort();
+    Ok(names)
+}
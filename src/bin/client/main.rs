@@ -0,0 +1,849 @@
+// `TcpStream` represents an active TCP connection.
+// On the client side we use it to connect TO the server,
+// whereas on the server side we received it from incoming connections.
+use tokio::net::TcpStream;
+
+// The two halves a TcpStream splits into, so we can name their types.
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+// `JoinHandle` is what `tokio::spawn` gives back: we can wait for the task
+// to end through it, or stop it early with `abort()`.
+use tokio::task::JoinHandle;
+
+// `AsyncBufReadExt` is a trait that gives us the `read_line()` method.
+// We use it to read complete lines both from the server and from the terminal.
+// Without importing this trait `read_line` would not exist on our BufReader.
+use tokio::io::AsyncBufReadExt;
+
+// `AsyncWriteExt` is a trait that gives us the `write_all()` method.
+// We use it to send the user's typed messages to the server as raw bytes.
+// Without importing this trait `write_all` would not exist on our writer.
+use tokio::io::AsyncWriteExt;
+
+// `BufReader` wraps a reader and adds an internal buffer to it.
+// Without buffering we'd have to read one byte at a time which is very inefficient.
+// BufReader accumulates incoming bytes and lets us read higher level constructs
+// like entire lines in one operation.
+use tokio::io::BufReader;
+
+// `Arc` stands for "Atomically Reference Counted" - it lets multiple tasks
+// share ownership of the same data safely by keeping a count of how many
+// owners exist, and only cleaning up when the last owner is gone.
+use std::sync::Arc;
+
+// `Mutex` stands for "Mutual Exclusion" - it ensures only one task can
+// access the shared data at a time, preventing race conditions.
+// We use it here to share the writer between two tasks safely.
+use tokio::sync::Mutex;
+
+// The protocol module from our own library (`src/protocol.rs`).
+// The server uses the very same code to build the lines it sends us,
+// so both sides always agree on the format.
+use chatty_rusty::protocol::{ChatMessage, Frame};
+
+// `BTreeMap` is a map that keeps its keys sorted. We use it to remember the
+// messages we've shown, sorted by ID, so the oldest one is always first.
+use std::collections::BTreeMap;
+
+// `AtomicBool` is a bool that several tasks can read and change at the same
+// time without a Mutex. The hardware makes each read or write "atomic" -
+// it happens all at once, so no task ever sees a half-written value.
+// `Ordering` tells the compiler how strict to be; `Relaxed` is plenty for
+// a simple on/off switch like ours.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// `Duration` is a span of time, like "2 seconds".
+use std::time::Duration;
+
+// `IsTerminal` tells us whether we're printing to a terminal or to a file.
+use std::io::IsTerminal;
+
+// Sending and receiving files lives in `transfer.rs` next to this file,
+// the command line options in `config.rs`, the modes for scripts
+// (`--send`, `--stdin`, `--listen`) in `batch.rs`, the slash commands in
+// `commands.rs`, reading the keyboard, with Tab completion, in `input.rs`
+// the transcript kept with `--log` in `log.rs`, what happens when
+// someone mentions us in `notify.rs`, who's typing in `typing.rs`, and
+// encrypted private messages in `e2e.rs`.
+mod batch;
+mod commands;
+mod config;
+mod e2e;
+mod input;
+mod log;
+mod notify;
+mod transfer;
+mod typing;
+
+use commands::Action;
+use config::Config;
+use e2e::{E2e, Opened, SharedE2e, Step};
+use input::{Input, SharedNames};
+use log::Log;
+use notify::Notify;
+use transfer::Transfers;
+use typing::SharedTyping;
+
+// How many messages the client remembers so it can re-render them
+// when they're edited or deleted.
+const TRANSCRIPT_LIMIT: usize = 1000;
+
+// How often we tell the server how far we've read. Sending a `/read` for
+// every message would double the traffic in a busy room.
+const MARKER_INTERVAL: Duration = Duration::from_secs(2);
+
+// For each room, the newest message we've shown and not yet told the server
+// about. The read task fills it in and the marker task empties it - a plain
+// `std` Mutex because neither holds it across an `.await`.
+type Markers = Arc<std::sync::Mutex<BTreeMap<String, u64>>>;
+
+// This attribute macro transforms our main function into an async one
+// powered by the Tokio runtime - the engine that drives all our async code.
+#[tokio::main]
+
+// `async fn main()` is our client's entry point. Just like the server,
+// we use async so we can handle reading and writing concurrently without blocking.
+async fn main() {
+
+    // Read the command line options. If they don't make sense, say why and stop.
+    // `client 127.0.0.1:8081` connects to another server, e.g. one of
+    // several linked together.
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // `client log search` only reads files - no server needed.
+    if let Some(search) = &config.search {
+        std::process::exit(log::search(search));
+    }
+
+    // `TcpStream::connect` initiates a TCP connection to the server.
+    // This is the client equivalent of `TcpListener::bind` on the server -
+    // instead of waiting for connections it actively creates one.
+    // `.await` pauses until the connection is established, which fails if
+    // for example the server isn't running yet.
+    let socket = match TcpStream::connect(&config.server).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("error: can't connect to {}: {}", config.server, e);
+            std::process::exit(1);
+        }
+    };
+
+    // A script is using us - see `batch.rs`. The exit status tells it how it went.
+    if config.batch() {
+        std::process::exit(batch::run(config, socket).await);
+    }
+    println!("Connected to Chatty Rusty server!");
+
+    // `--nick` and `--room` work just like typing `/nick` and `/join`.
+    // We send them again whenever we `/connect` somewhere else.
+    let mut setup = String::new();
+    if let Some(nick) = &config.nick {
+        setup.push_str(&format!("/nick {}\n", nick));
+    }
+    if let Some(room) = &config.room {
+        setup.push_str(&format!("/join {}\n", room));
+    }
+    // Our identity for encrypted private messages - see `e2e.rs`. Without
+    // one the client still works, but private messages need `/plain`.
+    let e2e: Option<SharedE2e> = match E2e::load(&config.identity) {
+        Ok(e2e) => Some(Arc::new(std::sync::Mutex::new(e2e))),
+        Err(e) => {
+            println!("! no encrypted private messages: {}", e);
+            None
+        }
+    };
+    // Publish our public key, so others can send us encrypted messages.
+    if let Some(e2e) = &e2e {
+        setup.push_str(&e2e.lock().unwrap().setup_line());
+    }
+    // Then catch up: the server sends what we haven't read since last time.
+    setup.push_str("/unread\n");
+
+    // Whether replies are shown in full (`false`) or collapsed into a short
+    // note (`true`). The user switches it with `/threads` here, and the read
+    // task looks at it when printing, so both need a handle.
+    let collapsed = Arc::new(AtomicBool::new(false));
+
+    // What to do when someone mentions us - see `notify.rs`.
+    let notify = Notify::new(config.bell, config.notify.clone());
+
+    // The nicks and rooms we've seen, for Tab completion - see `input.rs`.
+    let names: SharedNames = Default::default();
+
+    // Who's typing - see `typing.rs`. None with `--no-typing`.
+    let typing: Option<SharedTyping> = config.typing.then(Default::default);
+    if let Some(typing) = &typing {
+        tokio::spawn(typing::show_in_title(typing.clone()));
+    }
+
+    // What the user types arrives from the line editor in `input.rs`.
+    let (mut input, mut typed) = Input::start(names.clone(), typing.clone());
+
+    // Our connection to the server. It becomes None if the server goes away,
+    // and the user can then `/connect` again or quit.
+    // Each connection gets a fresh log (see `log.rs`) if `--log` was given.
+    let new_log = || config.log.as_ref().map(|dir| Log::new(dir, config.log_format));
+    let start = |socket, address: &str| {
+        if let Some(e2e) = &e2e {
+            e2e.lock().unwrap().connected(address);
+        }
+        let transcript = Transcript::new(collapsed.clone(), notify.clone(), e2e.clone());
+        Session::start(socket, &setup, transcript, &names, typing.clone(), new_log())
+    };
+    let mut session = Some(start(socket, &config.server).await);
+
+    loop {
+        // Wait for the user to type a line. While we're connected we also
+        // watch the read task, which ends when the server disconnects.
+        // `tokio::select!` waits for whichever comes FIRST.
+        let line = match &mut session {
+            Some(current) => tokio::select! {
+                line = input.line() => line,
+                // The user is typing a message: let the room know.
+                _ = typed.next() => {
+                    current.send("/typing").await;
+                    continue;
+                }
+                _ = &mut current.read_task => {
+                    session = None;
+                    println!("* /connect <address> to connect again, or /quit");
+                    continue;
+                }
+            },
+            None => input.line().await,
+        };
+
+        // None means the user pressed Ctrl+D or Ctrl+C - they want to quit.
+        let Some(line) = line else {
+            println!("Disconnecting...");
+            typing::restore_title();
+            break;
+        };
+
+        // See `commands.rs` for what each command does.
+        match commands::parse(&line) {
+            Action::Quit => {
+                println!("Disconnecting...");
+                // Tell the server how far we got, for next time.
+                if let Some(current) = &session {
+                    current.send_markers().await;
+                }
+                typing::restore_title();
+                break;
+            }
+            Action::Help => {
+                commands::print_help();
+                // Then the server's own list, which includes its bots' commands.
+                if let Some(current) = &session {
+                    current.send("/help").await;
+                }
+            }
+            // The ANSI escape codes for "clear the screen" and "move the
+            // cursor to the top left corner".
+            Action::Clear => print!("\x1b[2J\x1b[H"),
+            // `/threads collapsed` and `/threads expanded` only change how WE
+            // display replies, so they never go to the server.
+            Action::Threads(value) => collapsed.store(value, Ordering::Relaxed),
+            Action::Usage(text) => println!("! {}", text),
+            // Connect to the new server BEFORE leaving the old one, so a typo
+            // in the address doesn't leave us with no connection at all.
+            Action::Connect(address) => match TcpStream::connect(&address).await {
+                Ok(socket) => {
+                    if let Some(old) = &session {
+                        old.send_markers().await;
+                    }
+                    // Names from the old server would only get in the way.
+                    names.lock().unwrap().clear();
+                    if let Some(typing) = &typing {
+                        typing.lock().unwrap().clear();
+                    }
+                    println!("Connected to {}", address);
+                    session = Some(start(socket, &address).await);
+                }
+                Err(e) => println!("! can't connect to {}: {}", address, e),
+            },
+            // Everything else needs a server.
+            _ if session.is_none() => println!("! not connected - /connect <address> first"),
+            // `/send <nick|#room> <path>` reads a file from OUR disk, so the
+            // client uploads it in the background while the user keeps chatting.
+            Action::SendFile { target, path } => {
+                if let Some(current) = &session {
+                    tokio::spawn(transfer::send_file(
+                        target,
+                        path,
+                        current.writer.clone(),
+                        current.transfers.clone(),
+                    ));
+                }
+            }
+            // Encrypted private messages and their keys - see `e2e.rs`.
+            Action::Private { .. } | Action::Verify(_) | Action::Trust(_) if e2e.is_none() => {
+                println!("! no encrypted private messages - /plain <nick> <text> sends one unencrypted")
+            }
+            Action::Private { to, text } => {
+                if let (Some(e2e), Some(current)) = (&e2e, &session) {
+                    let steps = e2e.lock().unwrap().private(&to, &text);
+                    follow(steps, &current.writer).await;
+                }
+            }
+            Action::Verify(nick) => {
+                if let (Some(e2e), Some(current)) = (&e2e, &session) {
+                    let steps = e2e.lock().unwrap().verify(&nick);
+                    follow(steps, &current.writer).await;
+                }
+            }
+            Action::Trust(nick) => {
+                if let (Some(e2e), Some(current)) = (&e2e, &session) {
+                    let steps = e2e.lock().unwrap().trust(&nick);
+                    follow(steps, &current.writer).await;
+                }
+            }
+            Action::Server(line) => {
+                if let Some(current) = &session {
+                    current.send(&line).await;
+                }
+            }
+        }
+    }
+}
+
+// One connection to a server.
+// - `writer`: where we send lines. Wrapped in Arc<Mutex<...>> because file
+//   uploads running in the background write to it too.
+// - `transfers`: file transfers in progress on this connection.
+// - `read_task`: the task printing what the server sends. It ends when the
+//   server disconnects.
+// - `markers`: how far we've read, waiting to be sent by `marker_task`.
+struct Session {
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    transfers: Arc<Mutex<Transfers>>,
+    read_task: JoinHandle<()>,
+    markers: Markers,
+    marker_task: JoinHandle<()>,
+}
+
+impl Session {
+    // Send `setup` over a new connection and start reading from it.
+    // Each connection gets a fresh `transcript` - message IDs from another
+    // server mean nothing here.
+    async fn start(
+        socket: TcpStream,
+        setup: &str,
+        transcript: Transcript,
+        names: &SharedNames,
+        typing: Option<SharedTyping>,
+        log: Option<Log>,
+    ) -> Session {
+        // Split the TcpStream into independent read and write halves.
+        // - `reader`: used to receive incoming messages FROM the server
+        // - `writer`: used to send our messages TO the server
+        // We split because the read task needs the read half while we keep
+        // the write half, and Rust's ownership rules don't allow two owners
+        // of the same value.
+        let (reader, mut writer) = socket.into_split();
+        if let Err(e) = writer.write_all(setup.as_bytes()).await {
+            println!("Error sending message: {}", e);
+        }
+
+        // File transfers in progress. The read task receives the file data
+        // and `/send` starts uploads, so both get a handle.
+        let transfers = Arc::new(Mutex::new(Transfers::new()));
+
+        // The transcript notes every message it shows, and every
+        // MARKER_INTERVAL we send the server a `/read` for each room.
+        let markers = transcript.markers.clone();
+        let writer = Arc::new(Mutex::new(writer));
+        let read_task = tokio::spawn(read_server(
+            BufReader::new(reader),
+            transcript,
+            transfers.clone(),
+            names.clone(),
+            typing,
+            log,
+            writer.clone(),
+        ));
+        let session_writer = writer.clone();
+        let session_markers = markers.clone();
+        let marker_task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MARKER_INTERVAL);
+            loop {
+                interval.tick().await;
+                if !send_markers(&session_writer, &session_markers).await {
+                    break;
+                }
+            }
+        });
+        Session {
+            writer,
+            transfers,
+            read_task,
+            markers,
+            marker_task,
+        }
+    }
+
+    // Send any read markers we haven't sent yet, right now.
+    async fn send_markers(&self) {
+        send_markers(&self.writer, &self.markers).await;
+    }
+
+    // Send one line to the server.
+    async fn send(&self, line: &str) {
+        // `if let Err(e)` means: if write_all returns an error capture
+        // it as `e` and handle it - otherwise do nothing on success.
+        let line = format!("{}\n", line);
+        if let Err(e) = self.writer.lock().await.write_all(line.as_bytes()).await {
+            println!("Error sending message: {}", e);
+        }
+    }
+}
+
+// Leaving a connection (switching servers, or the server went away) stops
+// its tasks. Dropping the writer and the reader closes the socket.
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.read_task.abort();
+        self.marker_task.abort();
+    }
+}
+
+// Send a `/read <room> <id>` for each room in `markers`, and forget them.
+// Returns false if the connection is gone.
+async fn send_markers(writer: &Arc<Mutex<OwnedWriteHalf>>, markers: &Markers) -> bool {
+    // `take` swaps in an empty map, so the lock is only held for a moment.
+    let pending = std::mem::take(&mut *markers.lock().unwrap());
+    for (room, id) in pending {
+        let line = format!("/read {} {}\n", room, id);
+        if writer.lock().await.write_all(line.as_bytes()).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+// Do what `e2e.rs` says: show lines to the user and send lines to the server.
+async fn follow(steps: Vec<Step>, writer: &Arc<Mutex<OwnedWriteHalf>>) {
+    for step in steps {
+        match step {
+            Step::Show(text) => println!("{}", text),
+            Step::Send(line) => {
+                let line = format!("{}\n", line);
+                if let Err(e) = writer.lock().await.write_all(line.as_bytes()).await {
+                    println!("Error sending message: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// The read task: print everything the server sends until it disconnects.
+// `writer` is for sending encrypted messages once the key they wait for arrives.
+async fn read_server(
+    mut server_reader: BufReader<OwnedReadHalf>,
+    mut transcript: Transcript,
+    transfers: Arc<Mutex<Transfers>>,
+    names: SharedNames,
+    typing: Option<SharedTyping>,
+    mut log: Option<Log>,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+) {
+    // A reusable String buffer that will hold each incoming message from the server.
+    // We reuse the same buffer on every iteration to avoid allocating a new
+    // String each time, which is more memory efficient.
+    let mut server_line = String::new();
+    loop {
+        // Wait for a complete line to arrive from the server.
+        // `.await` pauses here without blocking - other tasks can run freely.
+        match server_reader.read_line(&mut server_line).await {
+
+            // `Ok(0)` means zero bytes were read - the server has disconnected.
+            // We notify the user and break out of the loop ending this task.
+            Ok(0) => {
+                println!("Server disconnected.");
+                break;
+            }
+
+            // `Ok(_)` means we received some bytes - we have a complete line.
+            // We use `_` here because we don't need to know how many bytes arrived,
+            // we just know the read was successful.
+            // We decode the line into a Frame, display it and clear the buffer
+            // for the next iteration.
+            Ok(_) => {
+                let mut frame = Frame::parse(&server_line);
+                // An encrypted private message is opened before anything else
+                // looks at it, so the log gets what it says. Keys we asked for
+                // go to `e2e.rs` too.
+                let mut encrypted = false;
+                if let Some(e2e) = &transcript.e2e {
+                    match &mut frame {
+                        Some(Frame::Private { from, to, text, .. }) => {
+                            match e2e.lock().unwrap().open(from, to, text) {
+                                Opened::Plain => {}
+                                Opened::Read(plain) => {
+                                    *text = plain;
+                                    encrypted = true;
+                                }
+                                Opened::Unreadable(reason) => {
+                                    *text = format!("(can't read this encrypted message: {})", reason);
+                                    encrypted = true;
+                                }
+                            }
+                        }
+                        Some(Frame::Key { nick, key }) => {
+                            let steps = e2e.lock().unwrap().learn(nick, key.as_deref());
+                            follow(steps, &writer).await;
+                        }
+                        Some(Frame::Nick { old, new }) => e2e.lock().unwrap().renamed(old, new),
+                        _ => {}
+                    }
+                }
+                // Remember any nicks and rooms in it for Tab completion.
+                // And write it to the log, if we keep one.
+                if let Some(frame) = &frame {
+                    names.lock().unwrap().learn(frame);
+                    if let Some(typing) = &typing {
+                        typing.lock().unwrap().learn(frame);
+                    }
+                    if let Some(log) = &mut log {
+                        log.record(frame).await;
+                    }
+                }
+                // `apply` gives us None when there is nothing to show,
+                // e.g. for a duplicate message.
+                // The `@` pattern gives the whole frame a name while
+                // also checking which variant it is.
+                let text = match frame {
+                    Some(
+                        frame @ (Frame::Offer { .. }
+                        | Frame::Chunk { .. }
+                        | Frame::Done { .. }
+                        | Frame::Uploaded { .. }
+                        | Frame::Reject { .. }),
+                    ) => transfers.lock().await.apply(frame).await,
+                    Some(Frame::Private { time, from, to, text }) if encrypted => {
+                        Some(private_line(&time, &from, &to, &text, true))
+                    }
+                    Some(frame) => transcript.apply(frame),
+                    // A line we don't understand - show it as it is
+                    // rather than silently losing it.
+                    None => Some(server_line.trim_end().to_string()),
+                };
+                if let Some(text) = text {
+                    println!("{}", text);
+                }
+                server_line.clear();
+            }
+
+            // `Err` means something went wrong with the connection.
+            // We log the error and break out of the loop ending this task.
+            Err(e) => {
+                println!("Error reading from server: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+// The client's memory of the conversation.
+// - `last_seen_id`: the highest message ID we have displayed so far.
+//   Because the server hands out IDs in increasing order, any message with an
+//   ID we've already passed is a duplicate (for example one we receive again
+//   after asking the server to replay history with `/since <id>`).
+// - `messages`: the recent messages we've shown, so that when one is edited,
+//   deleted or reacted to we can print it again in its new form.
+// - `collapsed`: the `/threads` switch shared with the write task.
+// - `thread_left`: how many MSG lines of a `/thread` answer are still to come.
+//   Those are printed even if we've seen them before - the user asked for them.
+// - `me`: our own nick, to spot messages mentioning us. The server puts every
+//   new connection in #general before sending it anything else, so the first
+//   JOIN we get is our own; after that NICK lines tell us when it changes.
+// - `notify`: what to do when we're mentioned.
+// - `markers`: the newest message shown in each room, for read markers.
+// - `e2e`: our identity for encrypted private messages, if we have one.
+struct Transcript {
+    last_seen_id: u64,
+    messages: BTreeMap<u64, Shown>,
+    collapsed: Arc<AtomicBool>,
+    thread_left: usize,
+    me: Option<String>,
+    notify: Notify,
+    markers: Markers,
+    e2e: Option<SharedE2e>,
+}
+
+// A message we've shown, together with its current reactions
+// as (emoji, count) pairs.
+#[derive(Clone)]
+struct Shown {
+    msg: ChatMessage,
+    reactions: Vec<(String, usize)>,
+}
+
+impl Transcript {
+    fn new(collapsed: Arc<AtomicBool>, notify: Notify, e2e: Option<SharedE2e>) -> Self {
+        Transcript {
+            last_seen_id: 0,
+            messages: BTreeMap::new(),
+            collapsed,
+            thread_left: 0,
+            me: None,
+            notify,
+            markers: Markers::default(),
+            e2e,
+        }
+    }
+
+    // Update our memory with a frame from the server and return the text
+    // to display for it, or None if nothing should be displayed.
+    fn apply(&mut self, frame: Frame) -> Option<String> {
+        match frame {
+            // Part of a thread the user asked for - always show it, expanded.
+            Frame::Msg(msg) if self.thread_left > 0 => {
+                self.thread_left -= 1;
+                let shown = self.remember(msg);
+                let text = match shown.msg.reply_to {
+                    Some(_) => format!("    ↳ {}", render(&shown)),
+                    None => render(&shown),
+                };
+                Some(self.mark(&shown.msg, text))
+            }
+            // A chat message we've already shown - skip it.
+            Frame::Msg(msg) if msg.id <= self.last_seen_id => None,
+            Frame::Msg(msg) => {
+                self.last_seen_id = msg.id;
+                let shown = self.remember(msg);
+                // A new message mentioning us: get the user's attention.
+                if self.mentions_me(&shown.msg) {
+                    self.notify.mention(&shown.msg);
+                }
+                match shown.msg.reply_to {
+                    None => Some(self.mark(&shown.msg, render(&shown))),
+                    Some(_) if !self.collapsed.load(Ordering::Relaxed) => {
+                        Some(self.mark(&shown.msg, format!("    ↳ {}", render(&shown))))
+                    }
+                    // Collapsed: just tell the user the thread has grown.
+                    Some(_) => {
+                        let root = self.root_of(shown.msg.id);
+                        let replies = self.replies_in_thread(root);
+                        Some(format!(
+                            "    ↳ #{} has {} {} - /thread {} to read",
+                            root,
+                            replies,
+                            if replies == 1 { "reply" } else { "replies" },
+                            root
+                        ))
+                    }
+                }
+            }
+            // Our terminal can't go back and change a line it already printed,
+            // so "re-rendering" means printing the message again as it is now.
+            Frame::Edit { id, text } => match self.messages.get_mut(&id) {
+                Some(shown) => {
+                    shown.msg.text = text;
+                    shown.msg.edited = true;
+                    Some(render(shown))
+                }
+                None => Some(format!("* #{} was edited: {}", id, text)),
+            },
+            Frame::Delete { id } => match self.messages.remove(&id) {
+                Some(Shown { msg, .. }) => Some(format!(
+                    "[{}] [{}] #{} {}: (message deleted)",
+                    clock(&msg.time),
+                    msg.room,
+                    msg.id,
+                    msg.sender
+                )),
+                None => Some(format!("* #{} was deleted", id)),
+            },
+            // Reactions on a message we never saw (e.g. it's older than
+            // anything we remember) aren't worth interrupting the user for.
+            Frame::React { id, counts } => {
+                let shown = self.messages.get_mut(&id)?;
+                shown.reactions = counts;
+                Some(render(shown))
+            }
+            Frame::Thread { root, count } => {
+                self.thread_left = count;
+                Some(format!("* thread #{} ({} messages)", root, count))
+            }
+            Frame::Join { nick, room } => {
+                if self.me.is_none() {
+                    self.me = Some(nick.clone());
+                }
+                Some(format!("* {} joined {}", nick, room))
+            }
+            Frame::Part { nick, room, reason } if reason.is_empty() => {
+                Some(format!("* {} left {}", nick, room))
+            }
+            Frame::Part { nick, room, reason } => {
+                Some(format!("* {} left {} ({})", nick, room, reason))
+            }
+            Frame::Nick { old, new } => {
+                if self.me.as_ref() == Some(&old) {
+                    self.me = Some(new.clone());
+                }
+                Some(format!("* {} is now known as {}", old, new))
+            }
+            Frame::Info(text) => Some(format!("* {}", text)),
+            Frame::Error(text) => Some(format!("! {}", text)),
+            Frame::Pong(token) => Some(format!("* pong {}", token)),
+            Frame::Private { time, from, to, text } => {
+                Some(private_line(&time, &from, &to, &text, false))
+            }
+            // With an identity, `e2e.rs` takes care of keys. Without one, it's
+            // the answer to a `/key` typed by hand.
+            Frame::Key { .. } if self.e2e.is_some() => None,
+            Frame::Key { nick, key: Some(key) } => Some(format!("* {}'s key: {}", nick, key)),
+            Frame::Key { nick, key: None } => Some(format!("* {} has no key", nick)),
+            Frame::Topic { room, topic } => Some(format!("* topic of {}: {}", room, topic)),
+            Frame::Welcome {
+                server,
+                version,
+                users,
+            } => Some(banner(format!(
+                "═══ Welcome to {} - Chatty Rusty {} - {} {} online ═══",
+                server,
+                version,
+                users,
+                if users == 1 { "person" } else { "people" }
+            ))),
+            Frame::Motd(text) => Some(banner(format!("│ {}", text))),
+            // Shown in the status area rather than the transcript - see `typing.rs`.
+            Frame::Typing { .. } => None,
+            // File transfer frames are handled by `Transfers`, not here.
+            Frame::Offer { .. }
+            | Frame::Chunk { .. }
+            | Frame::Done { .. }
+            | Frame::Uploaded { .. }
+            | Frame::Reject { .. } => None,
+        }
+    }
+
+    // Does `msg` mention us? Our own messages don't count.
+    fn mentions_me(&self, msg: &ChatMessage) -> bool {
+        match &self.me {
+            Some(me) => msg.mentions.contains(me) && (msg.sender != *me || msg.origin.is_some()),
+            None => false,
+        }
+    }
+
+    // Highlight `text`, the rendered `msg`, if it mentions us.
+    fn mark(&self, msg: &ChatMessage, text: String) -> String {
+        if self.mentions_me(msg) {
+            notify::highlight(&text)
+        } else {
+            text
+        }
+    }
+
+    // Store a message (keeping any reactions we already know about) and
+    // return a copy of what we stored, ready to be rendered.
+    // Every message goes through here before it's shown, so it's also where
+    // we note that it's been read.
+    fn remember(&mut self, msg: ChatMessage) -> Shown {
+        let mut markers = self.markers.lock().unwrap();
+        let marker = markers.entry(msg.room.clone()).or_insert(0);
+        *marker = (*marker).max(msg.id);
+        drop(markers);
+
+        // `remove` hands us the old entry if there was one.
+        let reactions = self
+            .messages
+            .remove(&msg.id)
+            .map(|old| old.reactions)
+            .unwrap_or_default();
+        let shown = Shown { msg, reactions };
+        self.messages.insert(shown.msg.id, shown.clone());
+        // Forget the oldest message once we remember too many.
+        if self.messages.len() > TRANSCRIPT_LIMIT {
+            self.messages.pop_first();
+        }
+        shown
+    }
+
+    // Follow `reply_to` links upwards to find the message that started
+    // the thread. We stop at the oldest parent we still remember.
+    fn root_of(&self, mut id: u64) -> u64 {
+        while let Some(parent) = self.messages.get(&id).and_then(|s| s.msg.reply_to) {
+            if !self.messages.contains_key(&parent) {
+                return parent;
+            }
+            id = parent;
+        }
+        id
+    }
+
+    // Count the replies we know about in the thread started by `root`.
+    fn replies_in_thread(&self, root: u64) -> usize {
+        self.messages
+            .keys()
+            .filter(|&&id| id != root && self.root_of(id) == root)
+            .count()
+    }
+}
+
+// Turn a chat message into the text we show the user, e.g.
+// [09:41:07] [#general] #17 alice: hello! [👍 2] [🎉 1]
+// Replies also say which message they answer:
+// [09:41:12] [#general] #18 bob (re #17): thanks!
+fn render(shown: &Shown) -> String {
+    let msg = &shown.msg;
+    // `if` is an expression in Rust, so it can produce a value directly.
+    let edited = if msg.edited { " (edited)" } else { "" };
+    let parent = match msg.reply_to {
+        Some(parent) => format!(" (re #{})", parent),
+        None => String::new(),
+    };
+    // Messages relayed from a linked server show where they came from.
+    let sender = match &msg.origin {
+        Some(origin) => format!("{}@{}", msg.sender, origin),
+        None => msg.sender.clone(),
+    };
+    let mut text = format!(
+        "[{}] [{}] #{} {}{}: {}{}",
+        clock(&msg.time),
+        msg.room,
+        msg.id,
+        sender,
+        parent,
+        msg.text,
+        edited
+    );
+    for (emoji, count) in &shown.reactions {
+        text.push_str(&format!(" [{} {}]", emoji, count));
+    }
+    text
+}
+
+// A private message as we show it:
+// "[09:41:07] [private] alice -> bob: hi!", or "[private, encrypted]" if it
+// was end-to-end encrypted.
+fn private_line(time: &str, from: &str, to: &str, text: &str, encrypted: bool) -> String {
+    let kind = if encrypted { "private, encrypted" } else { "private" };
+    format!("[{}] [{}] {} -> {}: {}", clock(time), kind, from, to, text)
+}
+
+// The server's welcome and message of the day stand apart from the chat:
+// framed by `═` and `│`, and in cyan on a terminal (in a file or a pipe the
+// escape codes would just be noise).
+fn banner(text: String) -> String {
+    if std::io::stdout().is_terminal() {
+        format!("\x1b[36m{}\x1b[0m", text)
+    } else {
+        text
+    }
+}
+
+// Pick the time of day out of an RFC 3339 timestamp.
+// In "2026-10-18T09:41:07Z" characters 11 to 19 are "09:41:07".
+// `.get(11..19)` returns None instead of crashing if the string is shorter
+// than we expect, in which case we show the whole thing.
+fn clock(time: &str) -> &str {
+    time.get(11..19).unwrap_or(time)
+}
@@ -0,0 +1,80 @@
+// Getting the user's attention when someone mentions them with `@nick`.
+//
+// The server tags every message with the nicks it mentions (`mention=bob`),
+// so the client only has to compare them with its own nick. A message that
+// mentions us is always highlighted. On top of that:
+// - `--bell` rings the terminal bell
+// - `--notify CMD` runs CMD, e.g. to show a desktop notification:
+//
+//     client --notify 'notify-send "$CHATTY_FROM in $CHATTY_ROOM" "$CHATTY_TEXT"'
+//
+//   The message is passed in environment variables rather than pasted into
+//   the command, so nothing anybody writes can turn into a shell command:
+//   CHATTY_ID, CHATTY_ROOM, CHATTY_FROM and CHATTY_TEXT.
+
+use std::io::IsTerminal;
+
+use tokio::process::Command;
+
+use chatty_rusty::protocol::ChatMessage;
+
+// The ANSI escape codes for "bold yellow" and "back to normal".
+const HIGHLIGHT: &str = "\x1b[1;33m";
+const NORMAL: &str = "\x1b[0m";
+
+#[derive(Clone)]
+pub struct Notify {
+    bell: bool,
+    command: Option<String>,
+}
+
+impl Notify {
+    pub fn new(bell: bool, command: Option<String>) -> Notify {
+        Notify { bell, command }
+    }
+
+    // Someone mentioned us in `msg`: ring the bell and run the command if
+    // the user asked for them. The command runs in the background.
+    pub fn mention(&self, msg: &ChatMessage) {
+        if self.bell {
+            // The bell is a character like any other - the terminal beeps
+            // (or flashes) when it's printed. It goes out with the next line.
+            print!("\x07");
+        }
+        if let Some(command) = &self.command {
+            tokio::spawn(run(command.clone(), msg.clone()));
+        }
+    }
+}
+
+// Make a line stand out. Only on a terminal: in a file or a pipe the escape
+// codes would just be noise.
+pub fn highlight(text: &str) -> String {
+    if std::io::stdout().is_terminal() {
+        format!("{}{}{}", HIGHLIGHT, text, NORMAL)
+    } else {
+        text.to_string()
+    }
+}
+
+// Run the `--notify` command for `msg` and complain if it fails.
+async fn run(command: String, msg: ChatMessage) {
+    let sender = match &msg.origin {
+        Some(origin) => format!("{}@{}", msg.sender, origin),
+        None => msg.sender.clone(),
+    };
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .env("CHATTY_ID", msg.id.to_string())
+        .env("CHATTY_ROOM", &msg.room)
+        .env("CHATTY_FROM", sender)
+        .env("CHATTY_TEXT", &msg.text)
+        .status()
+        .await;
+    match status {
+        Ok(status) if !status.success() => println!("! the --notify command failed ({})", status),
+        Ok(_) => {}
+        Err(e) => println!("! can't run the --notify command: {}", e),
+    }
+}
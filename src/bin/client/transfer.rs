@@ -0,0 +1,318 @@
+// Sending and receiving files.
+//
+// `/send <nick|#room> <path>` starts an upload task that streams the file to
+// the server in base64 chunks (see `src/bin/server/transfer.rs` for the
+// lines involved). The task locks the writer for one chunk at a time, so
+// anything the user types in between still goes out straight away.
+//
+// When someone sends us a file we get an OFFER. Typing `/accept <id>` makes
+// the server start sending CHUNK lines, which we write into the `downloads`
+// folder and check against the SHA-256 fingerprint from the offer.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::Mutex;
+
+// `Sha256` computes a SHA-256 fingerprint of a file and `Digest` is the trait
+// that gives it the `new()`, `update()` and `finalize()` methods.
+use sha2::{Digest, Sha256};
+
+use chatty_rusty::protocol::{base64_decode, base64_encode, Frame};
+
+// How many bytes of the file go into each `/chunk` line.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// Received files are saved in this folder, next to where the client runs.
+const DOWNLOAD_DIR: &str = "downloads";
+
+// A file someone offered us, waiting for `/accept`.
+struct Offer {
+    sender: String,
+    name: String,
+    size: u64,
+    sha256: String,
+}
+
+// A file we're receiving right now.
+struct Download {
+    offer: Offer,
+    path: PathBuf,
+    file: File,
+    hasher: Sha256,
+    received: u64,
+    progress: Progress,
+}
+
+// Everything the client keeps track of about transfers. The read task
+// (which receives OFFER and CHUNK lines) and the upload tasks both use it,
+// so it's shared behind an `Arc<Mutex<...>>` like the writer.
+// - `next_token`: the token for our next upload
+// - `rejected`: uploads the server refused - their tasks should stop sending
+pub struct Transfers {
+    next_token: u64,
+    rejected: HashSet<u64>,
+    offers: HashMap<u64, Offer>,
+    downloads: HashMap<u64, Download>,
+}
+
+impl Transfers {
+    pub fn new() -> Self {
+        Transfers {
+            next_token: 1,
+            rejected: HashSet::new(),
+            offers: HashMap::new(),
+            downloads: HashMap::new(),
+        }
+    }
+
+    // Handle a transfer frame from the server and return the text to show
+    // the user, if any.
+    pub async fn apply(&mut self, frame: Frame) -> Option<String> {
+        match frame {
+            Frame::Offer {
+                xfer,
+                sender,
+                size,
+                sha256,
+                name,
+            } => {
+                let text = format!(
+                    "* {} wants to send you {} ({}) - /accept {} or /decline {}",
+                    sender,
+                    name,
+                    human_size(size),
+                    xfer,
+                    xfer
+                );
+                self.offers.insert(
+                    xfer,
+                    Offer {
+                        sender,
+                        name,
+                        size,
+                        sha256,
+                    },
+                );
+                Some(text)
+            }
+            Frame::Chunk { xfer, data } => {
+                let Some(bytes) = base64_decode(&data) else {
+                    return Some(format!("! file #{} sent us invalid data", xfer));
+                };
+                let download = match self.download(xfer).await {
+                    Ok(download) => download,
+                    Err(e) => return Some(e),
+                };
+                download.hasher.update(&bytes);
+                download.received += bytes.len() as u64;
+                if let Err(e) = download.file.write_all(&bytes).await {
+                    return Some(format!("! can't write {}: {}", download.path.display(), e));
+                }
+                download
+                    .progress
+                    .update(download.received, download.offer.size)
+                    .map(|percent| format!("↓ {} {}%", download.offer.name, percent))
+            }
+            Frame::Done { xfer } => {
+                // An empty file has no chunks, so make sure the download exists.
+                if let Err(e) = self.download(xfer).await {
+                    return Some(e);
+                }
+                let mut download = self.downloads.remove(&xfer)?;
+                let _ = download.file.flush().await;
+                let sha256 = format!("{:x}", download.hasher.finalize());
+                if sha256 == download.offer.sha256 {
+                    Some(format!(
+                        "* saved {} from {} to {} (SHA-256 verified)",
+                        download.offer.name,
+                        download.offer.sender,
+                        download.path.display()
+                    ))
+                } else {
+                    // Don't keep a damaged file around.
+                    drop(download.file);
+                    let _ = tokio::fs::remove_file(&download.path).await;
+                    Some(format!(
+                        "! {} was damaged on the way (SHA-256 mismatch) and was deleted",
+                        download.offer.name
+                    ))
+                }
+            }
+            Frame::Uploaded { token, xfer } => {
+                Some(format!("* upload {} done, offered as file #{}", token, xfer))
+            }
+            Frame::Reject { token, reason } => {
+                // `insert` returns false if we already knew - the server
+                // rejects every chunk still on its way, no need to repeat.
+                self.rejected
+                    .insert(token)
+                    .then(|| format!("! upload {} failed: {}", token, reason))
+            }
+            _ => None,
+        }
+    }
+
+    // Get the download for `xfer`, starting it if this is its first chunk.
+    async fn download(&mut self, xfer: u64) -> Result<&mut Download, String> {
+        if !self.downloads.contains_key(&xfer) {
+            let offer = self
+                .offers
+                .remove(&xfer)
+                .ok_or(format!("! got data for file #{} we never accepted", xfer))?;
+            let path = download_path(xfer, &offer.name);
+            tokio::fs::create_dir_all(DOWNLOAD_DIR)
+                .await
+                .map_err(|e| format!("! can't create {}: {}", DOWNLOAD_DIR, e))?;
+            let file = File::create(&path)
+                .await
+                .map_err(|e| format!("! can't create {}: {}", path.display(), e))?;
+            self.downloads.insert(
+                xfer,
+                Download {
+                    offer,
+                    path,
+                    file,
+                    hasher: Sha256::new(),
+                    received: 0,
+                    progress: Progress::new(),
+                },
+            );
+        }
+        // We just made sure it's there.
+        self.downloads
+            .get_mut(&xfer)
+            .ok_or(format!("! file #{} went missing", xfer))
+    }
+}
+
+// Upload the file at `path` to `target` (a nick or a #room).
+// This runs as its own task so the user can keep chatting meanwhile.
+pub async fn send_file(
+    target: String,
+    path: String,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    transfers: Arc<Mutex<Transfers>>,
+) {
+    if let Err(e) = upload(&target, &path, &writer, &transfers).await {
+        println!("! can't send {}: {}", path, e);
+    }
+}
+
+async fn upload(
+    target: &str,
+    path: &str,
+    writer: &Mutex<OwnedWriteHalf>,
+    transfers: &Mutex<Transfers>,
+) -> std::io::Result<()> {
+    // The name the recipient sees is just the file name, without the folders.
+    let name = Path::new(path)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string());
+    let size = tokio::fs::metadata(path).await?.len();
+
+    // First pass: compute the SHA-256 fingerprint, reading the file in pieces
+    // so even a big file never has to fit in memory at once.
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let sha256 = format!("{:x}", hasher.finalize());
+
+    // Pick a token for this upload.
+    let token = {
+        let mut transfers = transfers.lock().await;
+        transfers.next_token += 1;
+        transfers.next_token - 1
+    };
+
+    let start = format!("/upload {} {} {} {} {}\n", token, target, size, sha256, name);
+    writer.lock().await.write_all(start.as_bytes()).await?;
+
+    // Second pass: send the file chunk by chunk.
+    let mut file = File::open(path).await?;
+    let mut sent = 0;
+    let mut progress = Progress::new();
+    loop {
+        // Stop if the server has refused the upload.
+        if transfers.lock().await.rejected.contains(&token) {
+            return Ok(());
+        }
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        let chunk = format!("/chunk {} {}\n", token, base64_encode(&buf[..n]));
+        // The lock is only held for this one line.
+        writer.lock().await.write_all(chunk.as_bytes()).await?;
+        sent += n as u64;
+        if let Some(percent) = progress.update(sent, size) {
+            println!("↑ {} {}%", name, percent);
+        }
+    }
+    let end = format!("/end {}\n", token);
+    writer.lock().await.write_all(end.as_bytes()).await?;
+    Ok(())
+}
+
+// Remembers the last progress we reported, so we only print a line every 10%.
+struct Progress {
+    last: u64,
+}
+
+impl Progress {
+    fn new() -> Self {
+        Progress { last: 0 }
+    }
+
+    // Return the new percentage if it reached the next 10% step.
+    fn update(&mut self, done: u64, total: u64) -> Option<u64> {
+        // `max(1)` avoids dividing by zero for an empty file.
+        let percent = done * 100 / total.max(1);
+        let step = percent / 10 * 10;
+        if step > self.last {
+            self.last = step;
+            Some(step)
+        } else {
+            None
+        }
+    }
+}
+
+// Where to save a received file. The sender picks the name, so we only keep
+// the last part of it - a name like "../../.bashrc" must not escape the
+// downloads folder. If the file already exists we put the transfer ID in front.
+fn download_path(xfer: u64, name: &str) -> PathBuf {
+    let name = Path::new(name)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| format!("file-{}", xfer));
+    let path = Path::new(DOWNLOAD_DIR).join(&name);
+    if path.exists() {
+        Path::new(DOWNLOAD_DIR).join(format!("{}-{}", xfer, name))
+    } else {
+        path
+    }
+}
+
+// Show a number of bytes the way people read them, e.g. "1.5 MB".
+fn human_size(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    } else if bytes >= 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{} bytes", bytes)
+    }
+}
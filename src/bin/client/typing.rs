@@ -0,0 +1,136 @@
+// "alice is typing…" - showing who's writing something right now.
+//
+// While you type a message (not a command), the client sends `/typing` to the
+// server, at most once every SEND_EVERY. The server passes it on to everyone
+// else in your active room as `TYPING <nick> <room>` and forgets it straight
+// away - it's never stored or sent over server links.
+//
+// We show someone as typing until their message arrives, they leave, or
+// nothing more has come from them for SHOW_FOR. Where we show it:
+// - after what you're typing yourself, dimmed, e.g. `hi  (alice is typing…)`.
+//   The line editor only redraws the line when you press a key, so this is
+//   up to date while you type.
+// - in the terminal's title bar, which we can change at any moment, so it
+//   appears and disappears even when you're just watching.
+//
+// `--no-typing` turns all of it off: nothing is sent and nothing is shown.
+
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chatty_rusty::protocol::Frame;
+
+// How often we tell the server we're still typing.
+pub const SEND_EVERY: Duration = Duration::from_secs(3);
+
+// How long someone counts as typing after their last `TYPING`. A bit more
+// than two SEND_EVERY, so one late signal doesn't make them flicker.
+const SHOW_FOR: Duration = Duration::from_secs(7);
+
+// How often the title is brought up to date.
+const TITLE_EVERY: Duration = Duration::from_millis(500);
+
+// The title while nobody is typing.
+const TITLE: &str = "Chatty Rusty";
+
+// Who's typing: each nick with the room they're typing in and when we stop
+// showing them. The read task changes it, and the editor thread and the
+// title task read it, hence the `Arc<Mutex<...>>`.
+#[derive(Default)]
+pub struct Typing {
+    people: BTreeMap<String, (String, Instant)>,
+}
+
+pub type SharedTyping = Arc<Mutex<Typing>>;
+
+impl Typing {
+    // Keep track of who's typing from a frame from the server.
+    pub fn learn(&mut self, frame: &Frame) {
+        match frame {
+            Frame::Typing { nick, room } => {
+                let until = Instant::now() + SHOW_FOR;
+                self.people.insert(nick.clone(), (room.clone(), until));
+            }
+            // They've finished: here's the message.
+            Frame::Msg(msg) if msg.origin.is_none() => {
+                self.people.remove(&msg.sender);
+            }
+            Frame::Part { nick, room, .. }
+                if self
+                    .people
+                    .get(nick)
+                    .is_some_and(|(typing_in, _)| typing_in == room) =>
+            {
+                self.people.remove(nick);
+            }
+            Frame::Nick { old, new } => {
+                if let Some(typing) = self.people.remove(old) {
+                    self.people.insert(new.clone(), typing);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Forget everyone, e.g. after connecting to another server.
+    pub fn clear(&mut self) {
+        self.people.clear();
+    }
+
+    // "alice is typing…", "alice and bob are typing…", or None if nobody is.
+    // People we haven't heard from for SHOW_FOR are dropped first.
+    pub fn status(&mut self) -> Option<String> {
+        let now = Instant::now();
+        self.people.retain(|_, (_, until)| *until > now);
+        let nicks: Vec<&str> = self.people.keys().map(String::as_str).collect();
+        match nicks.as_slice() {
+            [] => None,
+            [one] => Some(format!("{} is typing…", one)),
+            [one, two] => Some(format!("{} and {} are typing…", one, two)),
+            [one, two, rest @ ..] => Some(format!(
+                "{}, {} and {} more are typing…",
+                one,
+                two,
+                rest.len()
+            )),
+        }
+    }
+}
+
+// Keep the terminal's title showing who's typing, forever. Only on a
+// terminal - in a file or a pipe the escape codes would just be noise.
+pub async fn show_in_title(typing: SharedTyping) {
+    if !std::io::stdout().is_terminal() {
+        return;
+    }
+    // Most terminals can save the title they had, so we can put it back
+    // when we quit (see `restore_title`).
+    print!("\x1b[22;0t");
+    let mut shown = None;
+    let mut interval = tokio::time::interval(TITLE_EVERY);
+    loop {
+        interval.tick().await;
+        let status = typing.lock().unwrap().status();
+        // Only write when it changes, not twice a second.
+        if status != shown {
+            let title = match &status {
+                Some(status) => format!("{} - {}", TITLE, status),
+                None => TITLE.to_string(),
+            };
+            // `ESC ] 2 ; <title> BEL` sets the window title.
+            print!("\x1b]2;{}\x07", title);
+            let _ = std::io::stdout().flush();
+            shown = status;
+        }
+    }
+}
+
+// Put back the title the terminal had before `show_in_title` changed it.
+pub fn restore_title() {
+    if std::io::stdout().is_terminal() {
+        print!("\x1b[23;0t");
+        let _ = std::io::stdout().flush();
+    }
+}
@@ -0,0 +1,338 @@
+// Sharing rooms between several copies of the server ("horizontal scaling").
+//
+// Everything the server broadcasts goes through a bus: anything that
+// implements the `Bus` trait below. What travels on it depends on the bus:
+// - `LocalBus`: the bus inside one process. Every frame for our own clients -
+//   a message for a room, someone joining, an answer to a command - is
+//   published on it, and it hands the frame to the connections it's for.
+//   It's always there, and with `--bus local` (the default) it's the only one.
+// - `RedisBus` (`--bus redis://HOST:PORT[/CHANNEL]`): Redis publish/subscribe.
+//   It only needs PUBLISH and SUBSCRIBE, so anything that speaks the Redis
+//   protocol works - Redis itself, Valkey, KeyDB or a stand-in for testing.
+// - A server link: one other server at the end of a channel.
+//
+// One server process can only hold so many connections. To handle more, run
+// several copies - each with its own `--listen` address and `--name` - and
+// connect them all to the same Redis with `--bus`. Whatever happens on one
+// copy (messages, joins, leaves, nick changes) is published there as the same
+// lines linked servers send each other (see `federation.rs`), and every
+// other copy publishes it on its own `LocalBus` for its own clients.
+//
+// Copies of the server on one bus are meant to look like ONE server, so
+// unlike people on linked servers, people on other copies are shown by
+// their nick alone, and a nick taken on one copy is taken on all of them
+// (see `federation.rs`).
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use chatty_rusty::protocol::Frame;
+
+use crate::federation;
+use crate::rooms::Shared;
+use crate::state::Db;
+
+// The bus is attached as a link with this name (see `federation.rs`), so no
+// server may be called that.
+pub const LINK_NAME: &str = "bus";
+
+// The Redis channel used when the `--bus` address doesn't name one.
+const DEFAULT_CHANNEL: &str = "chatty_rusty";
+
+// When the bus connection drops we try again after 1 second, then 2, 4...
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// Anything that can carry broadcasts: `T` is what travels on it - relay
+// lines for other servers unless it says otherwise (`T = String`).
+// `publish` is called while the state lock is held, so it must never wait:
+// implementations queue what they're given and let a task of their own
+// send it.
+// `Send + Sync` lets the state (and so the bus) move between tasks.
+pub trait Bus<T = String>: Send + Sync {
+    fn publish(&self, item: T);
+}
+
+// A channel to a task that sends things on: the writer task of a server
+// link, or the task in `run_redis` that shares room changes.
+impl<T: Send> Bus<T> for mpsc::UnboundedSender<T> {
+    fn publish(&self, item: T) {
+        // Only fails if the task is going away anyway.
+        let _ = self.send(item);
+    }
+}
+
+// Which bus `--bus` asked for. `PartialEq` lets `/reload` tell whether it
+// changed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BusKind {
+    Local,
+    Redis { addr: String, channel: String },
+}
+
+impl BusKind {
+    // Read a `--bus` value: `local` or `redis://HOST:PORT[/CHANNEL]`.
+    // Anything else is a mistake, and the server won't start with it.
+    pub fn parse(text: &str) -> Result<BusKind, String> {
+        if text == "local" {
+            return Ok(BusKind::Local);
+        }
+        let rest = text
+            .strip_prefix("redis://")
+            .ok_or(format!("unknown bus {} - use local or redis://HOST:PORT", text))?;
+        let (addr, channel) = match rest.split_once('/') {
+            Some((addr, channel)) if !channel.is_empty() => (addr, channel),
+            Some((addr, _)) => (addr, DEFAULT_CHANNEL),
+            None => (rest, DEFAULT_CHANNEL),
+        };
+        if addr.is_empty() {
+            return Err(format!("{} has no address", text));
+        }
+        Ok(BusKind::Redis {
+            addr: addr.to_string(),
+            channel: channel.to_string(),
+        })
+    }
+}
+
+// A frame for some of the clients connected to this process, by address.
+pub struct Broadcast {
+    pub to: Vec<String>,
+    pub frame: Frame,
+}
+
+// The bus inside one process. It knows the channel to every connection's
+// writer task (see `main.rs`), and publishing a `Broadcast` queues the frame
+// on the channels of everyone it's for.
+#[derive(Default)]
+pub struct LocalBus {
+    connections: HashMap<String, mpsc::UnboundedSender<Frame>>,
+}
+
+impl LocalBus {
+    // Start passing frames for `addr` into `tx`.
+    pub fn connect(&mut self, addr: &str, tx: mpsc::UnboundedSender<Frame>) {
+        self.connections.insert(addr.to_string(), tx);
+    }
+
+    // Forget `addr`. Dropping its `tx` tells the writer task to stop.
+    pub fn disconnect(&mut self, addr: &str) {
+        self.connections.remove(addr);
+    }
+}
+
+impl Bus<Broadcast> for LocalBus {
+    fn publish(&self, broadcast: Broadcast) {
+        for addr in &broadcast.to {
+            if let Some(tx) = self.connections.get(addr) {
+                // `send` only fails if the writer task has stopped, which
+                // means the client is disconnecting - nothing to do about it.
+                let _ = tx.send(broadcast.frame.clone());
+            }
+        }
+    }
+}
+
+// Redis publish/subscribe. `publish` queues the line for the publisher task
+// in `run_redis`, which owns the connection.
+pub struct RedisBus {
+    tx: mpsc::UnboundedSender<String>,
+}
+
+impl Bus for RedisBus {
+    fn publish(&self, line: String) {
+        let _ = self.tx.send(line);
+    }
+}
+
+// Connect this server to the bus and keep it connected. Runs for as long as
+// the server does.
+// `shared` brings the changes to the rooms that the other copies need to
+// hear about (see `rooms.rs`).
+pub async fn run(kind: BusKind, db: Db, mut shared: mpsc::UnboundedReceiver<Shared>) {
+    match kind {
+        // The `LocalBus` is part of the state from the start, so there's
+        // nothing to connect to.
+        BusKind::Local => {}
+        BusKind::Redis { addr, channel } => {
+            let mut delay = Duration::from_secs(1);
+            loop {
+                match run_redis(&addr, &channel, &db, &mut shared).await {
+                    // Only returns after it was connected, so start over.
+                    Ok(()) => delay = Duration::from_secs(1),
+                    Err(e) => println!("Message bus {}: {}", addr, e),
+                }
+                println!("Reconnecting to message bus {} in {}s", addr, delay.as_secs());
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+// One Redis connection, from connecting until it drops.
+//
+// Redis wants two connections: once a connection has sent SUBSCRIBE it may
+// only receive, so publishing needs a second one.
+async fn run_redis(
+    addr: &str,
+    channel: &str,
+    db: &Db,
+    shared: &mut mpsc::UnboundedReceiver<Shared>,
+) -> Result<(), String> {
+    let err = |e: std::io::Error| e.to_string();
+    let subscriber = TcpStream::connect(addr).await.map_err(err)?;
+    let publisher = TcpStream::connect(addr).await.map_err(err)?;
+
+    let (reader, mut writer) = subscriber.into_split();
+    let mut reader = BufReader::new(reader);
+    writer
+        .write_all(&command(&["SUBSCRIBE", channel]))
+        .await
+        .map_err(err)?;
+    // The first answer confirms the subscription: ["subscribe", channel, 1].
+    match read_array(&mut reader).await.map_err(err)?.first() {
+        Some(kind) if kind == "subscribe" => {}
+        _ => return Err("SUBSCRIBE was refused".to_string()),
+    }
+
+    // The publisher task sends whatever `RedisBus::publish` queued.
+    let (tx, rx) = mpsc::unbounded_channel();
+    let channel_name = channel.to_string();
+    let mut publish_task = tokio::spawn(publish_lines(publisher, channel_name, rx));
+
+    {
+        let mut state = db.lock().await;
+        federation::attach(&mut state, LINK_NAME, Box::new(RedisBus { tx }))?;
+        // Copies of the server that were already on the bus don't know about us.
+        federation::request_members(&mut state, LINK_NAME);
+    }
+    println!("Joined message bus {} on channel {}", addr, channel);
+
+    // Read messages until either connection fails.
+    let result = loop {
+        tokio::select! {
+            message = read_array(&mut reader) => match message {
+                // A message looks like ["message", channel, text].
+                Ok(parts) => {
+                    if let [kind, _, text] = &parts[..]
+                        && kind == "message"
+                    {
+                        federation::receive_line(&mut *db.lock().await, LINK_NAME, text);
+                    }
+                }
+                Err(e) => break Err(e.to_string()),
+            },
+            // Changes made while the bus was down waited here.
+            Some(change) = shared.recv() => federation::share(&mut *db.lock().await, change),
+            _ = &mut publish_task => break Err("can't publish".to_string()),
+        }
+    };
+    publish_task.abort();
+    federation::detach(&mut *db.lock().await, LINK_NAME);
+    println!("Lost message bus {}", addr);
+    result
+}
+
+// The publisher task: send every queued line as a PUBLISH command.
+async fn publish_lines(
+    socket: TcpStream,
+    channel: String,
+    mut rx: mpsc::UnboundedReceiver<String>,
+) {
+    let (reader, mut writer) = socket.into_split();
+    let mut reader = BufReader::new(reader);
+    while let Some(line) = rx.recv().await {
+        if writer
+            .write_all(&command(&["PUBLISH", &channel, &line]))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        // Redis answers with the number of subscribers that got it.
+        // We don't need the number, only to know the connection still works.
+        if read_value(&mut reader).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Redis speaks "RESP". A command is an array of "bulk strings", each one
+// prefixed with its length, so the text may contain anything - even newlines:
+//
+//   *2\r\n$9\r\nSUBSCRIBE\r\n$12\r\nchatty_rusty\r\n
+fn command(args: &[&str]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len());
+    for arg in args {
+        out.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    out.into_bytes()
+}
+
+// Read one reply that should be an array of plain values, such as a
+// pub/sub message.
+async fn read_array<R>(reader: &mut BufReader<R>) -> std::io::Result<Vec<String>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let header = read_line(reader).await?;
+    let count = match header.strip_prefix('*').map(str::parse::<usize>) {
+        Some(Ok(count)) => count,
+        _ => return Err(bad_reply(&header)),
+    };
+    let mut parts = Vec::with_capacity(count);
+    for _ in 0..count {
+        parts.push(read_value(reader).await?);
+    }
+    Ok(parts)
+}
+
+// Read one plain value: a simple string (`+OK`), a number (`:1`) or a bulk
+// string (`$5` followed by 5 bytes). An error (`-ERR ...`) becomes an `Err`.
+async fn read_value<R>(reader: &mut BufReader<R>) -> std::io::Result<String>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let line = read_line(reader).await?;
+    // `split_at(1)` needs at least one byte - an empty line isn't valid RESP.
+    if line.is_empty() {
+        return Err(bad_reply(&line));
+    }
+    let (kind, rest) = line.split_at(1);
+    match kind {
+        "+" | ":" => Ok(rest.to_string()),
+        "-" => Err(std::io::Error::other(rest.to_string())),
+        "$" => {
+            let len: usize = rest.parse().map_err(|_| bad_reply(&line))?;
+            // The text, then "\r\n".
+            let mut buf = vec![0u8; len + 2];
+            reader.read_exact(&mut buf).await?;
+            buf.truncate(len);
+            String::from_utf8(buf).map_err(|_| bad_reply("text that isn't UTF-8"))
+        }
+        _ => Err(bad_reply(&line)),
+    }
+}
+
+// Read one "\r\n" terminated line, without the ending.
+async fn read_line<R>(reader: &mut BufReader<R>) -> std::io::Result<String>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "connection closed",
+        ));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn bad_reply(what: &str) -> std::io::Error {
+    std::io::Error::other(format!("unexpected reply: {}", what))
+}
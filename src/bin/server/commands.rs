@@ -0,0 +1,596 @@
+// Everything that happens when a client sends a line starting with `/`.
+
+use std::borrow::Cow;
+
+use sha2::{Digest, Sha256};
+
+use chatty_rusty::protocol::{base64_decode, Frame};
+
+use crate::federation;
+use crate::handoff;
+use crate::motd;
+use crate::plugins;
+use crate::reload;
+use crate::rooms;
+use crate::search;
+use crate::state::{Db, State};
+use crate::transfer;
+
+// The name of the environment variable holding the operator password.
+// Operators may edit or delete anyone's message. If the variable isn't set,
+// nobody can become an operator.
+const OPER_PASSWORD_VAR: &str = "CHATTY_OPER_PASSWORD";
+
+// The longest reaction we accept, in characters. Enough for any emoji
+// (some are several characters glued together) or a short word like ":+1:".
+const MAX_REACTION_LEN: usize = 16;
+
+// The longest nick and room name we accept, in characters.
+const MAX_NAME_LEN: usize = 20;
+
+// The commands below, with a short description of each, for `/help`.
+#[cfg_attr(not(feature = "plugin-help"), allow(dead_code))]
+pub const HELP: &[(&str, &str)] = &[
+    ("/nick", "<name> [password] - change your nick (a registered one needs its password)"),
+    ("/register", "<password> - protect your nick, and your roles in rooms, with a password"),
+    ("/join", "#room [password] - join a room and talk in it"),
+    ("/part", "[#room] - leave a room"),
+    ("/topic", "<#room> [text|-] - show or change a room's topic"),
+    ("/mode", "<#room> [+|-][i|k|m|t|o|v|b] [password|nick] - show or change a room's modes"),
+    ("/invite", "<nick> <#room> - let someone into a room"),
+    ("/rooms", "- list the rooms with people in them, and what you haven't read"),
+    ("/unread", "- get the messages you haven't read in your rooms"),
+    ("/read", "<#room> <id> - you've read <#room> up to message <id>"),
+    ("/msg", "<#room|nick> <text> - talk in a room without switching to it, or privately"),
+    ("/since", "<id> - get the messages after <id>"),
+    ("/edit", "<id> <text> - change one of your messages"),
+    ("/delete", "<id> - delete one of your messages"),
+    ("/react", "<id> <emoji> - react to a message (again to take it back)"),
+    ("/reply", "<id> <text> - answer a message in its thread"),
+    ("/thread", "<id> - show the whole thread of a message"),
+    ("/search", "<words> [#room] [from:nick] [since:date] [page:N] - find messages"),
+    ("/mentions", "- the messages mentioning you that you haven't seen"),
+    ("/typing", "[#room] - tell a room you're typing (clients send it for you)"),
+    ("/setkey", "<key> - publish your encryption key (clients send it for you)"),
+    ("/key", "<nick> - get someone's encryption key"),
+    ("/accept", "<id> - receive a file someone offered you"),
+    ("/decline", "<id> - refuse a file"),
+    ("/motd", "[reload] - show the message of the day (operators: read it again)"),
+    ("/oper", "<password> - become an operator"),
+    ("/reload", "- read the configuration again (operators only)"),
+    ("/restart", "- restart the server without dropping anyone (operators only)"),
+    ("/ping", "[token] - answers PONG once everything you sent before is done"),
+];
+
+// Handle a line starting with `/` sent by the client at `addr`.
+pub async fn handle_command(text: &str, addr: &str, db: &Db) {
+    // Split the command name from its arguments, e.g. "/since 42" -> ("/since", "42").
+    let (command, args) = text.split_once(' ').unwrap_or((text, ""));
+
+    // `/reload` reads files, which may take a while, so it only takes the
+    // lock when it needs it (see `reload.rs`).
+    if command == "/reload" {
+        let replies = reload::command(db, addr).await;
+        let state = db.lock().await;
+        for frame in replies {
+            state.send_to(addr, frame);
+        }
+        return;
+    }
+
+    // `/nick` and `/register` may have to hash a nick password, which takes
+    // a while on purpose, so they let go of the lock meanwhile (see
+    // `rooms.rs`).
+    if command == "/nick" || command == "/register" {
+        let replies = if command == "/nick" {
+            change_nick(db, addr, args).await
+        } else {
+            register(db, addr, args).await
+        };
+        let state = db.lock().await;
+        for frame in replies {
+            state.send_to(addr, frame);
+        }
+        return;
+    }
+
+    // Every command replies with zero or more frames which we collect here
+    // and send back to this client only.
+    let mut replies = Vec::new();
+
+    // Other commands are quick, so we simply hold the lock for the whole command.
+    // `&mut *` turns the lock guard into a plain `&mut State`.
+    let mut guard = db.lock().await;
+    let state = &mut *guard;
+
+    match command {
+        // `/since <id>` asks for every message newer than `<id>` that is still
+        // in the history. A client uses this after reconnecting to catch up on
+        // what it missed - it just sends the last ID it saw.
+        "/since" => match args.trim().parse::<u64>() {
+            Ok(since) => {
+                let Some(client) = state.clients.get(addr) else {
+                    return;
+                };
+                // `filter` keeps only the messages we want: newer ones, from
+                // rooms this client is in.
+                for stored in state
+                    .history
+                    .iter()
+                    .filter(|s| s.msg.id > since && client.rooms.contains(&s.msg.room))
+                {
+                    replies.push(Frame::Msg(stored.msg.clone()));
+                    // Follow each message with its reactions, if it has any.
+                    if !stored.reactions.is_empty()
+                        && let Some(index) = state.position(stored.msg.id)
+                    {
+                        replies.push(state.reaction_frame(index));
+                    }
+                }
+            }
+            Err(_) => replies.push(Frame::Error("usage: /since <id>".to_string())),
+        },
+
+        // `/ping [token]` answers `PONG <token>`. A client reads our lines one
+        // at a time and its frames are queued in order, so by the time the
+        // PONG arrives everything it sent before has been handled - scripts
+        // use this to know when it's safe to hang up.
+        "/ping" => replies.push(Frame::Pong(args.trim().to_string())),
+
+        // `/oper <password>` turns this client into an operator.
+        "/oper" => {
+            // `std::env::var` reads an environment variable. It returns an
+            // error if the variable isn't set, and `.ok()` turns that into None.
+            match std::env::var(OPER_PASSWORD_VAR).ok() {
+                Some(password) if !password.is_empty() && same_secret(args, &password) => {
+                    if let Some(client) = state.clients.get_mut(addr) {
+                        client.operator = true;
+                    }
+                    println!("{} is now an operator", addr);
+                    replies.push(Frame::Info("you are now an operator".to_string()));
+                }
+                _ => replies.push(Frame::Error("wrong operator password".to_string())),
+            }
+        }
+
+        // `/join #room [password]` joins a room (creating it if nobody is in
+        // it yet) and makes it the room your messages go to. Some rooms need
+        // a password or an invitation - see `rooms.rs`.
+        "/join" => {
+            let (room, password) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+            if valid_room(room) {
+                if let Err(e) = state.enter(addr, room, password.trim()) {
+                    replies.push(Frame::Error(e));
+                }
+            } else {
+                replies.push(bad_room());
+            }
+        }
+
+        // `/part [#room]` leaves a room - the active one if none is given.
+        "/part" => {
+            let current = state.clients.get(addr).and_then(|c| c.room.clone());
+            // `then_some` turns a bool into an Option: Some(value) if true.
+            let room = (!args.trim().is_empty())
+                .then_some(args.trim().to_string())
+                .or(current);
+            match room {
+                Some(room) if state.clients.get(addr).is_some_and(|c| c.rooms.contains(&room)) => {
+                    state.part(addr, &room);
+                }
+                _ => replies.push(Frame::Error("you're not in that room".to_string())),
+            }
+        }
+
+        // `/msg #room <text>` talks in one of your rooms without making it
+        // your active room, and `/msg <nick> <text>` talks to one person.
+        "/msg" => match args.trim().split_once(' ') {
+            Some((room, text)) if room.starts_with('#') => {
+                if state.clients.get(addr).is_some_and(|c| c.rooms.contains(room)) {
+                    state.post(addr, room, text, None);
+                } else {
+                    replies.push(Frame::Error("you're not in that room".to_string()));
+                }
+            }
+            Some((nick, text)) => {
+                if let Err(e) = state.private(addr, nick, text) {
+                    replies.push(Frame::Error(e));
+                }
+            }
+            None => replies.push(Frame::Error("usage: /msg <#room|nick> <text>".to_string())),
+        },
+
+        // `/rooms` lists every room that has people in it, here or on a
+        // linked server.
+        "/rooms" => {
+            let mut rooms = federation::remote_members(state);
+            for client in state.clients.values() {
+                for room in &client.rooms {
+                    // Count the members of each room.
+                    *rooms.entry(room.clone()).or_insert(0) += 1;
+                }
+            }
+            // Rooms with unread messages are listed even if nobody's there.
+            let unread = state.read.counts(&state.nick(addr), &state.history);
+            for (room, count) in &unread {
+                if *count > 0 {
+                    rooms.entry(room.clone()).or_insert(0);
+                }
+            }
+            let current = state.clients.get(addr).and_then(|c| c.room.clone());
+            for (room, members) in rooms {
+                let marker = if Some(&room) == current.as_ref() { " (active)" } else { "" };
+                let unread = match unread.get(&room) {
+                    Some(&count) if count > 0 => format!(", {} unread", count),
+                    _ => String::new(),
+                };
+                replies.push(Frame::Info(format!(
+                    "{} - {} online{}{}",
+                    room, members, marker, unread
+                )));
+            }
+        }
+
+        // `/unread` sends again every message you haven't read in the rooms
+        // you're in, oldest first - all rooms together in ID order, so a
+        // client skipping IDs it has already seen doesn't skip any of them.
+        // A client sends it after connecting to show what was missed.
+        "/unread" => {
+            let Some(client) = state.clients.get(addr) else {
+                return;
+            };
+            let missed = state.read.messages(&client.nick, &state.history, |room| {
+                client.rooms.contains(room)
+            });
+            if missed.is_empty() {
+                replies.push(Frame::Info("nothing unread".to_string()));
+            } else {
+                let mut rooms: Vec<&str> = missed.iter().map(|msg| msg.room.as_str()).collect();
+                rooms.sort();
+                rooms.dedup();
+                replies.push(Frame::Info(format!(
+                    "unread since you were last here: {} in {}",
+                    missed.len(),
+                    rooms.join(" ")
+                )));
+                replies.extend(missed.into_iter().map(Frame::Msg));
+            }
+        }
+
+        // `/read <#room> <id>` moves your read marker: you've seen everything
+        // in <#room> up to message <id>. Clients send it as they show messages,
+        // so it doesn't answer unless something is wrong.
+        "/read" => {
+            let (room, id) = args.trim().split_once(' ').unwrap_or((args, ""));
+            match id.trim().parse::<u64>() {
+                Ok(id) if valid_room(room) && id < state.next_id => {
+                    let nick = state.nick(addr);
+                    state.read.mark(&nick, room, id);
+                }
+                _ => replies.push(Frame::Error("usage: /read <#room> <id>".to_string())),
+            }
+        }
+
+        // `/edit <id> <text>` replaces the text of one of your messages.
+        "/edit" => {
+            let (id, new_text) = args.split_once(' ').unwrap_or((args, ""));
+            match (id.parse::<u64>(), new_text.trim()) {
+                (Ok(_), "") | (Err(_), _) => {
+                    replies.push(Frame::Error("usage: /edit <id> <text>".to_string()))
+                }
+                (Ok(id), new_text) => {
+                    if let Err(e) = state.edit(addr, id, new_text) {
+                        replies.push(Frame::Error(e));
+                    }
+                }
+            }
+        }
+
+        // `/delete <id>` removes one of your messages.
+        "/delete" => match args.trim().parse::<u64>() {
+            Ok(id) => {
+                if let Err(e) = state.delete(addr, id) {
+                    replies.push(Frame::Error(e));
+                }
+            }
+            Err(_) => replies.push(Frame::Error("usage: /delete <id>".to_string())),
+        },
+
+        // `/react <id> <emoji>` adds a reaction to a message.
+        // Sending the same reaction again takes it back.
+        "/react" => {
+            let (id, emoji) = args.split_once(' ').unwrap_or((args, ""));
+            let emoji = emoji.trim();
+            match id.parse::<u64>() {
+                Ok(id) if valid_reaction(emoji) => {
+                    if let Err(e) = state.react(addr, id, emoji) {
+                        replies.push(Frame::Error(e));
+                    }
+                }
+                _ => replies.push(Frame::Error("usage: /react <id> <emoji>".to_string())),
+            }
+        }
+
+        // `/reply <id> <text>` sends a message that answers message `<id>`.
+        // The reply goes to the same room as the message it answers.
+        "/reply" => {
+            let (id, reply) = args.split_once(' ').unwrap_or((args, ""));
+            match (id.parse::<u64>(), reply.trim()) {
+                (Ok(_), "") | (Err(_), _) => {
+                    replies.push(Frame::Error("usage: /reply <id> <text>".to_string()))
+                }
+                (Ok(id), reply) => match state.find_visible(addr, id) {
+                    Ok(index) => {
+                        let room = state.history[index].msg.room.clone();
+                        state.post(addr, &room, reply, Some(id));
+                    }
+                    Err(e) => replies.push(Frame::Error(e)),
+                },
+            }
+        }
+
+        // `/thread <id>` sends back the whole conversation `<id>` belongs to:
+        // the message that started it and every reply, replies to replies included.
+        "/thread" => match args.trim().parse::<u64>() {
+            Ok(id) => match state.find_visible(addr, id) {
+                Ok(index) => {
+                    let messages = state.thread(index);
+                    replies.push(Frame::Thread {
+                        root: messages[0].id,
+                        count: messages.len(),
+                    });
+                    replies.extend(messages.into_iter().map(Frame::Msg));
+                }
+                Err(e) => replies.push(Frame::Error(e)),
+            },
+            Err(_) => replies.push(Frame::Error("usage: /thread <id>".to_string())),
+        },
+
+        // Running a room: `/mode`, `/invite` and `/topic` - see `rooms.rs`.
+        "/mode" => replies.extend(rooms::mode(state, addr, args)),
+        "/invite" => replies.extend(rooms::invite(state, addr, args)),
+        "/topic" => replies.extend(rooms::topic(state, addr, args)),
+
+        // `/motd` shows the message of the day - see `motd.rs`.
+        "/motd" => replies.extend(motd::command(state, addr, args)),
+
+        // `/restart` hands everything over to a new server - see `handoff.rs`.
+        "/restart" => replies.extend(handoff::command(state, addr)),
+
+        // `/search <words> ...` finds messages in the history - see `search.rs`.
+        "/search" => replies.extend(search::search(state, addr, args)),
+
+        // `/mentions` lists the messages mentioning you that you weren't in
+        // the room to see - sent while you were away, or in a room you're not
+        // in - and counts them as seen.
+        "/mentions" => {
+            let missed = state.take_unseen(&state.nick(addr));
+            if missed.is_empty() {
+                replies.push(Frame::Info("no unseen mentions".to_string()));
+            } else {
+                let noun = if missed.len() == 1 { "mention" } else { "mentions" };
+                replies.push(Frame::Info(format!("{} unseen {}:", missed.len(), noun)));
+                replies.extend(missed.iter().map(|msg| Frame::Info(search::listing(msg))));
+            }
+        }
+
+        // `/typing [#room]` tells everyone else in the room (your active room
+        // if you don't name one) that you're typing. Clients send it every few
+        // seconds while their user types. It's only interesting right now, so
+        // it's not stored, not sent over server links, and never answered -
+        // not even when you're not in the room, it simply goes nowhere.
+        "/typing" => {
+            let room = match args.trim() {
+                "" => state.clients.get(addr).and_then(|client| client.room.clone()),
+                room => Some(room.to_string()),
+            };
+            if let Some(room) = room
+                && let Some(client) = state.clients.get(addr)
+                && client.rooms.contains(&room)
+            {
+                let frame = Frame::Typing {
+                    nick: client.nick.clone(),
+                    room: room.clone(),
+                };
+                let others = state
+                    .clients
+                    .iter()
+                    .filter(|(other, client)| *other != addr && client.rooms.contains(&room))
+                    .map(|(other, _)| other.clone())
+                    .collect();
+                state.broadcast(others, frame);
+            }
+        }
+
+        // `/setkey <key>` publishes the client's public key for end-to-end
+        // encrypted private messages, and `/key <nick>` asks for someone's.
+        // The server only passes keys around: it never sees the secret half,
+        // so it can't read the messages, which it relays like any other
+        // private message. A key is 32 bytes, in base64.
+        "/setkey" => match base64_decode(args.trim()) {
+            Some(bytes) if bytes.len() == 32 => {
+                if let Some(client) = state.clients.get_mut(addr) {
+                    client.key = Some(args.trim().to_string());
+                }
+            }
+            _ => replies.push(Frame::Error("usage: /setkey <32 bytes in base64>".to_string())),
+        },
+        "/key" => match args.trim() {
+            "" => replies.push(Frame::Error("usage: /key <nick>".to_string())),
+            nick => match state.find_nick(nick) {
+                Some(who) => replies.push(Frame::Key {
+                    nick: nick.to_string(),
+                    key: state.clients.get(&who).and_then(|client| client.key.clone()),
+                }),
+                // Keys don't travel between servers, so the client falls
+                // back to `/plain` for someone on another one.
+                None if federation::find_remote(state, nick).is_some() => {
+                    replies.push(Frame::Key {
+                        nick: nick.to_string(),
+                        key: None,
+                    })
+                }
+                None => replies.push(Frame::Error(format!("{} isn't on this server", nick))),
+            },
+        },
+
+        // `/accept <xfer>` and `/decline <xfer>` answer a file offer.
+        "/accept" | "/decline" => match args.trim().parse::<u64>() {
+            Ok(xfer) => {
+                if let Err(e) = transfer::answer(state, db, addr, xfer, command == "/accept") {
+                    replies.push(Frame::Error(e));
+                }
+            }
+            Err(_) => replies.push(Frame::Error(format!("usage: {} <id>", command))),
+        },
+
+        // Anything else may be a command one of the plugins added.
+        _ => {
+            if !plugins::command(state, addr, command, args) {
+                replies.push(Frame::Error(format!("unknown command {}", command)));
+            }
+        }
+    }
+
+    // Send the replies to this client only.
+    for frame in replies {
+        state.send_to(addr, frame);
+    }
+}
+
+// `/nick <name> [password]` changes the name other people see. A
+// registered nick needs its password (see `/register`).
+async fn change_nick(db: &Db, addr: &str, args: &str) -> Vec<Frame> {
+    let (nick, password) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+    if !valid_nick(nick) {
+        return vec![Frame::Error(format!(
+            "a nick is 1 to {} letters, digits, - or _ and can't start with a digit",
+            MAX_NAME_LEN
+        ))];
+    }
+    let taken = || Frame::Error(format!("{} is already taken", nick));
+    let state = db.lock().await;
+    if nick_taken(&state, nick) {
+        return vec![taken()];
+    }
+    let (mut state, right) = rooms::check_nick_password(db, state, nick, password.trim()).await;
+    // Someone may have taken it while we were checking the password.
+    if nick_taken(&state, nick) {
+        return vec![taken()];
+    }
+    if !right {
+        return vec![Frame::Error(format!(
+            "{} is registered - /nick {} <password>",
+            nick, nick
+        ))];
+    }
+    state.rename(addr, nick);
+    // Say if people mentioned this nick while nobody had it.
+    match state.unseen.get(nick) {
+        Some(ids) if !ids.is_empty() => vec![Frame::Info(
+            "you were mentioned while you were away - /mentions to see where".to_string(),
+        )],
+        _ => Vec::new(),
+    }
+}
+
+// `/register <password>` protects your nick with a password, so nobody else
+// can take it - and with it your roles in rooms - while you're away. Again to
+// change the password.
+async fn register(db: &Db, addr: &str, args: &str) -> Vec<Frame> {
+    let password = args.trim();
+    if password.is_empty() {
+        return vec![Frame::Error("usage: /register <password>".to_string())];
+    }
+    let nick = db.lock().await.nick(addr);
+    if nick == addr {
+        return vec![Frame::Error("pick a nick with /nick first".to_string())];
+    }
+    let Some(hash) = rooms::hash_in_background(password).await else {
+        return vec![Frame::Error("couldn't register - try again".to_string())];
+    };
+    let mut state = db.lock().await;
+    // Only a client can change its own nick, and it's busy here - but it
+    // may have gone while we were hashing.
+    if state.nick(addr) != nick {
+        return Vec::new();
+    }
+    state.rooms.register(&nick, hash);
+    println!("{} registered their nick", nick);
+    vec![Frame::Info(format!(
+        "{} is registered - come back with /nick {} <password>",
+        nick, nick
+    ))]
+}
+
+// Is `nick` in use - by someone here, by a bot, or by someone on another
+// copy of the server on the bus?
+fn nick_taken(state: &State, nick: &str) -> bool {
+    state.find_nick(nick).is_some()
+        || plugins::is_plugin(state, nick)
+        || federation::taken_on_bus(state, nick)
+}
+
+// A command line the way it's safe to print: the passwords of `/oper`,
+// `/join #room <password>` and `/mode #room +k <password>` are replaced
+// with `***`, so they never end up in the server's log.
+// `Cow` ("clone on write") lets us hand back the line itself when there's
+// nothing to hide, and only build a new String when there is.
+pub fn redacted(text: &str) -> Cow<'_, str> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    match words.as_slice() {
+        ["/oper", _, ..] => Cow::Borrowed("/oper ***"),
+        ["/register", _, ..] => Cow::Borrowed("/register ***"),
+        ["/nick", nick, _, ..] => Cow::Owned(format!("/nick {} ***", nick)),
+        ["/join", room, _, ..] => Cow::Owned(format!("/join {} ***", room)),
+        ["/mode", room, "+k", _, ..] => Cow::Owned(format!("/mode {} +k ***", room)),
+        _ => Cow::Borrowed(text),
+    }
+}
+
+// Is `given` the same secret as `wanted`? A plain `==` stops at the first
+// character that differs, so by timing many guesses someone could find the
+// secret one character at a time. Comparing the SHA-256 of both, every byte
+// of it, always takes just as long - however much of the guess was right.
+pub fn same_secret(given: &str, wanted: &str) -> bool {
+    let given = Sha256::digest(given.as_bytes());
+    let wanted = Sha256::digest(wanted.as_bytes());
+    // `^` is 0 only where two bytes are equal, and `|` keeps any 1 it sees.
+    given.iter().zip(wanted.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Nicks follow the same rules as names, and can't start with a digit.
+pub fn valid_nick(nick: &str) -> bool {
+    valid_name(nick) && !nick.starts_with(|c: char| c.is_ascii_digit())
+}
+
+// `chars().count()` counts characters, `len()` would count bytes.
+// A reaction can't contain spaces or `=` because those separate the pieces of
+// a REACT line.
+pub fn valid_reaction(emoji: &str) -> bool {
+    !emoji.is_empty()
+        && emoji.chars().count() <= MAX_REACTION_LEN
+        && !emoji.contains(|c: char| c.is_whitespace() || c == '=')
+}
+
+// A room is `#` followed by a name.
+pub fn valid_room(room: &str) -> bool {
+    room.strip_prefix('#').is_some_and(valid_name)
+}
+
+// The error for a room name `valid_room` turns down.
+pub fn bad_room() -> Frame {
+    Frame::Error(format!(
+        "a room is # followed by 1 to {} letters, digits, - or _",
+        MAX_NAME_LEN
+    ))
+}
+
+// Check that a nick or room name (without the `#`) is 1 to MAX_NAME_LEN
+// letters, digits, `-` or `_`. Keeping names this simple means they can never
+// contain a space, so they're always safe to put in the middle of a line.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
@@ -0,0 +1,187 @@
+// Command line options for the server.
+//
+//   server [--config FILE] [--listen ADDR] [--name NAME] [--link-listen ADDR]
+//          [--peer ADDR]... [--bus local|redis://HOST:PORT[/CHANNEL]]
+//          [--irc-listen ADDR] [--webhooks FILE]
+//          [--http-listen ADDR --http-tokens FILE] [--rooms FILE] [--motd FILE]
+//          [--scripts DIR] [--history-limit N] [--max-file-size BYTES]
+//
+// - `--config`: a file with more of these options (see below)
+// - `--listen`: where clients connect (default 127.0.0.1:8080)
+// - `--name`: this server's name when linked to others (default: the listen address)
+// - `--link-listen`: where other servers may connect to link with us
+// - `--peer`: the link address of another server to connect to - can be repeated
+// - `--bus`: the message bus shared with other copies of this server (default local)
+// - `--irc-listen`: where IRC clients connect, if at all
+// - `--webhooks`: a file listing web addresses to tell about chat events
+// - `--http-listen`: where programs can post messages over HTTP, if at all
+// - `--http-tokens`: the file of tokens allowed to post there
+// - `--rooms`: where room settings are saved (default chatty-rooms.json)
+// - `--motd`: the message of the day shown to everyone who connects
+//   (default chatty-motd.txt - if it isn't there, there's none)
+// - `--scripts`: the folder of Rhai scripts (default: CHATTY_SCRIPTS, or scripts)
+// - `--history-limit`: how many messages the server remembers (default 1000)
+// - `--max-file-size`: the biggest file that may be sent, in bytes (default 10 MiB)
+//
+// The config file has one option per line, without the `--`:
+//
+//   // Lines starting with // are comments.
+//   listen 0.0.0.0:8080
+//   motd /etc/chatty/motd.txt
+//   peer office2:9000
+//   peer office3:9000
+//
+// The command line wins over the file, except `--peer`: peers from both count.
+// The file is read again on SIGHUP or `/reload` - see `reload.rs`.
+
+use crate::bus::BusKind;
+use crate::federation;
+
+// The address clients connect to when no `--listen` is given.
+const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+// Where room owners, modes, bans and topics are saved unless `--rooms` says otherwise.
+const DEFAULT_ROOMS: &str = "chatty-rooms.json";
+
+// The message of the day, unless `--motd` says otherwise.
+const DEFAULT_MOTD: &str = "chatty-motd.txt";
+
+// How many recent messages the server remembers. Older ones are forgotten.
+const DEFAULT_HISTORY_LIMIT: usize = 1000;
+
+// The biggest file we accept: 10 MiB.
+const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+// `#[derive(Debug)]` lets us print the whole config with `{:?}`.
+// `Clone` and `PartialEq` let `/reload` keep the old one and compare.
+// - `file`: the `--config` file, if there is one.
+// - `scripts`: None means the default folder (see `plugins/scripts.rs`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub file: Option<String>,
+    pub listen: String,
+    pub name: String,
+    pub link_listen: Option<String>,
+    pub peers: Vec<String>,
+    pub bus: BusKind,
+    pub irc_listen: Option<String>,
+    pub webhooks: Option<String>,
+    pub http_listen: Option<String>,
+    pub http_tokens: Option<String>,
+    pub rooms: String,
+    pub motd: String,
+    pub scripts: Option<String>,
+    pub history_limit: usize,
+    pub max_file_size: u64,
+}
+
+impl Config {
+    // Read the options from the config file (if `--config` names one) and
+    // the command line.
+    // On a mistake we return a message explaining it, and `main` prints it.
+    pub fn from_args() -> Result<Config, String> {
+        // `std::env::args()` gives us every word typed on the command line.
+        // The first one is the program's own name, so `skip(1)` drops it.
+        let args: Vec<String> = std::env::args().skip(1).collect();
+
+        let mut config = Config {
+            file: None,
+            listen: DEFAULT_LISTEN.to_string(),
+            // Filled in with the listen address below if nothing sets it.
+            name: String::new(),
+            link_listen: None,
+            peers: Vec::new(),
+            bus: BusKind::Local,
+            irc_listen: None,
+            webhooks: None,
+            http_listen: None,
+            http_tokens: None,
+            rooms: DEFAULT_ROOMS.to_string(),
+            motd: DEFAULT_MOTD.to_string(),
+            scripts: None,
+            history_limit: DEFAULT_HISTORY_LIMIT,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+        };
+
+        // The file goes first, so the command line can change what it says.
+        if let Some(at) = args.iter().position(|arg| arg == "--config") {
+            let path = args.get(at + 1).ok_or("--config needs a value")?;
+            config.read_file(path)?;
+            config.file = Some(path.clone());
+        }
+
+        let mut args = args.into_iter();
+        // `while let` keeps looping as long as `args.next()` returns Some.
+        while let Some(arg) = args.next() {
+            // Every option needs a value right after it.
+            let value = args.next().ok_or(format!("{} needs a value", arg))?;
+            match arg.strip_prefix("--") {
+                Some("config") => {}
+                Some(option) => config.set(option, value)?,
+                None => return Err(format!("unknown option {}", arg)),
+            }
+        }
+
+        // Linked servers check each other's names with the same rules.
+        if config.name.is_empty() {
+            config.name = config.listen.clone();
+        }
+        federation::check_server_name(&config.name).map_err(|e| format!("--name: {}", e))?;
+        // An HTTP endpoint anybody could post to would be an open door.
+        if config.http_listen.is_some() != config.http_tokens.is_some() {
+            return Err("--http-listen and --http-tokens go together".to_string());
+        }
+        Ok(config)
+    }
+
+    // Take the options from a config file, one `option value` per line.
+    fn read_file(&mut self, path: &str) -> Result<(), String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            let bad = |what: String| format!("{} line {}: {}", path, number + 1, what);
+            let Some((option, value)) = line.split_once(char::is_whitespace) else {
+                return Err(bad(format!("{} needs a value", line)));
+            };
+            if option == "config" {
+                return Err(bad("a config file can't name another one".to_string()));
+            }
+            self.set(option, value.trim().to_string()).map_err(bad)?;
+        }
+        Ok(())
+    }
+
+    // Set one option, given without its `--`.
+    fn set(&mut self, option: &str, value: String) -> Result<(), String> {
+        // Numbers must be whole and above 0.
+        let number = |value: &str| -> Result<u64, String> {
+            match value.parse() {
+                Ok(n) if n > 0 => Ok(n),
+                _ => Err(format!("--{} must be a number above 0", option)),
+            }
+        };
+        match option {
+            "listen" => self.listen = value,
+            "name" if value.is_empty() => return Err("--name can't be empty".to_string()),
+            "name" => self.name = value,
+            "link-listen" => self.link_listen = Some(value),
+            "peer" => self.peers.push(value),
+            "bus" => self.bus = BusKind::parse(&value)?,
+            "irc-listen" => self.irc_listen = Some(value),
+            "webhooks" => self.webhooks = Some(value),
+            "http-listen" => self.http_listen = Some(value),
+            "http-tokens" => self.http_tokens = Some(value),
+            "rooms" => self.rooms = value,
+            "motd" => self.motd = value,
+            "scripts" => self.scripts = Some(value),
+            "history-limit" => self.history_limit = number(&value)? as usize,
+            "max-file-size" => self.max_file_size = number(&value)?,
+            _ => return Err(format!("unknown option --{}", option)),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1083 @@
+// Linking servers together ("federation").
+//
+// Two servers can be linked so that people on either one can talk in the same
+// rooms. One server listens for links with `--link-listen`, the other connects
+// to it with `--peer`. Both must be started with the same link password in the
+// CHATTY_LINK_PASSWORD environment variable - that's what makes the link
+// trusted. The password itself never travels: each side proves it knows it
+// (see `run_link`). The lines after that aren't encrypted though, so only
+// link servers over a network you trust.
+//
+// Once linked, the servers tell each other about room messages (and edits,
+// deletions and reactions to them), private messages, and people joining,
+// leaving and changing nick. Each of those is an `Event`, sent as one line:
+//
+//   FMSG <origin> <path> <seq> <id> <reply> <time> <sender> <room> <text>
+//   FEDIT <origin> <path> <seq> <id> <text>
+//   FDEL <origin> <path> <seq> <id>
+//   FREACT <origin> <path> <seq> <message> <nick> <on|off> <emoji>
+//   FPRIV <origin> <path> <seq> <server> <from> <to> <text>
+//   FJOIN <origin> <path> <seq> <nick> <room>
+//   FPART <origin> <path> <seq> <nick> <room>
+//   FNICK <origin> <path> <seq> <old> <new>
+//   FSYNC <origin> <path> <seq>
+//   FROOM <origin> <path> <seq> <room> <settings>
+//   FREG <origin> <path> <seq> <nick> <hash>
+//
+// FSYNC asks "who's around?" - whoever receives it answers with an FJOIN for
+// everyone in a room on their server. A server link doesn't need it (both
+// sides send that as soon as they're linked), but a message bus does.
+//
+// FROOM (a room's settings, as the JSON the rooms file keeps them in) and
+// FREG (a registered nick and its password hash) are only for copies of this
+// server on the message bus, which share their rooms (see `rooms.rs`). They
+// only travel over the bus, and are never passed on: a linked server has
+// rooms of its own. A copy answers an FSYNC with them too.
+//
+// - `<origin>` is the name of the server where it happened. We show remote
+//   people as `nick@origin` so everyone can tell where they are - except
+//   people on other copies of this server on the same message bus, who are
+//   shown by their nick alone (see `bus.rs`).
+// - `<path>` lists, comma separated, every server the line has passed through.
+//   A server never sends a line to a server already on its path and drops any
+//   line that has its own name on it, so no line goes round in circles.
+// - `<seq>` numbers the lines sent by the first server on the path. It counts
+//   on from the time that server started, in microseconds, so the numbers
+//   keep going up even when a server restarts.
+// - `<id>` is the ID the message got on its origin server. Every server gives
+//   the message an ID of its own too, and remembers which one it gave, so an
+//   FEDIT or FDEL changes the right message. Only the origin server sends them.
+// - `<reply>` and `<message>` point at a message from any server, as
+//   `<server>,<id>` with the ID it got there. `<reply>` is `-` for a message
+//   that isn't a reply.
+// - FPRIV is for `<to>` on `<server>`. Other servers only pass it on.
+//
+// The path alone isn't enough when servers are linked in a loop (A-B, B-C,
+// C-A): C hears about everything A does straight from A AND again through B.
+// So we remember (first server on the path, seq) of the last SEEN_LIMIT lines
+// and only act on - and pass on - the FIRST copy of each. Every path delivers
+// lines in the order they were sent, so the first copies arrive in that
+// order too, and a join is never overtaken by a nick change for example.
+// The later copies still tell us something: that the link they came over
+// reaches those people too. Each link keeps its own list of who it reaches,
+// so losing one link only loses the people no other link can reach.
+//
+// Every link sends its lines through a `Bus` (see `bus.rs`). For a server link
+// that's just a channel to the link's writer task; several copies of the
+// server can also share a message bus such as Redis, which is attached here
+// as one more link.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use chatty_rusty::protocol::{ChatMessage, Frame, is_rfc3339, now_rfc3339};
+
+use crate::bus::{self, Bus};
+use crate::commands::{same_secret, valid_nick, valid_reaction, valid_room};
+use crate::rooms::{self, Shared};
+use crate::state::{Db, State};
+
+// The environment variable holding the password linked servers share.
+const LINK_PASSWORD_VAR: &str = "CHATTY_LINK_PASSWORD";
+
+// How long the other server has to introduce itself.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How many lines from other servers we remember, to spot copies.
+const SEEN_LIMIT: usize = 10_000;
+
+// When a link drops we try again after 1 second, then 2, 4, 8... up to this.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// A remote person: (room, nick, origin server).
+type Member = (String, String, String);
+
+// One server (or message bus) we're linked to.
+// - `bus`: where lines for it go
+// - `members`: the remote people this link reaches. If the link drops,
+//   they're gone - unless another link reaches them too.
+pub struct Link {
+    bus: Box<dyn Bus>,
+    members: BTreeSet<Member>,
+}
+
+// What we know about the other servers, apart from the links themselves:
+// - `seq`: the `<seq>` of the last line we sent, 0 before the first one
+// - `seen`: (first server on the path, seq) of the last SEEN_LIMIT lines we
+//   received, with the same pairs oldest first in `order`, to spot copies
+// - `ids`: (origin server, the ID it gave a message) mapped to the ID we
+//   gave it, so an FEDIT changes the right message. `by_id` maps them the
+//   other way round, so we can forget the ones that left our history.
+// - `members`: everyone in a room on another server
+// - `instances`: the other copies of this server on the message bus
+#[derive(Default)]
+pub struct Remote {
+    seq: u64,
+    seen: HashSet<(String, u64)>,
+    order: VecDeque<(String, u64)>,
+    ids: HashMap<(String, u64), u64>,
+    by_id: BTreeMap<u64, (String, u64)>,
+    members: BTreeSet<Member>,
+    instances: BTreeSet<String>,
+}
+
+impl Remote {
+    // The `<seq>` for the next line we send.
+    fn next_seq(&mut self) -> u64 {
+        if self.seq == 0 {
+            // A u64 of microseconds lasts for over 500,000 years.
+            self.seq = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_micros() as u64);
+        }
+        self.seq += 1;
+        self.seq
+    }
+
+    // Remember line `seq` from `first`. Returns false if we already had it.
+    fn first_copy(&mut self, first: &str, seq: u64) -> bool {
+        let key = (first.to_string(), seq);
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > SEEN_LIMIT
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.seen.remove(&oldest);
+        }
+        true
+    }
+
+    // Remember that we gave message `id` from `origin` the ID `local`, and
+    // forget the messages before `oldest`, the oldest one in our history.
+    fn remember_id(&mut self, origin: &str, id: u64, local: u64, oldest: u64) {
+        let key = (origin.to_string(), id);
+        // A server that was stopped and started again numbers its messages
+        // from 1 again, so the new message replaces the old one.
+        if let Some(old) = self.ids.insert(key.clone(), local) {
+            self.by_id.remove(&old);
+        }
+        self.by_id.insert(local, key);
+        while let Some(entry) = self.by_id.first_entry()
+            && *entry.key() < oldest
+        {
+            self.ids.remove(&entry.remove());
+        }
+    }
+
+    // The ID we gave message `id` from `origin`, if we remember it.
+    fn local_id(&self, origin: &str, id: u64) -> Option<u64> {
+        self.ids.get(&(origin.to_string(), id)).copied()
+    }
+
+    // Whether our message `local` came from another server.
+    pub fn is_remote(&self, local: u64) -> bool {
+        self.by_id.contains_key(&local)
+    }
+}
+
+// A message on some server: (server name, the ID it got there).
+type MsgRef = (String, u64);
+
+// Write a `MsgRef` as `<server>,<id>`. Server names never contain commas.
+fn write_ref((server, id): &MsgRef) -> String {
+    format!("{},{}", server, id)
+}
+
+fn parse_ref(text: &str) -> Option<MsgRef> {
+    let (server, id) = text.split_once(',')?;
+    if server.is_empty() {
+        return None;
+    }
+    Some((server.to_string(), id.parse().ok()?))
+}
+
+// Where our message `local` came from, for pointing at it in a relay line.
+pub fn msg_ref(state: &State, local: u64) -> MsgRef {
+    match state.remote.by_id.get(&local) {
+        Some(found) => found.clone(),
+        None => (state.name.clone(), local),
+    }
+}
+
+// Our ID for the message `msg` points at, if we have it.
+fn local_msg(state: &State, (server, id): &MsgRef) -> Option<u64> {
+    if *server == state.name {
+        Some(*id)
+    } else {
+        state.remote.local_id(server, *id)
+    }
+}
+
+// Something that happened on one server that linked servers should know about.
+pub enum Event {
+    Msg {
+        id: u64,
+        reply: Option<MsgRef>,
+        time: String,
+        sender: String,
+        room: String,
+        text: String,
+    },
+    Edit { id: u64, text: String },
+    Delete { id: u64 },
+    React {
+        msg: MsgRef,
+        nick: String,
+        on: bool,
+        emoji: String,
+    },
+    Private {
+        server: String,
+        from: String,
+        to: String,
+        text: String,
+    },
+    Join { nick: String, room: String },
+    Part { nick: String, room: String },
+    Nick { old: String, new: String },
+    Sync,
+    Room { room: String, settings: rooms::Room },
+    Register { nick: String, hash: String },
+}
+
+// An event together with where it came from and where it has been.
+struct Relay {
+    origin: String,
+    path: Vec<String>,
+    seq: u64,
+    event: Event,
+}
+
+impl Relay {
+    fn to_line(&self) -> String {
+        let head = format!("{} {} {}", self.origin, self.path.join(","), self.seq);
+        match &self.event {
+            Event::Msg {
+                id,
+                reply,
+                time,
+                sender,
+                room,
+                text,
+            } => {
+                let reply = reply.as_ref().map_or("-".to_string(), write_ref);
+                format!(
+                    "FMSG {} {} {} {} {} {} {}\n",
+                    head, id, reply, time, sender, room, text
+                )
+            }
+            Event::Edit { id, text } => format!("FEDIT {} {} {}\n", head, id, text),
+            Event::Delete { id } => format!("FDEL {} {}\n", head, id),
+            Event::React {
+                msg,
+                nick,
+                on,
+                emoji,
+            } => {
+                let on = if *on { "on" } else { "off" };
+                format!("FREACT {} {} {} {} {}\n", head, write_ref(msg), nick, on, emoji)
+            }
+            Event::Private {
+                server,
+                from,
+                to,
+                text,
+            } => format!("FPRIV {} {} {} {} {}\n", head, server, from, to, text),
+            Event::Join { nick, room } => format!("FJOIN {} {} {}\n", head, nick, room),
+            Event::Part { nick, room } => format!("FPART {} {} {}\n", head, nick, room),
+            Event::Nick { old, new } => format!("FNICK {} {} {}\n", head, old, new),
+            Event::Sync => format!("FSYNC {}\n", head),
+            // `to_string` writes JSON on one line: a newline in the topic
+            // becomes `\n`.
+            Event::Room { room, settings } => {
+                format!("FROOM {} {} {}\n", head, room, settings.to_json())
+            }
+            Event::Register { nick, hash } => format!("FREG {} {} {}\n", head, nick, hash),
+        }
+    }
+
+    // Read a relay line. Anything that doesn't follow the rules - including a
+    // nick, room or time that no server of ours would send - gives `None`, so
+    // a broken or badly behaved server can't put nonsense in front of people.
+    fn parse(line: &str) -> Option<Relay> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        // Every line has a kind, origin, path and seq, followed by the
+        // event's own fields.
+        let mut parts = line.splitn(5, ' ');
+        let kind = parts.next()?;
+        let origin = parts.next()?.to_string();
+        let path: Vec<String> = parts.next()?.split(',').map(String::from).collect();
+        if origin.is_empty() || origin.contains(',') || path.iter().any(String::is_empty) {
+            return None;
+        }
+        let seq = parts.next()?.parse().ok()?;
+        // FSYNC has no fields of its own.
+        let rest = parts.next().unwrap_or("");
+
+        let event = match kind {
+            "FMSG" => {
+                let [id, reply, time, sender, room, text] = fields::<6>(rest)?;
+                let reply = match reply.as_str() {
+                    "-" => None,
+                    reply => Some(parse_ref(reply)?),
+                };
+                if !is_rfc3339(&time)
+                    || !valid_person(&sender)
+                    || !valid_room(&room)
+                    || text.is_empty()
+                {
+                    return None;
+                }
+                Event::Msg {
+                    id: id.parse().ok()?,
+                    reply,
+                    time,
+                    sender,
+                    room,
+                    text,
+                }
+            }
+            "FEDIT" => {
+                let [id, text] = fields::<2>(rest)?;
+                if text.is_empty() {
+                    return None;
+                }
+                Event::Edit {
+                    id: id.parse().ok()?,
+                    text,
+                }
+            }
+            "FDEL" => Event::Delete {
+                id: rest.parse().ok()?,
+            },
+            "FREACT" => {
+                let [msg, nick, on, emoji] = fields::<4>(rest)?;
+                if !valid_person(&nick) || !valid_reaction(&emoji) {
+                    return None;
+                }
+                Event::React {
+                    msg: parse_ref(&msg)?,
+                    nick,
+                    on: match on.as_str() {
+                        "on" => true,
+                        "off" => false,
+                        _ => return None,
+                    },
+                    emoji,
+                }
+            }
+            "FPRIV" => {
+                let [server, from, to, text] = fields::<4>(rest)?;
+                if server.is_empty() || !valid_person(&from) || !valid_person(&to) || text.is_empty()
+                {
+                    return None;
+                }
+                Event::Private {
+                    server,
+                    from,
+                    to,
+                    text,
+                }
+            }
+            "FJOIN" => {
+                let [nick, room] = fields::<2>(rest)?;
+                if !valid_person(&nick) || !valid_room(&room) {
+                    return None;
+                }
+                Event::Join { nick, room }
+            }
+            "FPART" => {
+                let [nick, room] = fields::<2>(rest)?;
+                if !valid_person(&nick) || !valid_room(&room) {
+                    return None;
+                }
+                Event::Part { nick, room }
+            }
+            "FNICK" => {
+                let [old, new] = fields::<2>(rest)?;
+                if !valid_person(&old) || !valid_person(&new) {
+                    return None;
+                }
+                Event::Nick { old, new }
+            }
+            "FSYNC" => Event::Sync,
+            "FROOM" => {
+                let [room, settings] = fields::<2>(rest)?;
+                let settings: Value = serde_json::from_str(&settings).ok()?;
+                if !valid_room(&room) || !settings.is_object() {
+                    return None;
+                }
+                Event::Room {
+                    room,
+                    settings: rooms::Room::from_json(&settings),
+                }
+            }
+            "FREG" => {
+                let [nick, hash] = fields::<2>(rest)?;
+                if !valid_nick(&nick) || hash.is_empty() || hash.contains(' ') {
+                    return None;
+                }
+                Event::Register { nick, hash }
+            }
+            _ => return None,
+        };
+        Some(Relay {
+            origin,
+            path,
+            seq,
+            event,
+        })
+    }
+}
+
+// Split the `N` fields of an event off `rest`. The last one is the rest of
+// the line, so it may contain spaces. None unless there are exactly `N`.
+fn fields<const N: usize>(rest: &str) -> Option<[String; N]> {
+    let fields: Vec<String> = rest.splitn(N, ' ').map(String::from).collect();
+    fields.try_into().ok()
+}
+
+// Whether `nick` could be someone's name on another server: a valid nick,
+// or the address people are known by until they pick one.
+fn valid_person(nick: &str) -> bool {
+    valid_nick(nick) || nick.parse::<SocketAddr>().is_ok()
+}
+
+// Check a server's name, whether it's our own `--name` or the one another
+// server gives in the link handshake. Names travel inside space and comma
+// separated lines, so they can't contain either, and the message bus is
+// linked under `bus::LINK_NAME`, so nobody else may use that.
+pub fn check_server_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("a server name can't be empty".to_string());
+    }
+    if name.contains([' ', ',']) {
+        return Err("a server name can't contain spaces or commas".to_string());
+    }
+    if name == bus::LINK_NAME {
+        return Err(format!("a server name can't be \"{}\"", bus::LINK_NAME));
+    }
+    Ok(())
+}
+
+// Tell every linked server about something that happened HERE.
+pub fn relay_local(state: &mut State, event: Event) {
+    let relay = Relay {
+        origin: state.name.clone(),
+        path: vec![state.name.clone()],
+        seq: state.remote.next_seq(),
+        event,
+    };
+    send_to_links(state, &relay, None);
+}
+
+// Send a relay line to every link that isn't already on its path, except the
+// link called `except` - the one the line just arrived on. That matters for a
+// message bus: everyone on it has already heard the line. Lines about a room
+// that's restricted here only go on the bus (see `restricted`).
+fn send_to_links(state: &State, relay: &Relay, except: Option<&str>) {
+    let line = relay.to_line();
+    let restricted = restricted(state, &relay.origin, &relay.event);
+    for (name, link) in &state.links {
+        if !relay.path.contains(name)
+            && except != Some(name.as_str())
+            && (name == bus::LINK_NAME || !restricted)
+        {
+            link.bus.publish(line.clone());
+        }
+    }
+}
+
+// Whether `event` (from the server `origin`) is about a room that's invite
+// only (`+i`) or needs a password (`+k`) here. A linked server has rooms of
+// its own, where anybody could join one with the same name and read along,
+// so what happens in such a room stays here - and on the other copies of
+// this server on the bus, which share its modes (see `rooms.rs`). We don't
+// take lines about it from linked servers either.
+fn restricted(state: &State, origin: &str, event: &Event) -> bool {
+    let message_room = |msg: &MsgRef| {
+        let index = state.position(local_msg(state, msg)?)?;
+        Some(state.history[index].msg.room.clone())
+    };
+    let room = match event {
+        Event::Msg { room, .. } | Event::Join { room, .. } | Event::Part { room, .. } => {
+            Some(room.clone())
+        }
+        Event::Edit { id, .. } | Event::Delete { id } => message_room(&(origin.to_string(), *id)),
+        Event::React { msg, .. } => message_room(msg),
+        _ => None,
+    };
+    room.and_then(|room| state.rooms.get(&room))
+        .is_some_and(|settings| settings.invite_only || settings.password.is_some())
+}
+
+// Send the link called `name` an FJOIN for everyone in a room HERE, so a newly
+// linked server (or a new server on the bus) knows who's around. Restricted
+// rooms are left out, except on the bus (see `restricted`).
+fn announce_members(state: &mut State, name: &str) {
+    let mut joins = Vec::new();
+    for client in state.clients.values() {
+        for room in &client.rooms {
+            joins.push(Event::Join {
+                nick: client.nick.clone(),
+                room: room.clone(),
+            });
+        }
+    }
+    if name != bus::LINK_NAME {
+        let origin = state.name.clone();
+        joins.retain(|event| !restricted(state, &origin, event));
+    }
+    for event in joins {
+        let relay = Relay {
+            origin: state.name.clone(),
+            path: vec![state.name.clone()],
+            seq: state.remote.next_seq(),
+            event,
+        };
+        if let Some(link) = state.links.get(name) {
+            link.bus.publish(relay.to_line());
+        }
+    }
+}
+
+// Start using `bus` as the link called `name`. Fails if there already is one.
+pub fn attach(state: &mut State, name: &str, bus: Box<dyn Bus>) -> Result<(), String> {
+    if name == state.name || state.links.contains_key(name) {
+        return Err(format!("already linked to {}", name));
+    }
+    state.links.insert(
+        name.to_string(),
+        Link {
+            bus,
+            members: BTreeSet::new(),
+        },
+    );
+    announce_members(state, name);
+    Ok(())
+}
+
+// The link called `name` is gone: everyone we could only reach through it has
+// left.
+pub fn detach(state: &mut State, name: &str) {
+    let Some(link) = state.links.remove(name) else {
+        return;
+    };
+    for member in link.members {
+        // Still reachable through another link (servers linked in a loop),
+        // or gone already.
+        if reachable(state, &member) || !state.remote.members.remove(&member) {
+            continue;
+        }
+        let (room, nick, origin) = member;
+        let frame = Frame::Part {
+            nick: shown(state, &nick, &origin),
+            room: room.clone(),
+            reason: format!("lost link to {}", name),
+        };
+        state.send_to_room(&room, &frame);
+        // Let other linked servers know as well.
+        let relay = Relay {
+            origin,
+            path: vec![state.name.clone()],
+            seq: state.remote.next_seq(),
+            event: Event::Part { nick, room },
+        };
+        send_to_links(state, &relay, None);
+    }
+    if name == bus::LINK_NAME {
+        state.remote.instances.clear();
+    }
+}
+
+// Handle one line that arrived over the link called `from`.
+pub fn receive_line(state: &mut State, from: &str, line: &str) {
+    match Relay::parse(line) {
+        Some(relay) => receive(state, from, relay),
+        None => println!("Ignoring bad line from {}: {}", from, line.trim_end()),
+    }
+}
+
+// Handle a line that arrived over the link to server `from`.
+fn receive(state: &mut State, from: &str, mut relay: Relay) {
+    // Loop prevention: if we're already on the path, we've seen this before.
+    if relay.origin == state.name || relay.path.contains(&state.name) {
+        return;
+    }
+    if from != bus::LINK_NAME && restricted(state, &relay.origin, &relay.event) {
+        return;
+    }
+    // A line that comes straight from the server it started on, over the
+    // bus, comes from another copy of this server.
+    if from == bus::LINK_NAME && relay.path.len() == 1 {
+        state.remote.instances.insert(relay.origin.clone());
+    }
+    // Every copy of a line tells us who the link it came over reaches...
+    if let Some(link) = state.links.get_mut(from) {
+        learn(&mut link.members, &relay.origin, &relay.event);
+    }
+    // ...but only the first copy is news (see the top of this file).
+    if !state.remote.first_copy(&relay.path[0], relay.seq) {
+        return;
+    }
+    let origin = relay.origin.clone();
+    match &relay.event {
+        Event::Msg {
+            id,
+            reply,
+            time,
+            sender,
+            room,
+            text,
+        } => {
+            let msg = ChatMessage {
+                // `store` fills these in.
+                id: 0,
+                mentions: Vec::new(),
+                time: time.clone(),
+                sender: sender.clone(),
+                room: room.clone(),
+                text: text.clone(),
+                edited: false,
+                reply_to: reply.as_ref().and_then(|reply| local_msg(state, reply)),
+                // Clients show where it came from - unless that's another
+                // copy of this server.
+                origin: (!state.remote.instances.contains(&origin)).then(|| origin.clone()),
+            };
+            let local = state.post_remote(msg);
+            let oldest = state.history.front().map_or(local, |stored| stored.msg.id);
+            state.remote.remember_id(&origin, *id, local, oldest);
+        }
+        Event::Edit { id, text } => {
+            if let Some(local) = state.remote.local_id(&origin, *id) {
+                state.change_text(local, text);
+            }
+        }
+        Event::Delete { id } => {
+            if let Some(local) = state.remote.local_id(&origin, *id) {
+                state.remove_message(local);
+            }
+        }
+        Event::React {
+            msg,
+            nick,
+            on,
+            emoji,
+        } => {
+            // Remote people react under `nick@origin`, which no local
+            // client's address can look like.
+            if let Some(local) = local_msg(state, msg) {
+                let who = format!("{}@{}", nick, origin);
+                state.set_reaction(local, &who, emoji, *on);
+            }
+        }
+        Event::Private {
+            server,
+            from,
+            to,
+            text,
+        } => {
+            // For someone here: deliver it, and it goes no further.
+            if *server == state.name {
+                if let Some(addr) = state.find_nick(to) {
+                    let frame = Frame::Private {
+                        time: now_rfc3339(),
+                        from: shown(state, from, &origin),
+                        to: to.clone(),
+                        text: text.clone(),
+                    };
+                    state.send_to(&addr, frame);
+                }
+                return;
+            }
+        }
+        Event::Join { nick, room } => {
+            // Nothing to tell if we knew already - e.g. from an answer to an
+            // FSYNC.
+            let rooms = learn(&mut state.remote.members, &origin, &relay.event);
+            let frame = Frame::Join {
+                nick: shown(state, nick, &origin),
+                room: room.clone(),
+            };
+            state.send_to_rooms(&rooms, None, &frame);
+        }
+        Event::Part { nick, room } => {
+            let rooms = learn(&mut state.remote.members, &origin, &relay.event);
+            let frame = Frame::Part {
+                nick: shown(state, nick, &origin),
+                room: room.clone(),
+                reason: String::new(),
+            };
+            state.send_to_rooms(&rooms, None, &frame);
+        }
+        Event::Nick { old, new } => {
+            let rooms = learn(&mut state.remote.members, &origin, &relay.event);
+            let frame = Frame::Nick {
+                old: shown(state, old, &origin),
+                new: shown(state, new, &origin),
+            };
+            state.send_to_rooms(&rooms, None, &frame);
+        }
+        Event::Sync => {
+            // Answer only the link that asked. Not passed on.
+            announce_members(state, from);
+            if from == bus::LINK_NAME {
+                state.rooms.share_everything();
+            }
+            return;
+        }
+        Event::Room { room, settings } => {
+            if from == bus::LINK_NAME {
+                let (room, settings) = (room.clone(), settings.clone());
+                state.rooms.apply(Shared::Room(room.clone(), settings.clone()));
+                throw_out_banned(state, &room, &settings);
+            }
+            return;
+        }
+        Event::Register { nick, hash } => {
+            if from == bus::LINK_NAME {
+                state.rooms.apply(Shared::Nick(nick.clone(), hash.clone()));
+            }
+            return;
+        }
+    }
+    // Pass it on to everyone else, adding ourselves to the path.
+    relay.path.push(state.name.clone());
+    send_to_links(state, &relay, Some(from));
+}
+
+// Another copy of this server banned someone from `room`: if they're in it
+// here, they have to go - unless they're a server operator.
+fn throw_out_banned(state: &mut State, room: &str, settings: &rooms::Room) {
+    let banned: Vec<String> = state
+        .clients
+        .iter()
+        .filter(|(_, c)| c.rooms.contains(room) && !c.operator && settings.banned.contains(&c.nick))
+        .map(|(addr, _)| addr.clone())
+        .collect();
+    for addr in banned {
+        state.part_because(&addr, room, "banned");
+    }
+}
+
+// Tell the other copies of this server on the bus about a change to the
+// rooms (see `rooms.rs`).
+pub fn share(state: &mut State, change: Shared) {
+    let event = match change {
+        Shared::Room(room, settings) => Event::Room { room, settings },
+        Shared::Nick(nick, hash) => Event::Register { nick, hash },
+    };
+    let relay = Relay {
+        origin: state.name.clone(),
+        path: vec![state.name.clone()],
+        seq: state.remote.next_seq(),
+        event,
+    };
+    if let Some(link) = state.links.get(bus::LINK_NAME) {
+        link.bus.publish(relay.to_line());
+    }
+}
+
+// Update `members` with what `event` says about the people on `origin`, and
+// return the rooms where that changed something.
+fn learn(members: &mut BTreeSet<Member>, origin: &str, event: &Event) -> BTreeSet<String> {
+    let mut rooms = BTreeSet::new();
+    match event {
+        Event::Join { nick, room } => {
+            let member = (room.clone(), nick.clone(), origin.to_string());
+            if members.insert(member) {
+                rooms.insert(room.clone());
+            }
+        }
+        Event::Part { nick, room } => {
+            let member = (room.clone(), nick.clone(), origin.to_string());
+            if members.remove(&member) {
+                rooms.insert(room.clone());
+            }
+        }
+        Event::Nick { old, new } => {
+            // Find every room the remote person is in and rename them there.
+            let renamed: Vec<String> = members
+                .iter()
+                .filter(|(_, nick, o)| nick == old && o == origin)
+                .map(|(room, _, _)| room.clone())
+                .collect();
+            for room in renamed {
+                members.remove(&(room.clone(), old.clone(), origin.to_string()));
+                members.insert((room.clone(), new.clone(), origin.to_string()));
+                rooms.insert(room);
+            }
+        }
+        _ => {}
+    }
+    rooms
+}
+
+// Ask everyone on the link called `name` who's around (see FSYNC above).
+pub fn request_members(state: &mut State, name: &str) {
+    let relay = Relay {
+        origin: state.name.clone(),
+        path: vec![state.name.clone()],
+        seq: state.remote.next_seq(),
+        event: Event::Sync,
+    };
+    if let Some(link) = state.links.get(name) {
+        link.bus.publish(relay.to_line());
+    }
+}
+
+// Whether any link still reaches `member`.
+fn reachable(state: &State, member: &Member) -> bool {
+    state.links.values().any(|link| link.members.contains(member))
+}
+
+// Count the remote people in each room, for `/rooms`.
+pub fn remote_members(state: &State) -> BTreeMap<String, usize> {
+    let mut rooms = BTreeMap::new();
+    for (room, _, _) in &state.remote.members {
+        *rooms.entry(room.clone()).or_insert(0) += 1;
+    }
+    rooms
+}
+
+// The remote people in `room`, as they're shown, for listing members.
+pub fn remote_nicks(state: &State, room: &str) -> Vec<String> {
+    state
+        .remote
+        .members
+        .iter()
+        .filter(|(r, _, _)| r == room)
+        .map(|(_, nick, origin)| shown(state, nick, origin))
+        .collect()
+}
+
+// How we show `nick` from the server `origin`: `nick@origin`, or just `nick`
+// for someone on another copy of this server.
+fn shown(state: &State, nick: &str, origin: &str) -> String {
+    if state.remote.instances.contains(origin) {
+        nick.to_string()
+    } else {
+        format!("{}@{}", nick, origin)
+    }
+}
+
+// The people on other copies of this server on the bus, as (room, nick).
+pub fn bus_members(state: &State) -> impl Iterator<Item = (&str, &str)> {
+    state
+        .remote
+        .members
+        .iter()
+        .filter(|(_, _, origin)| state.remote.instances.contains(origin))
+        .map(|(room, nick, _)| (room.as_str(), nick.as_str()))
+}
+
+// Whether someone on another copy of this server is using `nick`. Copies on
+// one bus share their nicks, like one big server.
+pub fn taken_on_bus(state: &State, nick: &str) -> bool {
+    bus_members(state).any(|(_, other)| other == nick)
+}
+
+// Find a remote person by the name they're shown by (see `shown`), and
+// return their nick and server.
+pub fn find_remote(state: &State, name: &str) -> Option<(String, String)> {
+    state
+        .remote
+        .members
+        .iter()
+        .find(|(_, nick, origin)| shown(state, nick, origin) == name)
+        .map(|(_, nick, origin)| (nick.clone(), origin.clone()))
+}
+
+// Accept links from other servers on `addr` (`--link-listen`).
+pub async fn listen(addr: String, db: Db) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Can't listen for server links on {}: {}", addr, e);
+            return;
+        }
+    };
+    println!("Listening for server links on {}", addr);
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("Error accepting server link: {}", e);
+                continue;
+            }
+        };
+        let db = db.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run_link(socket, &db, false).await {
+                println!("Server link from {} ended: {}", peer, e);
+            }
+        });
+    }
+}
+
+// Keep a link to the server at `addr` (`--peer`) up, reconnecting whenever
+// it drops. This runs for as long as the server does.
+pub async fn connect(addr: String, db: Db) {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        match TcpStream::connect(&addr).await {
+            Ok(socket) => {
+                // A link that worked resets the delay.
+                let result = run_link(socket, &db, true).await;
+                delay = Duration::from_secs(1);
+                if let Err(e) = result {
+                    println!("Server link to {} ended: {}", addr, e);
+                }
+            }
+            Err(e) => println!("Can't link to {}: {}", addr, e),
+        }
+        println!("Reconnecting to {} in {}s", addr, delay.as_secs());
+        tokio::time::sleep(delay).await;
+        // Double the delay each time, up to the maximum - this is called
+        // "exponential backoff" and stops us hammering a server that's down.
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+// Run one server link from handshake until it drops.
+// `outgoing` is true on the side that connected.
+async fn run_link(socket: TcpStream, db: &Db, outgoing: bool) -> Result<(), String> {
+    let password = std::env::var(LINK_PASSWORD_VAR)
+        .ok()
+        .filter(|p| !p.is_empty())
+        .ok_or(format!("{} is not set", LINK_PASSWORD_VAR))?;
+    let own_name = db.lock().await.name.clone();
+
+    let (reader, mut writer) = socket.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+
+    // The handshake proves that both sides know the password without ever
+    // sending it:
+    //   1. each side sends `LINK <name> <nonce>`, the side that connected first.
+    //      A nonce is a random number made up for this one handshake.
+    //   2. the side that connected sends `AUTH <proof>` (see `proof`)
+    //   3. the other side checks it, and only then sends its own `AUTH <proof>`
+    // A proof mixes both nonces, so one overheard on the network is no use in
+    // any later handshake. The side accepting links only proves itself after
+    // the other side has, so a stranger never gets a proof to guess the
+    // password from.
+    let nonce = new_nonce();
+    let hello = format!("LINK {} {}\n", own_name, nonce);
+    if outgoing {
+        send_line(&mut writer, &hello).await?;
+    }
+    let (peer, peer_nonce) = match read_handshake(&mut reader, &mut line).await?[..] {
+        ["LINK", name, peer_nonce] => (name.to_string(), peer_nonce.to_string()),
+        _ => return Err("bad handshake".to_string()),
+    };
+    // The same rules as for our own name, and a server claiming to be us
+    // would make our own messages look like they came back around.
+    check_server_name(&peer).map_err(|e| format!("bad handshake: {}", e))?;
+    if peer == own_name {
+        return Err("bad handshake: the other server has our name".to_string());
+    }
+    if !outgoing {
+        send_line(&mut writer, &hello).await?;
+    }
+    // Both proofs cover the same names and nonces, in the same order.
+    let facts = if outgoing {
+        format!("{} {} {} {}", own_name, peer, nonce, peer_nonce)
+    } else {
+        format!("{} {} {} {}", peer, own_name, peer_nonce, nonce)
+    };
+    let ours = format!("AUTH {}\n", proof(&password, outgoing, &facts));
+    if outgoing {
+        send_line(&mut writer, &ours).await?;
+    }
+    let wanted = proof(&password, !outgoing, &facts);
+    match read_handshake(&mut reader, &mut line).await?[..] {
+        ["AUTH", given] if same_secret(given, &wanted) => {}
+        _ => return Err("wrong link password".to_string()),
+    }
+    if !outgoing {
+        send_line(&mut writer, &ours).await?;
+    }
+
+    // Register the link, refusing a second link to the same server.
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    attach(&mut *db.lock().await, &peer, Box::new(tx))?;
+    println!("Linked with server {}", peer);
+
+    // The link's writer task, like a client's.
+    let writer_task = tokio::spawn(async move {
+        while let Some(line) = rx.recv().await {
+            if writer.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // Read relay lines until the link drops.
+    let result = loop {
+        line.clear();
+        match reader.read_line(&mut line).await {
+            Ok(0) => break Ok(()),
+            Ok(_) => receive_line(&mut *db.lock().await, &peer, &line),
+            Err(e) => break Err(e.to_string()),
+        }
+    };
+
+    detach(&mut *db.lock().await, &peer);
+    writer_task.abort();
+    println!("Lost link with server {}", peer);
+    result
+}
+
+// Read one handshake line into `line`, split into its words.
+async fn read_handshake<'a>(
+    reader: &mut BufReader<OwnedReadHalf>,
+    line: &'a mut String,
+) -> Result<Vec<&'a str>, String> {
+    line.clear();
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, reader.read_line(line)).await {
+        Ok(Ok(n)) if n > 0 => Ok(line.trim_end().split(' ').collect()),
+        _ => Err("no handshake".to_string()),
+    }
+}
+
+// A new nonce for the handshake: 16 random bytes, in hex.
+fn new_nonce() -> String {
+    let mut bytes = [0u8; 16];
+    // If the operating system can't give us random numbers, there's no safe
+    // way to go on.
+    getrandom::getrandom(&mut bytes).expect("no random numbers available");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// The HMAC-SHA256, keyed with the link password, of `facts` (both names and
+// both nonces), in hex. `connecting` says which side the proof is from, so
+// one side's proof can never be sent back as the other side's.
+fn proof(password: &str, connecting: bool, facts: &str) -> String {
+    // An HMAC key can be any length, so this never fails.
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(password.as_bytes()).expect("any key length works");
+    mac.update(if connecting { b"connect " } else { b"accept " });
+    mac.update(facts.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+// Write one line during the handshake, before the writer task exists.
+async fn send_line(writer: &mut OwnedWriteHalf, line: &str) -> Result<(), String> {
+    writer
+        .write_all(line.as_bytes())
+        .await
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,605 @@
+// Restarting the server without dropping anybody, e.g. to deploy a new build.
+//
+// There are two ways to do it.
+//
+// 1. systemd socket activation. systemd opens the listening socket itself and
+//    passes it to the server when it starts it (file descriptor 3, with
+//    LISTEN_FDS and LISTEN_PID set - see `listener`). While the server
+//    restarts, systemd keeps the socket open, so people connecting just wait
+//    a moment instead of being refused. People already connected are
+//    disconnected, though, and have to connect again. A handoff isn't
+//    possible then: systemd watches the process it started, and would stop
+//    the service once that process handed over and exited.
+//
+// 2. A handoff. A server operator types `/restart`, or someone sends the
+//    server a SIGUSR2 (`kill -USR2 <pid>`). The running server starts a new
+//    copy of itself - the program file as it is NOW, so a new build is picked
+//    up - with the same options, and hands over the listening socket AND every
+//    connected client:
+//    - the old server stops accepting connections (they queue up for the new
+//      one) and starts the new server, which inherits copies of the sockets
+//      and one end of a private "channel" (a pair of connected local sockets)
+//    - the new server reads its options and files, says it's ready over the
+//      channel and waits
+//    - the old server stops reading from its clients and writes everything
+//      down on the channel: who's connected with which nick, rooms and key,
+//      the message history, the read markers and anything half read. Then it
+//      exits, which closes its end of the channel
+//    - once the channel closes, the new server knows the old one is gone and
+//      carries on where it stopped. Clients only notice a short pause.
+//    Nothing goes through a file, so nobody else on the machine can read the
+//    messages or slip the new server a made-up list of clients.
+//    If the new server isn't ready within STARTUP_TIMEOUT (a mistake in the
+//    config, a broken build...) the old one stops it and keeps running.
+//
+// A handoff doesn't keep IRC clients, links to other servers (those connect
+// again by themselves), files being transferred, or what the plugins
+// remember. It needs Unix, where a socket is a file descriptor that a new
+// process can inherit.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Duration;
+
+use serde_json::{Value, json};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use chatty_rusty::protocol::{Frame, base64_decode, base64_encode};
+
+use crate::rooms::Rooms;
+use crate::state::{Db, State, Stored};
+use crate::unread::ReadMarkers;
+
+// The environment variables the old server passes to the new one:
+// the descriptors of its end of the channel, the listening socket and every
+// client socket it passed on.
+const CHANNEL_VAR: &str = "CHATTY_HANDOFF_CHANNEL";
+const LISTENER_VAR: &str = "CHATTY_HANDOFF_LISTENER";
+const FDS_VAR: &str = "CHATTY_HANDOFF_FDS";
+
+// How long the new server has to get ready before we give up on it.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long the new server waits for the old one to hand over and exit.
+const TAKE_OVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long the clients' reader tasks have to stop.
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How long the writer tasks get to send what's already queued.
+const DRAIN: Duration = Duration::from_millis(200);
+
+// What the new server sends over the channel once it's ready.
+const READY: u8 = b'!';
+
+// Why a server that systemd started can't hand over.
+const SYSTEMD: &str = "systemd started this server - use `systemctl restart` instead";
+
+// Where a handoff has got to. Every client's reader task and the accept loop
+// in `main.rs` watch it.
+// - `Running`: nothing's happening
+// - `Starting`: the new server is starting - don't accept anybody
+// - `Stopping`: stop reading, and send back what you read of an unfinished
+//   line, with your address
+#[derive(Clone)]
+pub enum Phase {
+    Running,
+    Starting,
+    Stopping(mpsc::UnboundedSender<(String, Vec<u8>)>),
+}
+
+// Resolves once accepting has to pause.
+pub async fn paused(phase: &mut watch::Receiver<Phase>) {
+    if phase
+        .wait_for(|phase| !matches!(phase, Phase::Running))
+        .await
+        .is_err()
+    {
+        // Nobody can start a handoff any more, so it never happens.
+        std::future::pending::<()>().await;
+    }
+}
+
+// Resolves once a handoff that failed is over and we're running again.
+pub async fn resumed(phase: &mut watch::Receiver<Phase>) {
+    let _ = phase
+        .wait_for(|phase| matches!(phase, Phase::Running))
+        .await;
+}
+
+// Resolves once a client's reader has to stop, with where its leftovers go.
+pub async fn stopping(
+    phase: &mut watch::Receiver<Phase>,
+) -> mpsc::UnboundedSender<(String, Vec<u8>)> {
+    if let Ok(phase) = phase
+        .wait_for(|phase| matches!(phase, Phase::Stopping(_)))
+        .await
+        && let Phase::Stopping(leftovers) = &*phase
+    {
+        return leftovers.clone();
+    }
+    std::future::pending().await
+}
+
+// A client the old server handed over, to be carried on by `main.rs`.
+pub struct Adopted {
+    pub addr: String,
+    pub stream: TcpStream,
+    pub nick: String,
+    pub operator: bool,
+    pub rooms: BTreeSet<String>,
+    pub room: Option<String>,
+    pub key: Option<String>,
+    pub leftover: Vec<u8>,
+}
+
+// The file descriptor of a client's socket, to pass it on later.
+pub fn fd_of(socket: &TcpStream) -> Option<i32> {
+    os::raw_fd(socket)
+}
+
+// The file descriptor of the listening socket, to pass it on later.
+pub fn listener_fd(listener: &tokio::net::TcpListener) -> Option<i32> {
+    os::raw_fd(listener)
+}
+
+// A listening socket we were given instead of having to open one: by the
+// server we're taking over from, or by systemd.
+pub fn listener() -> Option<std::net::TcpListener> {
+    let fd = match std::env::var(LISTENER_VAR) {
+        Ok(fd) => fd.parse().ok()?,
+        // systemd's sockets start at 3.
+        Err(_) if from_systemd() => 3,
+        Err(_) => return None,
+    };
+    let listener = os::listener(fd)?;
+    // Tokio needs sockets that never make it wait.
+    listener.set_nonblocking(true).ok()?;
+    Some(listener)
+}
+
+// Did systemd start us and give us its socket? LISTEN_PID makes sure the
+// socket is meant for us rather than for a program that started us.
+fn from_systemd() -> bool {
+    let number = |var| std::env::var(var).ok()?.parse::<u32>().ok();
+    std::env::var(LISTENER_VAR).is_err()
+        && number("LISTEN_PID") == Some(std::process::id())
+        && number("LISTEN_FDS").is_some_and(|count| count >= 1)
+}
+
+// If we were started by `/restart`: say we're ready, wait for the old server
+// to hand over and exit, and return what it handed over. None if this is an
+// ordinary start, or the old server gave up.
+pub async fn take_over() -> Option<Value> {
+    let fd: i32 = std::env::var(CHANNEL_VAR).ok()?.parse().ok()?;
+    println!("Taking over from the server that started us");
+    let snapshot = match os::channel(fd) {
+        Some(mut channel) => receive(&mut channel).await,
+        None => None,
+    };
+    if snapshot.is_none() {
+        println!("Nothing was handed over - starting afresh");
+        // Nobody will use the client sockets we inherited. Closing them is
+        // what tells those clients they've been disconnected.
+        for fd in passed() {
+            os::close(fd);
+        }
+    }
+    snapshot
+}
+
+// Say we're ready, then read what the old server hands over. The channel
+// only ends once the old server has exited - nothing else has a copy of its
+// end - so when this comes back the old server can't touch the clients any
+// more. If it gave up instead, or wrote only half, that's None.
+async fn receive(channel: &mut os::Channel) -> Option<Value> {
+    channel.write_all(&[READY]).await.ok()?;
+    let mut text = Vec::new();
+    tokio::time::timeout(TAKE_OVER_TIMEOUT, channel.read_to_end(&mut text))
+        .await
+        .ok()?
+        .ok()?;
+    serde_json::from_slice(&text).ok()
+}
+
+// Put what the old server handed over into `state`, and return its clients.
+pub fn restore(state: &mut State, snapshot: &Value) -> Vec<Adopted> {
+    // We read the rooms file when we started, but rooms may have changed
+    // since. The old server saved them just before the snapshot.
+    match Rooms::load(&state.config.rooms) {
+        Ok(rooms) => state.rooms = rooms,
+        Err(e) => println!("{} - keeping the rooms read at startup", e),
+    }
+    state.next_id = snapshot["next_id"].as_u64().unwrap_or(1);
+    state.next_xfer = snapshot["next_xfer"].as_u64().unwrap_or(1);
+    for stored in snapshot["history"].as_array().into_iter().flatten() {
+        // Messages travel as the MSG lines clients get.
+        let Some(Frame::Msg(msg)) = stored["line"].as_str().and_then(Frame::parse) else {
+            continue;
+        };
+        let mut reactions = BTreeMap::new();
+        for (emoji, who) in stored["reactions"].as_object().into_iter().flatten() {
+            reactions.insert(emoji.clone(), strings(who).into_iter().collect());
+        }
+        state.index.add(msg.id, &msg.text);
+        state.history.push_back(Stored {
+            msg,
+            author: stored["author"].as_str().unwrap_or_default().to_string(),
+            reactions,
+        });
+    }
+    state.read = ReadMarkers::from_json(&snapshot["read"]);
+    for (nick, ids) in snapshot["unseen"].as_object().into_iter().flatten() {
+        let ids = ids.as_array().into_iter().flatten();
+        state
+            .unseen
+            .insert(nick.clone(), ids.filter_map(Value::as_u64).collect());
+    }
+
+    let mut adopted = Vec::new();
+    let mut taken = Vec::new();
+    for client in snapshot["clients"].as_array().into_iter().flatten() {
+        let (Some(addr), Some(fd)) = (client["addr"].as_str(), client["fd"].as_i64()) else {
+            continue;
+        };
+        let fd = fd as i32;
+        taken.push(fd);
+        let Some(stream) = os::stream(fd)
+            .filter(|stream| stream.set_nonblocking(true).is_ok())
+            .and_then(|stream| TcpStream::from_std(stream).ok())
+        else {
+            println!("Couldn't take over {}", addr);
+            continue;
+        };
+        let text = |key: &str| client[key].as_str().map(str::to_string);
+        adopted.push(Adopted {
+            addr: addr.to_string(),
+            stream,
+            nick: text("nick").unwrap_or_else(|| addr.to_string()),
+            operator: client["operator"].as_bool().unwrap_or(false),
+            rooms: strings(&client["rooms"]).into_iter().collect(),
+            room: text("room"),
+            key: text("key"),
+            leftover: text("leftover")
+                .and_then(|leftover| base64_decode(&leftover))
+                .unwrap_or_default(),
+        });
+    }
+    // Clients that left while the handoff was under way are gone - close
+    // their sockets so they see it.
+    for fd in passed() {
+        if !taken.contains(&fd) {
+            os::close(fd);
+        }
+    }
+    println!(
+        "Took over {} client(s) and {} message(s)",
+        adopted.len(),
+        state.history.len()
+    );
+    adopted
+}
+
+// `/restart` hands the server over to a new copy of itself. Server operators only.
+pub fn command(state: &mut State, addr: &str) -> Vec<Frame> {
+    if !state.clients.get(addr).is_some_and(|c| c.operator) {
+        return vec![Frame::Error("only operators can restart the server".to_string())];
+    }
+    if from_systemd() {
+        return vec![Frame::Error(SYSTEMD.to_string())];
+    }
+    match &state.restart {
+        Some(restart) if restart.send(Some(addr.to_string())).is_ok() => {
+            vec![Frame::Info("restarting - starting the new server".to_string())]
+        }
+        _ => vec![Frame::Error("this server can't restart itself".to_string())],
+    }
+}
+
+// Hand over whenever the server gets a SIGUSR2, for as long as it runs.
+#[cfg(unix)]
+pub async fn on_user_signal(restart: mpsc::UnboundedSender<Option<String>>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut signals = match signal(SignalKind::user_defined2()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            println!("Can't listen for SIGUSR2: {}", e);
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        println!("SIGUSR2: restarting");
+        if restart.send(None).is_err() {
+            return;
+        }
+    }
+}
+
+// Wait for `/restart` (a request with the address of who asked) or a SIGUSR2
+// (a request without one) and hand over. A handoff that works ends this
+// process; one that fails is reported and we carry on.
+pub async fn run(
+    db: Db,
+    listener_fd: Option<i32>,
+    phase: watch::Sender<Phase>,
+    mut requests: mpsc::UnboundedReceiver<Option<String>>,
+) {
+    while let Some(asked_by) = requests.recv().await {
+        // `command` already refuses `/restart`, but a SIGUSR2 ends up here.
+        if from_systemd() {
+            tell(&db, asked_by, SYSTEMD).await;
+            continue;
+        }
+        let Some(listener_fd) = listener_fd else {
+            tell(&db, asked_by, "this server can't pass its sockets on").await;
+            continue;
+        };
+        println!("Restarting: handing over to a new server");
+        {
+            let state = db.lock().await;
+            let notice = Frame::Info("the server is restarting - you'll stay connected".to_string());
+            for addr in state.clients.keys() {
+                state.send_to(addr, notice.clone());
+            }
+        }
+        let result = hand_over(&db, listener_fd, &phase).await;
+        if let Err(e) = result {
+            phase.send_replace(Phase::Running);
+            tell(&db, asked_by, &format!("restart failed, still running: {}", e)).await;
+        }
+    }
+}
+
+// Print why a handoff didn't happen, and tell whoever asked for it.
+async fn tell(db: &Db, asked_by: Option<String>, error: &str) {
+    println!("{}", error);
+    if let Some(addr) = asked_by {
+        db.lock().await.send_to(&addr, Frame::Error(error.to_string()));
+    }
+}
+
+// Start the new server and hand everything over to it. Only comes back if
+// that failed - the new server never started, so nobody noticed.
+async fn hand_over(db: &Db, listener_fd: i32, phase: &watch::Sender<Phase>) -> Result<(), String> {
+    // Stop accepting people and start the new server. It inherits copies of
+    // the sockets and of its end of the channel: `inheritable` makes them,
+    // and once it has started we close ours. A copy has the same number in
+    // the new server.
+    let (mut channel, theirs) = os::channel_pair().ok_or("can't open a channel to the new server")?;
+    let (mut child, fds) = {
+        let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+        let state = db.lock().await;
+        phase.send_replace(Phase::Starting);
+        let listener = os::inheritable(listener_fd).ok_or("can't copy the listening socket")?;
+        let fds: BTreeMap<String, i32> = state
+            .clients
+            .iter()
+            .filter_map(|(addr, client)| Some((addr.clone(), os::inheritable(client.fd?)?)))
+            .collect();
+        let started = std::process::Command::new(exe)
+            .args(std::env::args_os().skip(1))
+            .env(CHANNEL_VAR, theirs.to_string())
+            .env(LISTENER_VAR, listener.to_string())
+            .env(FDS_VAR, fds.values().map(i32::to_string).collect::<Vec<_>>().join(","))
+            .spawn();
+        for fd in fds.values().chain([&listener, &theirs]) {
+            os::close(*fd);
+        }
+        (started.map_err(|e| format!("can't start the new server: {}", e))?, fds)
+    };
+
+    // Wait for it to be ready. If its end of the channel closes first, it
+    // stopped. Either way a new server we give up on is stopped and waited
+    // for, so it's really gone before we carry on.
+    let mut ready = [0];
+    let answer = tokio::time::timeout(STARTUP_TIMEOUT, channel.read(&mut ready)).await;
+    if !matches!(answer, Ok(Ok(1)) if ready[0] == READY) {
+        let _ = child.kill();
+        let status = child.wait().map_err(|e| e.to_string())?;
+        return Err(match answer {
+            Err(_) => "the new server wasn't ready in time".to_string(),
+            Ok(_) => format!("the new server stopped ({})", status),
+        });
+    }
+
+    // From here on there's no going back. Stop reading from the clients -
+    // each reader hands back what it read of a line that isn't finished yet.
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    phase.send_replace(Phase::Stopping(tx));
+    let still_here = {
+        let state = db.lock().await;
+        fds.keys().filter(|addr| state.clients.contains_key(*addr)).count()
+    };
+    let mut leftovers = HashMap::new();
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    while leftovers.len() < still_here {
+        match tokio::time::timeout_at(deadline, rx.recv()).await {
+            Ok(Some((addr, leftover))) => leftovers.insert(addr, leftover),
+            _ => break,
+        };
+    }
+
+    // Keep the state locked until we exit, so nothing changes after it's
+    // written down. The writers get a moment to send what's queued.
+    let state = db.lock().await;
+    tokio::time::sleep(DRAIN).await;
+    // Room changes are saved in the background: make sure the last one is
+    // on disk before the new server reads the file again (see `restore`).
+    state.rooms.save_now();
+    let snapshot = snapshot(&state, &fds, &leftovers);
+    match channel.write_all(snapshot.to_string().as_bytes()).await {
+        Ok(()) => println!(
+            "Handed {} client(s) over to process {} - bye",
+            fds.len(),
+            child.id()
+        ),
+        // The new server starts afresh once we're gone.
+        Err(e) => println!("Can't hand over: {} - the clients will have to reconnect", e),
+    }
+    // Files waiting for their recipients aren't handed over - throw them away.
+    crate::transfer::remove_staging_dir();
+    std::process::exit(0);
+}
+
+// Everything the new server needs, as JSON.
+fn snapshot(state: &State, fds: &BTreeMap<String, i32>, leftovers: &HashMap<String, Vec<u8>>) -> Value {
+    let history: Vec<Value> = state
+        .history
+        .iter()
+        .map(|stored| {
+            json!({
+                "line": Frame::Msg(stored.msg.clone()).to_line(),
+                "author": stored.author,
+                "reactions": stored.reactions,
+            })
+        })
+        .collect();
+    let clients: Vec<Value> = fds
+        .iter()
+        .filter_map(|(addr, fd)| {
+            let client = state.clients.get(addr)?;
+            let leftover = leftovers.get(addr).map(|bytes| base64_encode(bytes));
+            Some(json!({
+                "addr": addr,
+                "fd": fd,
+                "nick": client.nick,
+                "operator": client.operator,
+                "rooms": client.rooms,
+                "room": client.room,
+                "key": client.key,
+                "leftover": leftover,
+            }))
+        })
+        .collect();
+    json!({
+        "next_id": state.next_id,
+        "next_xfer": state.next_xfer,
+        "history": history,
+        "read": state.read.to_json(),
+        "unseen": state.unseen,
+        "clients": clients,
+    })
+}
+
+// The client sockets the old server passed us.
+fn passed() -> Vec<i32> {
+    let fds = std::env::var(FDS_VAR).unwrap_or_default();
+    fds.split(',').filter_map(|fd| fd.parse().ok()).collect()
+}
+
+// The strings in a JSON array.
+fn strings(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|s| s.as_str().map(str::to_string))
+        .collect()
+}
+
+// The few things only the operating system can do. On Unix a socket is a
+// numbered "file descriptor" that a program we start can inherit.
+#[cfg(unix)]
+mod os {
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::os::unix::net::UnixStream;
+
+    // The channel between the old and the new server.
+    pub type Channel = tokio::net::UnixStream;
+
+    pub fn raw_fd(socket: &impl AsRawFd) -> Option<i32> {
+        Some(socket.as_raw_fd())
+    }
+
+    // A copy of `fd` that programs we start inherit. Rust marks every file
+    // it opens "close on exec" so they don't leak into other programs; `dup`
+    // makes a copy without that mark.
+    pub fn inheritable(fd: i32) -> Option<i32> {
+        // SAFETY: `dup` only reads the number and makes a new descriptor.
+        let copy = unsafe { libc::dup(fd) };
+        (copy >= 0).then_some(copy)
+    }
+
+    pub fn close(fd: i32) {
+        // SAFETY: we only close copies `inheritable` made, and nothing else uses them.
+        unsafe { libc::close(fd) };
+    }
+
+    // A new channel: our end, and a descriptor of the other end for the new
+    // server to inherit. Both ends are "close on exec", so the only copy
+    // that leaks into the new server is the one we made for it.
+    pub fn channel_pair() -> Option<(Channel, i32)> {
+        let (ours, theirs) = UnixStream::pair().ok()?;
+        let inherited = inheritable(theirs.as_raw_fd())?;
+        Some((into_tokio(ours)?, inherited))
+    }
+
+    // Our end of the channel the old server passed us.
+    pub fn channel(fd: i32) -> Option<Channel> {
+        close_on_exec(fd)?;
+        // SAFETY: the old server passed us this descriptor for the channel.
+        into_tokio(unsafe { UnixStream::from_raw_fd(fd) })
+    }
+
+    fn into_tokio(stream: UnixStream) -> Option<Channel> {
+        stream.set_nonblocking(true).ok()?;
+        Channel::from_std(stream).ok()
+    }
+
+    // The sockets we inherited. Each descriptor is taken over exactly once.
+    pub fn stream(fd: i32) -> Option<std::net::TcpStream> {
+        close_on_exec(fd)?;
+        // SAFETY: the old server passed us this descriptor for this socket.
+        Some(unsafe { std::net::TcpStream::from_raw_fd(fd) })
+    }
+
+    pub fn listener(fd: i32) -> Option<std::net::TcpListener> {
+        close_on_exec(fd)?;
+        // SAFETY: whoever started us passed us this descriptor for this socket.
+        Some(unsafe { std::net::TcpListener::from_raw_fd(fd) })
+    }
+
+    // Put back the "close on exec" mark `inheritable` left off. Otherwise the
+    // NEXT server we start would get a stray copy of every socket, and
+    // clients leaving wouldn't be disconnected while it runs.
+    fn close_on_exec(fd: i32) -> Option<()> {
+        // SAFETY: `fcntl` only changes the flags of the descriptor.
+        let done = unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        (done == 0).then_some(())
+    }
+}
+
+// Elsewhere sockets can't be passed on, so there are never any to take over.
+#[cfg(not(unix))]
+mod os {
+    pub fn raw_fd<T>(_socket: &T) -> Option<i32> {
+        None
+    }
+
+    pub fn inheritable(_fd: i32) -> Option<i32> {
+        None
+    }
+
+    pub fn close(_fd: i32) {}
+
+    pub type Channel = tokio::io::DuplexStream;
+
+    pub fn channel_pair() -> Option<(Channel, i32)> {
+        None
+    }
+
+    pub fn channel(_fd: i32) -> Option<Channel> {
+        None
+    }
+
+    pub fn stream(_fd: i32) -> Option<std::net::TcpStream> {
+        None
+    }
+
+    pub fn listener(_fd: i32) -> Option<std::net::TcpListener> {
+        None
+    }
+}
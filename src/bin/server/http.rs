@@ -0,0 +1,252 @@
+// Just enough HTTP/1.1 to send webhooks and to take messages posted to us.
+//
+// HTTP is a text protocol much like ours: a request is a line saying what we
+// want, some `Name: value` header lines, an empty line and then the body.
+//
+//   POST /hook HTTP/1.1
+//   Host: 127.0.0.1:9000
+//   Content-Type: application/json
+//   Content-Length: 27
+//
+//   {"event":"join","nick":"x"}
+//
+// The answer starts with a status line like `HTTP/1.1 200 OK`, and the number
+// in it says how it went: 2xx is success, 4xx means we sent something wrong,
+// 5xx means the other side had a problem.
+//
+// Only plain `http://` is supported - there's no encryption (that's what
+// `https://` adds). Point webhooks at something on the same machine or
+// network, or at a local proxy that forwards to an `https://` address, and
+// put the same kind of proxy in front of our own endpoint if it's reachable
+// from outside.
+//
+// Every connection carries exactly one request (`Connection: close`), which
+// keeps both sides simple.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+// How long a request may take, from connecting to reading the status line.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// The most header lines, bytes per line and body bytes we accept in a
+// request, so nobody can make us read forever.
+const MAX_HEADERS: usize = 64;
+const MAX_LINE: u64 = 8 * 1024;
+pub const MAX_BODY: usize = 64 * 1024;
+
+// An `http://host[:port][/path]` address, taken apart.
+#[derive(Clone, Debug)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(text: &str) -> Result<Url, String> {
+        let rest = text
+            .strip_prefix("http://")
+            .ok_or(format!("{} doesn't start with http://", text))?;
+        // Everything up to the first `/` is the host and port.
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| format!("{} has a bad port number", text))?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("{} has no host", text));
+        }
+        Ok(Url {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+// Send `body` to `url` as a POST request with the extra `headers`, and
+// return the status code of the answer.
+pub async fn post(url: &Url, headers: &[(&str, String)], body: &str) -> Result<u16, String> {
+    match tokio::time::timeout(TIMEOUT, send(url, headers, body)).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    }
+}
+
+async fn send(url: &Url, headers: &[(&str, String)], body: &str) -> std::io::Result<u16> {
+    let mut socket = TcpStream::connect((url.host.as_str(), url.port)).await?;
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nUser-Agent: chatty_rusty/{}\r\n\
+         Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.host,
+        url.port,
+        env!("CARGO_PKG_VERSION"),
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    socket.write_all(request.as_bytes()).await?;
+
+    // We only need the status line, e.g. "HTTP/1.1 204 No Content".
+    let mut reader = BufReader::new(socket);
+    let mut status = String::new();
+    (&mut reader).take(MAX_LINE).read_line(&mut status).await?;
+    status
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| std::io::Error::other(format!("bad answer: {}", status.trim_end())))
+}
+
+// A request someone sent us. Header names are stored in lowercase, because
+// HTTP doesn't care how they're written.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    // The value of the header `name` (lowercase), if it was sent.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Read one request. On failure we return the status code and message to
+// answer with, e.g. (413, "body too large").
+pub async fn read_request<R>(reader: &mut BufReader<R>) -> Result<Request, (u16, String)>
+where
+    R: AsyncRead + Unpin,
+{
+    let bad = |what: &str| (400, what.to_string());
+    let mut line = String::new();
+    read_line(reader, &mut line)
+        .await
+        .map_err(|_| bad("can't read the request"))?
+        .ok_or((414, "the request line is too long".to_string()))?;
+    // "POST /messages HTTP/1.1"
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(bad("bad request line"));
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    // Header lines until an empty one.
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        read_line(reader, &mut line)
+            .await
+            .map_err(|_| bad("can't read the headers"))?
+            .ok_or((431, "a header line is too long".to_string()))?;
+        let header = line.trim_end_matches(['\r', '\n']);
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err((431, "too many headers".to_string()));
+        }
+        let (name, value) = header.split_once(':').ok_or(bad("bad header line"))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+
+    // `Content-Length` says how many bytes of body follow.
+    let length = match headers.iter().find(|(name, _)| name == "content-length") {
+        Some((_, value)) => value.parse().map_err(|_| bad("bad Content-Length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY {
+        return Err((413, format!("the body can be at most {} bytes", MAX_BODY)));
+    }
+    let mut body = vec![0u8; length];
+    reader
+        .read_exact(&mut body)
+        .await
+        .map_err(|_| bad("the body is shorter than Content-Length"))?;
+    let body = String::from_utf8(body).map_err(|_| bad("the body isn't UTF-8"))?;
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+// Read one line of at most MAX_LINE bytes into `line`. `take` stops the
+// reading after that many bytes, so a line that goes on and on can't fill
+// up our memory. We return None if the line was cut off there.
+async fn read_line<R>(reader: &mut BufReader<R>, line: &mut String) -> std::io::Result<Option<()>>
+where
+    R: AsyncRead + Unpin,
+{
+    let n = (&mut *reader).take(MAX_LINE).read_line(line).await?;
+    if n as u64 == MAX_LINE && !line.ends_with('\n') {
+        return Ok(None);
+    }
+    Ok(Some(()))
+}
+
+// Send an answer with a JSON `body` and the extra `headers`.
+pub async fn respond<W>(
+    writer: &mut W,
+    status: u16,
+    headers: &[(&str, String)],
+    body: &str,
+) -> std::io::Result<()>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n",
+        status,
+        reason(status),
+        body.len()
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    writer.write_all(response.as_bytes()).await
+}
+
+// The words that go after the status code. Only the codes we use.
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,616 @@
+// A listener that speaks (a small part of) IRC, so people can use the IRC
+// clients they already have - irssi, weechat, HexChat... - and still talk with
+// everyone using our own `client`. Start the server with `--irc-listen ADDR`.
+//
+// An IRC connection gets a `Client` entry like any other, so it has a nick,
+// joins the same rooms (an IRC "channel" is simply one of our rooms) and
+// receives the same frames. Its writer task turns those frames into IRC lines
+// instead of our own protocol.
+//
+// What we understand: NICK, USER, JOIN, PART, PRIVMSG, NAMES, PING, QUIT, MODE
+// for rooms (see `mode`), plus just enough of CAP and WHO to keep clients
+// happy. What IRC can't show - message IDs, edits, reactions, threads,
+// files - is left out.
+//
+// An IRC line looks like
+//
+//   [:prefix] COMMAND param param ... [:last param, may contain spaces]
+//
+// and ends with "\r\n". Replies from the server carry a three digit number,
+// the "numeric", e.g. `001` to say welcome or `433` for a nick that's taken.
+// A line is at most 512 bytes, "\r\n" included, and we hang up on a client
+// that sends a longer one.
+
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use chatty_rusty::protocol::Frame;
+
+use crate::commands::{valid_nick, valid_room};
+use crate::federation;
+use crate::plugins;
+use crate::rooms;
+use crate::state::{Client, Db, State};
+
+// The longest line IRC allows, in bytes.
+const MAX_LINE: u64 = 512;
+
+// Accept IRC connections on `addr` (`--irc-listen`).
+pub async fn listen(addr: String, db: Db) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Can't listen for IRC clients on {}: {}", addr, e);
+            return;
+        }
+    };
+    println!("Listening for IRC clients on {}", addr);
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("Error accepting IRC client: {}", e);
+                continue;
+            }
+        };
+        println!("New IRC connection from: {}", peer);
+        tokio::spawn(handle_irc(socket, peer.to_string(), db.clone()));
+    }
+}
+
+// One IRC connection, from connecting to quitting.
+// - `nick`: the nick asked for with NICK, until the client is registered
+// - `user`: whether we've had the USER line yet
+// - `registered`: whether the client is in the registry
+// - `password`: from PASS - the password of a registered nick (see `rooms.rs`)
+// - `replies`: numerics and other lines for this client only (see `Out`)
+// - `frames`: the channel for the `Client` entry, handed over when registering
+struct Conn {
+    addr: String,
+    server: String,
+    nick: Option<String>,
+    user: bool,
+    registered: bool,
+    password: String,
+    replies: mpsc::UnboundedSender<Out>,
+    frames: Option<mpsc::UnboundedSender<Frame>>,
+}
+
+// What the connection's own task tells the writer task, apart from frames.
+enum Out {
+    // A line to send as it is.
+    Line(String),
+    // The client has registered with this nick.
+    Registered(String),
+}
+
+async fn handle_irc(socket: TcpStream, addr: String, db: Db) {
+    let (reader, writer) = socket.into_split();
+    let server = db.lock().await.name.clone();
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (replies_tx, replies_rx) = mpsc::unbounded_channel();
+    tokio::spawn(write_irc(writer, rx, replies_rx, server.clone()));
+
+    let mut conn = Conn {
+        addr: addr.clone(),
+        server,
+        nick: None,
+        user: false,
+        registered: false,
+        password: String::new(),
+        replies: replies_tx,
+        frames: Some(tx),
+    };
+
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        // `take` stops reading after MAX_LINE bytes, so a client that never
+        // ends its line can't fill up our memory (like `http::read_line`).
+        match (&mut reader).take(MAX_LINE).read_line(&mut line).await {
+            Ok(0) => break,
+            Ok(n) if n as u64 == MAX_LINE && !line.ends_with('\n') => {
+                println!("IRC client {} sent a line over {} bytes", addr, MAX_LINE);
+                break;
+            }
+            Ok(_) => {
+                let text = line.trim_end_matches(['\r', '\n']);
+                println!("IRC {}: {}", addr, redacted(text));
+                let Some((command, params)) = parse(text) else {
+                    continue;
+                };
+                if command == "QUIT" {
+                    break;
+                }
+                handle_command(&mut conn, &command, &params, &db).await;
+            }
+            Err(e) => {
+                println!("Error reading from IRC client {}: {}", addr, e);
+                break;
+            }
+        }
+    }
+    println!("IRC client {} disconnected", addr);
+    if conn.registered {
+        db.lock().await.disconnect(&addr);
+    }
+}
+
+// Split an IRC line into its command (in capitals) and parameters.
+fn parse(line: &str) -> Option<(String, Vec<String>)> {
+    let mut rest = line.trim_start();
+    // A prefix from a client means nothing to us - skip it.
+    if rest.starts_with(':') {
+        rest = rest.split_once(' ')?.1;
+    }
+    // The last parameter may start with ':' and run to the end of the line.
+    let (head, trailing) = match rest.split_once(" :") {
+        Some((head, trailing)) => (head, Some(trailing)),
+        None => (rest, None),
+    };
+    let mut words = head.split_whitespace();
+    let command = words.next()?.to_ascii_uppercase();
+    let mut params: Vec<String> = words.map(String::from).collect();
+    if let Some(trailing) = trailing {
+        params.push(trailing.to_string());
+    }
+    Some((command, params))
+}
+
+impl Conn {
+    // Queue a numeric reply, e.g. `:server 433 * alice :Nickname is already in use`.
+    fn numeric(&self, code: &str, text: &str) {
+        let target = self.nick.as_deref().unwrap_or("*");
+        self.send(format!(":{} {} {} {}", self.server, code, target, text));
+    }
+
+    // Queue a line for this client exactly as given.
+    fn send(&self, line: String) {
+        let _ = self.replies.send(Out::Line(line));
+    }
+
+    // The parameter at `index`, or a 461 reply if the client left it out.
+    fn param<'a>(&self, command: &str, params: &'a [String], index: usize) -> Option<&'a str> {
+        let param = params.get(index).map(String::as_str);
+        if param.is_none() {
+            self.numeric("461", &format!("{} :Not enough parameters", command));
+        }
+        param
+    }
+}
+
+async fn handle_command(conn: &mut Conn, command: &str, params: &[String], db: &Db) {
+    // These work before registering.
+    match command {
+        "PING" => {
+            let token = params.first().map(String::as_str).unwrap_or("");
+            conn.send(format!(":{} PONG {} :{}", conn.server, conn.server, token));
+            return;
+        }
+        // We don't offer any capabilities, but saying so keeps clients that
+        // ask from waiting.
+        "CAP" => {
+            match params.first().map(|p| p.to_ascii_uppercase()).as_deref() {
+                Some("LS") | Some("LIST") => conn.send(format!(":{} CAP * LS :", conn.server)),
+                Some("REQ") => {
+                    let wanted = params.get(1).map(String::as_str).unwrap_or("");
+                    conn.send(format!(":{} CAP * NAK :{}", conn.server, wanted));
+                }
+                _ => {}
+            }
+            return;
+        }
+        // IRC clients send PASS before NICK. We keep it for a registered nick.
+        "PASS" => {
+            conn.password = params.first().cloned().unwrap_or_default();
+            return;
+        }
+        "PONG" => return,
+        "NICK" => {
+            nick(conn, params, db).await;
+            return;
+        }
+        "USER" => {
+            if conn.registered {
+                conn.numeric("462", ":You may not reregister");
+            } else if params.len() < 4 {
+                conn.numeric("461", "USER :Not enough parameters");
+            } else {
+                conn.user = true;
+                register(conn, db).await;
+            }
+            return;
+        }
+        _ if !conn.registered => {
+            conn.numeric("451", ":You have not registered");
+            return;
+        }
+        _ => {}
+    }
+
+    let addr = conn.addr.clone();
+    let mut guard = db.lock().await;
+    let state = &mut *guard;
+    let in_room = |state: &State, room: &str| {
+        state
+            .clients
+            .get(&addr)
+            .is_some_and(|c| c.rooms.contains(room))
+    };
+
+    match command {
+        "JOIN" => {
+            let Some(rooms) = conn.param(command, params, 0) else {
+                return;
+            };
+            // `JOIN 0` means "leave every channel".
+            if rooms == "0" {
+                let rooms = state
+                    .clients
+                    .get(&addr)
+                    .map(|c| c.rooms.clone())
+                    .unwrap_or_default();
+                for room in rooms {
+                    state.part(&addr, &room);
+                }
+                return;
+            }
+            // `JOIN #a,#b key1,key2`: the keys are the rooms' passwords.
+            let keys: Vec<&str> = params.get(1).map_or(Vec::new(), |keys| keys.split(',').collect());
+            for (n, room) in rooms.split(',').enumerate() {
+                if !valid_room(room) {
+                    conn.numeric("403", &format!("{} :No such channel", room));
+                } else if !in_room(state, room) {
+                    // `enter` sends the JOIN line back to us too, through
+                    // the frames channel. The names follow it.
+                    match state.enter(&addr, room, keys.get(n).unwrap_or(&"")) {
+                        Ok(()) => names(conn, state, room),
+                        Err(_) if state
+                            .rooms
+                            .get(room)
+                            .is_some_and(|r| r.banned.contains(&state.nick(&addr))) =>
+                        {
+                            conn.numeric("474", &format!("{} :Cannot join channel (+b)", room))
+                        }
+                        Err(_) if state.rooms.get(room).is_some_and(|r| r.invite_only) => {
+                            conn.numeric("473", &format!("{} :Cannot join channel (+i)", room))
+                        }
+                        Err(_) => conn.numeric("475", &format!("{} :Cannot join channel (+k)", room)),
+                    }
+                }
+            }
+        }
+        "PART" => {
+            let Some(rooms) = conn.param(command, params, 0) else {
+                return;
+            };
+            for room in rooms.split(',') {
+                if in_room(state, room) {
+                    state.part(&addr, room);
+                } else {
+                    conn.numeric("442", &format!("{} :You're not on that channel", room));
+                }
+            }
+        }
+        "PRIVMSG" | "NOTICE" => {
+            let (Some(target), Some(text)) = (params.first(), params.get(1)) else {
+                // NOTICE must never be answered, even with an error.
+                if command == "PRIVMSG" {
+                    conn.numeric("412", ":No text to send");
+                }
+                return;
+            };
+            if in_room(state, target) {
+                state.post(&addr, target, text, None);
+            } else if command == "NOTICE" {
+                // Again, no errors for a NOTICE.
+            } else if target.starts_with('#') {
+                conn.numeric("404", &format!("{} :Cannot send to channel", target));
+            } else if state.private(&addr, &from_irc_nick(target), text).is_err() {
+                // Not here, and not anyone we know of on a linked server.
+                conn.numeric("401", &format!("{} :No such nick/channel", target));
+            }
+        }
+        "NAMES" => match params.first() {
+            Some(rooms) => {
+                for room in rooms.split(',') {
+                    names(conn, state, room);
+                }
+            }
+            None => conn.numeric("366", "* :End of /NAMES list"),
+        },
+        // Rooms have modes (see `rooms.rs`); users have none.
+        "MODE" => match params.first() {
+            Some(target) if target.starts_with('#') => mode(conn, state, &addr, params),
+            Some(_) => conn.numeric("221", "+"),
+            None => conn.numeric("461", "MODE :Not enough parameters"),
+        },
+        "WHO" => {
+            let mask = params.first().map(String::as_str).unwrap_or("*");
+            conn.numeric("315", &format!("{} :End of /WHO list", mask));
+        }
+        _ => conn.numeric("421", &format!("{} :Unknown command", command)),
+    }
+}
+
+// A line from an IRC client the way it's safe to print, like
+// `commands::redacted`: the passwords in PASS and OPER, the keys of
+// `JOIN #a,#b key1,key2` and of `MODE #room +k key` are replaced with `***`.
+fn redacted(text: &str) -> Cow<'_, str> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let command = words.first().map(|word| word.to_ascii_uppercase());
+    match (command.as_deref(), words.as_slice()) {
+        (Some("PASS"), [_, _, ..]) => Cow::Borrowed("PASS ***"),
+        (Some("OPER"), [_, name, _, ..]) => Cow::Owned(format!("OPER {} ***", name)),
+        (Some("JOIN"), [_, rooms, _, ..]) => Cow::Owned(format!("JOIN {} ***", rooms)),
+        (Some("MODE"), [_, room, change, _, ..]) if change.contains('k') => {
+            Cow::Owned(format!("MODE {} {} ***", room, change))
+        }
+        _ => Cow::Borrowed(text),
+    }
+}
+
+// MODE on a room:
+// - `MODE #room` asks for its modes: 324 "#room +imt" (the password itself
+//   isn't shown, like in `/mode`);
+// - `MODE #room b` asks for the ban list, which many IRC clients do when
+//   they join: one 367 per banned nick and a 368 to end it;
+// - `MODE #room +m` and the like change them, exactly like our `/mode`. If
+//   that's refused, the reason comes back as a 482.
+fn mode(conn: &Conn, state: &mut State, addr: &str, params: &[String]) {
+    let room = params[0].as_str();
+    match params.get(1).map(String::as_str) {
+        None => {
+            let modes = state.rooms.get(room).map(|r| r.modes()).unwrap_or_default();
+            let modes = if modes.is_empty() { "+".to_string() } else { modes };
+            conn.numeric("324", &format!("{} {}", room, modes));
+        }
+        Some("b") | Some("+b") if params.len() == 2 => {
+            if let Some(settings) = state.rooms.get(room) {
+                for nick in &settings.banned {
+                    conn.numeric("367", &format!("{} {}!*@*", room, nick));
+                }
+            }
+            conn.numeric("368", &format!("{} :End of channel ban list", room));
+        }
+        Some(_) => {
+            for reply in rooms::mode(state, addr, &params.join(" ")) {
+                if let Frame::Error(text) = reply {
+                    conn.numeric("482", &format!("{} :{}", room, text));
+                }
+            }
+        }
+    }
+}
+
+// NICK: pick a nick before registering, or change it afterwards.
+async fn nick(conn: &mut Conn, params: &[String], db: &Db) {
+    let Some(nick) = params.first() else {
+        conn.numeric("431", ":No nickname given");
+        return;
+    };
+    if !valid_nick(nick) {
+        conn.numeric("432", &format!("{} :Erroneous nickname", nick));
+        return;
+    }
+    let taken = |state: &State| {
+        state.find_nick(nick).is_some_and(|addr| addr != conn.addr)
+            || plugins::is_plugin(state, nick)
+            || federation::taken_on_bus(state, nick)
+    };
+    let state = db.lock().await;
+    if taken(&state) {
+        conn.numeric("433", &format!("{} :Nickname is already in use", nick));
+        return;
+    }
+    // Checking the password lets go of the lock for a while (see `rooms.rs`),
+    // so someone may have taken the nick by the time it's done.
+    let (mut state, right) = rooms::check_nick_password(db, state, nick, &conn.password).await;
+    if taken(&state) {
+        conn.numeric("433", &format!("{} :Nickname is already in use", nick));
+        return;
+    }
+    if !right {
+        conn.numeric("433", &format!("{} :Nickname is registered - connect with its password", nick));
+        return;
+    }
+    if conn.registered {
+        // The NICK line comes back to us like it does for everybody else.
+        state.rename(&conn.addr, nick);
+        conn.nick = Some(nick.clone());
+    } else {
+        conn.nick = Some(nick.clone());
+        drop(state);
+        register(conn, db).await;
+    }
+}
+
+// Once we have both NICK and USER, add the client to the registry and welcome it.
+async fn register(conn: &mut Conn, db: &Db) {
+    let (Some(nick), true, false) = (conn.nick.clone(), conn.user, conn.registered) else {
+        return;
+    };
+    let taken = |state: &State| {
+        state.find_nick(&nick).is_some()
+            || plugins::is_plugin(state, &nick)
+            || federation::taken_on_bus(state, &nick)
+    };
+    // Someone may have taken the nick while we were waiting for USER - or,
+    // for a registered one, while we were checking its password.
+    let state = db.lock().await;
+    let (mut state, right) = rooms::check_nick_password(db, state, &nick, &conn.password).await;
+    if taken(&state) {
+        conn.numeric("433", &format!("{} :Nickname is already in use", nick));
+        conn.nick = None;
+        return;
+    }
+    if !right {
+        conn.numeric("433", &format!("{} :Nickname is registered - connect with its password", nick));
+        conn.nick = None;
+        return;
+    }
+    let Some(tx) = conn.frames.take() else {
+        return;
+    };
+    // IRC clients can't receive files, so nothing reads this channel.
+    let (bulk, _) = mpsc::channel(1);
+    state.connect(
+        &conn.addr,
+        Client {
+            nick: nick.clone(),
+            operator: false,
+            rooms: BTreeSet::new(),
+            room: None,
+            bulk,
+            key: None,
+            fd: None,
+        },
+        tx,
+    );
+    conn.registered = true;
+    let _ = conn.replies.send(Out::Registered(nick.clone()));
+    println!("{} registered over IRC as {}", conn.addr, nick);
+
+    let version = env!("CARGO_PKG_VERSION");
+    conn.numeric("001", &format!(":Welcome to Chatty Rusty, {}", nick));
+    conn.numeric(
+        "002",
+        &format!(":Your host is {}, running chatty_rusty {}", conn.server, version),
+    );
+    conn.numeric("004", &format!("{} chatty_rusty-{} o o", conn.server, version));
+    conn.numeric(
+        "251",
+        &format!(":There are {} users on this server", state.clients.len()),
+    );
+    // The message of the day: a start line, the lines, an end line.
+    let motd = state.motd.lines();
+    if motd.is_empty() {
+        conn.numeric("422", ":MOTD File is missing");
+    } else {
+        conn.numeric("375", &format!(":- {} Message of the day -", conn.server));
+        for line in motd {
+            conn.numeric("372", &format!(":- {}", line));
+        }
+        conn.numeric("376", ":End of /MOTD command");
+    }
+}
+
+// Send the 353/366 numerics listing who's in `room`.
+fn names(conn: &Conn, state: &State, room: &str) {
+    let mut nicks: Vec<String> = state
+        .clients
+        .values()
+        .filter(|c| c.rooms.contains(room))
+        .map(|c| c.nick.clone())
+        .collect();
+    nicks.extend(federation::remote_nicks(state, room).iter().map(|n| irc_nick(n)));
+    if !nicks.is_empty() {
+        conn.numeric("353", &format!("= {} :{}", room, nicks.join(" ")));
+    }
+    conn.numeric("366", &format!("{} :End of /NAMES list", room));
+}
+
+// IRC uses `@` to separate a nick from a host, so people on linked servers
+// (`bob@office2`) are shown as `bob|office2` instead.
+fn irc_nick(nick: &str) -> String {
+    nick.replace('@', "|")
+}
+
+// The other way round, for nicks an IRC client sends us.
+fn from_irc_nick(nick: &str) -> String {
+    nick.replace('|', "@")
+}
+
+// The writer task of an IRC client. Like `write_frames` in `main.rs`, but it
+// turns frames into IRC lines. It keeps track of the client's nick so it can
+// leave out the client's own messages - IRC clients show those themselves.
+// Frames come first: a JOIN must reach the client before the names list that
+// follows it.
+async fn write_irc(
+    mut writer: OwnedWriteHalf,
+    mut rx: mpsc::UnboundedReceiver<Frame>,
+    mut replies: mpsc::UnboundedReceiver<Out>,
+    server: String,
+) {
+    let mut nick = String::from("*");
+    loop {
+        let line = tokio::select! {
+            biased;
+            Some(frame) = rx.recv() => match to_irc(frame, &mut nick, &server) {
+                Some(line) => line,
+                None => continue,
+            },
+            Some(out) = replies.recv() => match out {
+                Out::Line(line) => line,
+                Out::Registered(registered) => {
+                    nick = registered;
+                    continue;
+                }
+            },
+            else => break,
+        };
+        if writer
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+// Turn one of our frames into an IRC line, if IRC has a way to show it.
+fn to_irc(frame: Frame, nick: &mut String, server: &str) -> Option<String> {
+    // `nick!user@host` - we use the nick as the user, and the server as host.
+    let source = |who: &str| {
+        let who = irc_nick(who);
+        format!("{}!{}@{}", who, who, server)
+    };
+    match frame {
+        Frame::Msg(msg) => {
+            let sender = match &msg.origin {
+                Some(origin) => format!("{}@{}", msg.sender, origin),
+                None if msg.sender == *nick => return None,
+                None => msg.sender,
+            };
+            Some(format!(":{} PRIVMSG {} :{}", source(&sender), msg.room, msg.text))
+        }
+        // IRC clients show what they sent themselves, so our own copy is skipped.
+        Frame::Private { from, to, text, .. } if from != *nick => {
+            Some(format!(":{} PRIVMSG {} :{}", source(&from), irc_nick(&to), text))
+        }
+        Frame::Join { nick: who, room } => Some(format!(":{} JOIN {}", source(&who), room)),
+        Frame::Part { nick: who, room, reason } if reason.is_empty() => {
+            Some(format!(":{} PART {}", source(&who), room))
+        }
+        Frame::Part { nick: who, room, reason } => {
+            Some(format!(":{} PART {} :{}", source(&who), room, reason))
+        }
+        Frame::Nick { old, new } => {
+            let line = format!(":{} NICK {}", source(&old), irc_nick(&new));
+            if old == *nick {
+                *nick = new;
+            }
+            Some(line)
+        }
+        // RPL_TOPIC, which IRC clients show at the top of the channel.
+        Frame::Topic { room, topic } => Some(format!(":{} 332 {} {} :{}", server, nick, room, topic)),
+        // What `/motd` answers.
+        Frame::Motd(text) => Some(format!(":{} 372 {} :- {}", server, nick, text)),
+        Frame::Info(text) | Frame::Error(text) => {
+            Some(format!(":{} NOTICE {} :{}", server, nick, text))
+        }
+        Frame::Offer { sender, name, .. } => Some(format!(
+            ":{} NOTICE {} :{} wants to send you {} - receiving files needs the Chatty Rusty client",
+            server, nick, sender, name
+        )),
+        _ => None,
+    }
+}
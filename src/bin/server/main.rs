@@ -0,0 +1,549 @@
+// We're importing TcpListener from Tokio's networking module.
+// TcpListener is what allows our server to "listen" for incoming TCP connections,
+// just like a receptionist waiting for visitors to arrive.
+use tokio::net::TcpListener;
+
+// `Arc` stands for "Atomically Reference Counted" - it lets multiple parts of
+// your program share ownership of the same data safely.
+// Think of it as a shared pointer that keeps a count of how many owners exist,
+// and only cleans up the data when the last owner is gone.
+use std::sync::Arc;
+
+// `Mutex` stands for "Mutual Exclusion" - it ensures only one task can
+// access the shared data at a time, preventing race conditions.
+// A race condition is when two tasks try to modify the same data simultaneously,
+// causing unpredictable bugs.
+// `mpsc` is a channel: a queue one task pushes values into and another
+// task takes them out of, in the same order.
+// `watch` is a channel that only keeps the latest value - every task
+// watching it can see what it is now, and wait for it to change.
+use tokio::sync::{mpsc, watch, Mutex};
+
+// `TcpStream` represents an active TCP connection with a client.
+// Once a client connects, all communication happens through a TcpStream.
+use tokio::net::TcpStream;
+
+// `OwnedWriteHalf` is one half of a split TcpStream - the writing half.
+// Tokio allows us to split a TcpStream into a read half and a write half.
+// This is very useful because we want to:
+// - Read incoming messages from a client on one side
+// - Write outgoing messages to a client on the other side
+// Each client gets its own writer task that owns the write half and writes
+// whatever frames the other tasks queue up for it.
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+// `BufReader` wraps a reader and adds an internal buffer to it.
+// Without buffering we'd have to read one byte at a time which is very inefficient.
+// BufReader accumulates incoming bytes and lets us read higher level constructs
+// like entire lines in one operation.
+use tokio::io::BufReader;
+
+// `AsyncBufReadExt` is a trait that extends BufReader with async methods.
+// Specifically it gives us the `read_until()` method we use to read a full
+// line of text from a client. Without importing this trait, `read_until`
+// would simply not exist on our BufReader.
+// A trait in Rust is a collection of methods that a type can implement -
+// similar to interfaces in other languages.
+use tokio::io::AsyncBufReadExt;
+
+// `AsyncWriteExt` is a trait that gives us async write methods on our write half.
+// Specifically it provides `write_all()` which we use to send messages to clients.
+// Just like AsyncBufReadExt gave us `read_until()` for reading,
+// AsyncWriteExt gives us `write_all()` for writing.
+use tokio::io::AsyncWriteExt;
+
+// `AsyncReadExt` gives us `chain()`, which reads one thing to its end and
+// then carries on with another.
+use tokio::io::AsyncReadExt;
+
+// `Cursor` reads from bytes we already have in memory.
+use std::io::Cursor;
+
+// `BTreeSet` is a sorted set - we use it for the rooms a client is in.
+use std::collections::BTreeSet;
+
+// The protocol module lives in our own library (`src/protocol.rs`) so the
+// client can use exactly the same definitions to read what we send.
+use chatty_rusty::protocol::Frame;
+
+// The server is split into a few files. `mod` tells Rust to look for each
+// module in a file with the same name next to this one:
+// - `state.rs`: the shared State - clients, rooms and message history
+// - `commands.rs`: what each `/command` does
+// - `transfer.rs`: sending files through the server
+// - `config.rs`: the command line options
+// - `federation.rs`: linking servers together
+// - `bus.rs`: sharing rooms between copies of the server through a message bus
+// - `irc.rs`: letting IRC clients connect too
+// - `plugins/`: bots that run inside the server (a folder, with `mod.rs` inside)
+// - `webhooks.rs`: telling other programs about chat events over HTTP
+// - `inbound.rs`: letting programs post messages over HTTP
+// - `http.rs`: the little bit of HTTP the webhooks and `inbound.rs` need
+// - `search.rs`: finding messages in the history with `/search`
+// - `unread.rs`: how far everyone has read, for `/rooms` and `/unread`
+// - `rooms.rs`: room owners, operators and modes - who may join and talk
+// - `motd.rs`: the message of the day everyone sees when they connect
+// - `reload.rs`: applying a changed configuration on SIGHUP or `/reload`
+// - `handoff.rs`: restarting without dropping anyone
+mod bus;
+mod commands;
+mod config;
+mod federation;
+mod handoff;
+mod http;
+mod inbound;
+mod irc;
+mod motd;
+mod plugins;
+mod reload;
+mod rooms;
+mod search;
+mod state;
+mod transfer;
+mod unread;
+mod webhooks;
+
+use bus::BusKind;
+use config::Config;
+use handoff::{Adopted, Phase};
+use motd::Motd;
+use rooms::Rooms;
+use state::{Client, Db, State, DEFAULT_ROOM};
+use transfer::Uploads;
+use webhooks::Webhooks;
+
+// How many file chunks may wait in a client's `bulk` channel. When it's
+// full the download task waits, so a slow client can't make us buffer a
+// whole file in memory.
+const BULK_QUEUE: usize = 8;
+
+// This attribute macro transforms our regular main function into an async one
+// powered by the Tokio runtime. Rust by default doesn't know how to run async code -
+// Tokio provides the engine that actually drives it.
+// Think of it as starting a car engine before you can drive.
+#[tokio::main]
+
+// `async fn main()` is our program's entry point. The `async` keyword means this
+// function can perform non-blocking operations - it can "pause and wait" for things
+// like network connections without freezing the entire program.
+async fn main() {
+
+    // Read the command line options. If they don't make sense, say why and stop.
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // `TcpListener::bind(...)` tells the OS: "I want to receive TCP connections
+    // on this IP address and port." 
+    // - "127.0.0.1" is localhost, meaning only connections from this same machine.
+    // - "8080" is the default port number (like a specific door in a building).
+    // `.await` pauses here until the OS confirms the port is reserved.
+    // `.unwrap()` means: "if this fails, crash immediately with an error message."
+    // In production code you'd handle errors more gracefully, but this is fine for learning.
+    // If we were handed a socket that's already listening - by systemd, or
+    // by the server we're taking over from - we use that instead (see `handoff.rs`).
+    let listener = match handoff::listener() {
+        Some(inherited) => {
+            let listener = TcpListener::from_std(inherited).unwrap();
+            let addr = listener.local_addr().unwrap();
+            println!("Chatty Rusty server {} listening on {} (inherited)", config.name, addr);
+            listener
+        }
+        None => {
+            let listener = TcpListener::bind(&config.listen).await.unwrap();
+            // Simply print a message to the terminal so we know the server started successfully.
+            println!("Chatty Rusty server {} listening on {}", config.name, config.listen);
+            listener
+        }
+    };
+
+    // Create the empty shared state, wrap it in a Mutex, then wrap that in an Arc.
+    // This is our shared client registry - every connected client will be stored here.
+    let mut state = State::new(config.clone());
+    if let Some(path) = &config.webhooks {
+        match Webhooks::load(path) {
+            Ok(webhooks) => state.webhooks = webhooks,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+    // Room settings saved the last time the server ran - see `rooms.rs`.
+    match Rooms::load(&config.rooms) {
+        Ok(rooms) => state.rooms = rooms,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(2);
+        }
+    }
+    match Motd::load(&config.motd) {
+        Ok(motd) => state.motd = motd,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(2);
+        }
+    }
+    // Read the HTTP tokens now, so a mistake in the file stops us right away.
+    let http_tokens = match config.http_tokens.as_deref().map(inbound::load_tokens) {
+        Some(Ok(tokens)) => Some(tokens),
+        Some(Err(e)) => {
+            eprintln!("error: {}", e);
+            std::process::exit(2);
+        }
+        None => None,
+    };
+    state.http_tokens = http_tokens.clone();
+    plugins::configure(&mut state);
+
+    // If `/restart` started us, wait for the old server to hand over its
+    // clients and history. Everything that could go wrong at startup has
+    // been checked by now - see `handoff.rs`.
+    let adopted = match handoff::take_over().await {
+        Some(snapshot) => handoff::restore(&mut state, &snapshot),
+        None => Vec::new(),
+    };
+    // Nicks that aren't registered lose their roles once nobody uses them
+    // (see `rooms.rs`). Since we last ran, only the clients handed over to
+    // us have kept theirs - do this before anyone else can connect.
+    let online = adopted.iter().map(|client| client.nick.clone()).collect();
+    state.rooms.keep_online(&online);
+    // Copies of the server on a message bus share their rooms, so from now
+    // on every change goes to the bus too (see `rooms.rs`).
+    let (share, shared) = mpsc::unbounded_channel();
+    if config.bus != BusKind::Local {
+        state.rooms.share_with(Box::new(share));
+    }
+
+    // `/restart` and SIGUSR2 ask for a handoff through `restart`, and
+    // `phase` tells the clients' tasks how far it has got.
+    let (restart, requests) = mpsc::unbounded_channel();
+    state.restart = Some(restart.clone());
+    let (phase, phases) = watch::channel(Phase::Running);
+    let db: Db = Arc::new(Mutex::new(state));
+
+    // Linking with other servers runs in the background, next to the clients.
+    if let Some(addr) = config.link_listen.clone() {
+        tokio::spawn(federation::listen(addr, db.clone()));
+    }
+    for peer in config.peers.clone() {
+        tokio::spawn(federation::connect(peer, db.clone()));
+    }
+    tokio::spawn(bus::run(config.bus, db.clone(), shared));
+    if let Some(addr) = config.irc_listen.clone() {
+        tokio::spawn(irc::listen(addr, db.clone()));
+    }
+    if let (Some(addr), Some(tokens)) = (config.http_listen.clone(), http_tokens) {
+        tokio::spawn(inbound::listen(addr, tokens, db.clone()));
+    }
+    tokio::spawn(plugins::tick(db.clone()));
+    #[cfg(unix)]
+    tokio::spawn(reload::on_hangup(db.clone()));
+    #[cfg(unix)]
+    tokio::spawn(handoff::on_user_signal(restart));
+    let listener_fd = handoff::listener_fd(&listener);
+    tokio::spawn(handoff::run(db.clone(), listener_fd, phase, requests));
+
+    // Carry on with the clients the old server handed over.
+    for client in adopted {
+        adopt(client, &db, phases.clone()).await;
+    }
+    let mut paused = phases.clone();
+
+    // `loop` is Rust's infinite loop - it runs forever until the program is killed.
+    // Our server should always be running and ready to accept new connections,
+    // so an infinite loop is exactly what we want here.
+    loop {
+
+        // `listener.accept()` waits for a new client to connect.
+        // `.await` pauses here (without blocking other work) until someone connects.
+        // When a connection arrives, it returns two things which we unpack:
+        // - `socket`: the communication channel with that specific client
+        // - `addr`: the client's IP address and port (e.g. "127.0.0.1:54321")
+        // `.unwrap()` again crashes on error - acceptable for now.
+        // While a handoff is starting the new server we stop accepting:
+        // people connecting wait in the queue for whichever server ends up
+        // running.
+        let (socket, addr) = tokio::select! {
+            biased;
+            _ = handoff::paused(&mut paused) => {
+                handoff::resumed(&mut paused).await;
+                continue;
+            }
+            accepted = listener.accept() => accepted.unwrap(),
+        };
+
+        // Print the new client's address so we can see who connected.
+        // The `{}` is Rust's placeholder for displaying a value, similar to
+        // Python's f-strings or JavaScript's template literals.
+        println!("New connection from: {}", addr);
+
+        // Before we can pass `db` into a new task, we need to clone the Arc.
+        // IMPORTANT: cloning an Arc does NOT copy the underlying data -
+        // it just creates a new pointer to the same data and increments the reference count.
+        // This is cheap and is the intended way to share an Arc across tasks.
+        let db_clone = db.clone();
+        let phase = phases.clone();
+
+        // `tokio::spawn` launches a new task to handle this client independently.
+        // The `async move` block creates an async closure that takes ownership
+        // of the variables it uses - in this case `socket`, `addr`, and `db_clone`.
+        // `move` means the task owns these values, not the main loop.
+        // This is necessary because the main loop continues immediately to wait
+        // for the next connection, so we can't borrow - we must transfer ownership.
+        tokio::spawn(async move {
+            handle_client(socket, addr.to_string(), db_clone, phase).await;
+        });
+
+    } // Back to the top of the loop - wait for the next connection
+}
+
+// This function will handle an individual client connection.
+// It receives:
+// - `socket`: the full TcpStream for this client
+// - `addr`: the client's address as a String, used as their unique identifier
+// - `db`: the shared registry of all connected clients
+// - `phase`: where a `/restart` has got to, if one is under way
+async fn handle_client(socket: TcpStream, addr: String, db: Db, phase: watch::Receiver<Phase>) {
+    let fd = handoff::fd_of(&socket);
+    let (reader, tx, bulk_tx) = split(socket);
+
+    // Lock the Mutex to get exclusive access to the State, insert this
+    // client's entry and put them in the default room.
+    // `.lock().await` pauses until the lock is available.
+    // The lock is automatically released when the guard goes out of scope at
+    // the end of this block - this is Rust's ownership system keeping things safe.
+    {
+        let mut state = db.lock().await;
+        state.connect(
+            &addr,
+            Client {
+                nick: addr.clone(),
+                operator: false,
+                rooms: BTreeSet::new(),
+                room: None,
+                bulk: bulk_tx,
+                key: None,
+                fd,
+            },
+            tx,
+        );
+        // Say hello before anything else - see `motd.rs`.
+        for frame in motd::welcome(&state) {
+            state.send_to(&addr, frame);
+        }
+        state.join(&addr, DEFAULT_ROOM);
+    }
+
+    println!("{} has been added to the client registry", addr);
+    serve(reader, Vec::new(), addr, db, phase).await;
+}
+
+// Put a client the old server handed over back in the registry, just as it
+// was, and carry on reading from it.
+async fn adopt(adopted: Adopted, db: &Db, phase: watch::Receiver<Phase>) {
+    let fd = handoff::fd_of(&adopted.stream);
+    let (reader, tx, bulk_tx) = split(adopted.stream);
+    {
+        let mut state = db.lock().await;
+        state.connect(
+            &adopted.addr,
+            Client {
+                nick: adopted.nick,
+                operator: adopted.operator,
+                rooms: adopted.rooms,
+                room: adopted.room,
+                bulk: bulk_tx,
+                key: adopted.key,
+                fd,
+            },
+            tx,
+        );
+        state.send_to(
+            &adopted.addr,
+            Frame::Info("the server has restarted - carry on".to_string()),
+        );
+    }
+    let db = db.clone();
+    tokio::spawn(async move {
+        serve(reader, adopted.leftover, adopted.addr, db, phase).await;
+    });
+}
+
+// Start a client's writer task and return the read half of its socket and
+// the two channels leading to the writer.
+fn split(socket: TcpStream) -> (OwnedReadHalf, mpsc::UnboundedSender<Frame>, mpsc::Sender<Frame>) {
+    // `into_split()` consumes the TcpStream and splits it into two independent halves:
+    // - `reader`: we use this to READ messages coming FROM this client
+    // - `writer`: we hand this to the writer task which WRITES messages TO this client
+    let (reader, writer) = socket.into_split();
+
+    // Create the two channels leading to this client's writer task.
+    // `unbounded_channel` has no size limit, so sending into it never waits.
+    // `channel(BULK_QUEUE)` holds at most BULK_QUEUE frames.
+    // Each call gives us a sending end (`tx`) and a receiving end (`rx`).
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (bulk_tx, bulk_rx) = mpsc::channel(BULK_QUEUE);
+    tokio::spawn(write_frames(writer, rx, bulk_rx));
+    (reader, tx, bulk_tx)
+}
+
+// Read the lines a client sends and act on them until it disconnects.
+// `leftover` is what the old server had already read of a line that wasn't
+// finished when it handed this client over - empty for everyone else.
+async fn serve(
+    reader: OwnedReadHalf,
+    leftover: Vec<u8>,
+    addr: String,
+    db: Db,
+    mut phase: watch::Receiver<Phase>,
+) {
+    // `BufReader` wraps our read half and adds buffering to it.
+    // Without buffering, we'd have to read one byte at a time which is very inefficient.
+    // BufReader accumulates incoming bytes into an internal buffer and lets us
+    // read higher level constructs - like entire lines - in one operation.
+    // The leftover bytes come first, then whatever arrives on the socket.
+    let mut buf_reader = BufReader::new(Cursor::new(leftover).chain(reader));
+
+    // We create an empty buffer that will be reused on each iteration to hold
+    // the bytes of the current line. Using `mut` because its content will change.
+    let mut line = Vec::new();
+
+    // The files this client is uploading right now.
+    let mut uploads = Uploads::new();
+
+    // This loop keeps running as long as the client is connected.
+    // Each iteration waits for a complete line of text from the client.
+    loop {
+        // `read_until` reads bytes from the buffer until it hits a newline character `\n`
+        // and appends them to `line`.
+        // It returns a Result containing how many bytes were read.
+        // `.await` pauses here until a full line arrives - during this pause
+        // Tokio can run other tasks on this thread freely.
+        // Meanwhile we watch for a handoff: when the new server is ready to
+        // take over, we stop reading and give back what we have of an
+        // unfinished line. `read_until` keeps the bytes it has read in
+        // `line`, so stopping it halfway loses nothing.
+        let read = tokio::select! {
+            biased;
+            leftovers = handoff::stopping(&mut phase) => {
+                uploads.abort_all(&db).await;
+                let (cursor, _) = buf_reader.get_ref().get_ref();
+                let unread = &cursor.get_ref()[cursor.position() as usize..];
+                let mut leftover = std::mem::take(&mut line);
+                leftover.extend_from_slice(buf_reader.buffer());
+                leftover.extend_from_slice(unread);
+                let _ = leftovers.send((addr, leftover));
+                // The client stays in the registry: it's the new server's now.
+                return;
+            }
+            read = buf_reader.read_until(b'\n', &mut line) => read,
+        };
+        match read {
+            // `Ok(0)` means zero bytes were read - this is how TCP signals
+            // that the client has disconnected. We break out of the loop.
+            Ok(0) => {
+                println!("{} disconnected", addr);
+                break;
+            }
+            // `Ok(n)` means we successfully read n bytes - we have a complete line!
+            Ok(n) => {
+                // Strip the trailing newline - the protocol adds its own.
+                // `from_utf8_lossy` turns the bytes into text, replacing
+                // anything that isn't valid UTF-8 with '�'.
+                let text = String::from_utf8_lossy(&line);
+                let text = text.trim_end_matches(['\r', '\n']);
+
+                // Upload lines are handled on their own, and not printed:
+                // they can be thousands of characters of base64.
+                if Uploads::is_upload_line(text) {
+                    uploads.handle(text, &addr, &db).await;
+                    line.clear();
+                    continue;
+                }
+
+                // Passwords are hidden - see `commands::redacted`.
+                println!("Received {} bytes from {}: {}", n, addr, commands::redacted(text));
+
+                // Lines starting with `/` are commands for the server,
+                // everything else is a chat message for the client's active room.
+                if text.starts_with('/') {
+                    commands::handle_command(text, &addr, &db).await;
+                } else if !text.is_empty() {
+                    say(text, &addr, &db).await;
+                }
+
+                // We must clear the line buffer after each read, otherwise the next
+                // read_until call will APPEND to the existing content instead of
+                // replacing it, giving us garbled messages.
+                line.clear();
+            }
+            // `Err` means something went wrong with the connection - e.g. the client
+            // crashed or the network dropped. We log it and break out of the loop.
+            Err(e) => {
+                println!("Error reading from {}: {}", addr, e);
+                break;
+            }
+        }
+    }
+
+    // Throw away any uploads that didn't finish.
+    uploads.abort_all(&db).await;
+
+    // When the loop ends the client has disconnected. We remove them from the
+    // registry so we don't try to forward messages to a dead connection.
+    db.lock().await.disconnect(&addr);
+    println!("{} has been removed from the client registry", addr);
+}
+
+// Send a chat message from `addr` to their active room.
+async fn say(text: &str, addr: &str, db: &Db) {
+    // Lock the db to get access to all connected clients.
+    // We keep the lock until `post` is done - this is what guarantees that
+    // messages are delivered in ID order (see the comment on `State`).
+    let mut state = db.lock().await;
+    match state.clients.get(addr).and_then(|c| c.room.clone()) {
+        Some(room) => {
+            state.post(addr, &room, text, None);
+        }
+        None => state.send_to(
+            addr,
+            Frame::Error("you're not in any room - /join #room first".to_string()),
+        ),
+    }
+}
+
+// Every client has one of these tasks. It owns the write half of the socket
+// and writes every frame that arrives on the two channels, turning each one
+// into a line of text.
+async fn write_frames(
+    mut writer: OwnedWriteHalf,
+    mut rx: mpsc::UnboundedReceiver<Frame>,
+    mut bulk_rx: mpsc::Receiver<Frame>,
+) {
+    loop {
+        // `tokio::select!` waits on both channels at once and takes whichever
+        // has a frame ready. `biased;` makes it check them in the order written,
+        // so chat frames always go before file chunks.
+        // `recv()` returns None once every sender is gone - when that happens
+        // to a branch it's disabled, and once both are, `else` ends the loop.
+        let frame = tokio::select! {
+            biased;
+            Some(frame) = rx.recv() => frame,
+            Some(frame) = bulk_rx.recv() => frame,
+            else => break,
+        };
+
+        // `write_all` sends the entire line to the client.
+        // `.as_bytes()` converts our String into raw bytes since TCP works
+        // with bytes not text.
+        if let Err(e) = writer.write_all(frame.to_line().as_bytes()).await {
+            println!("Error sending message: {}", e);
+            break;
+        }
+    }
+}
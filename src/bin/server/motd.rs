@@ -0,0 +1,92 @@
+// The "message of the day": a few lines from a text file (`--motd FILE`)
+// that everybody is shown when they connect, e.g. the house rules or news
+// about the server.
+//
+// Right after a client is added to the registry it's sent a welcome:
+//
+//   WELCOME <server> <version> <users>   who we are and how many people are on
+//   MOTD <line>                          one per line of the file
+//
+// The file is read when the server starts. Change it and a server operator
+// can type `/motd reload` to read it again - no restart needed. `/motd`
+// shows it again to anyone who missed it. A missing file simply means there's
+// no message of the day.
+
+use chatty_rusty::protocol::Frame;
+
+use crate::state::State;
+
+// The lines of the message of the day and the file they came from.
+#[derive(Default)]
+pub struct Motd {
+    path: Option<String>,
+    lines: Vec<String>,
+}
+
+impl Motd {
+    // Read the message of the day from `path`.
+    pub fn load(path: &str) -> Result<Motd, String> {
+        let mut motd = Motd {
+            path: Some(path.to_string()),
+            lines: Vec::new(),
+        };
+        motd.reload()?;
+        Ok(motd)
+    }
+
+    // Read the file again and return how many lines it has now. On a
+    // mistake we keep the lines we had.
+    pub fn reload(&mut self) -> Result<usize, String> {
+        let Some(path) = &self.path else {
+            return Ok(0);
+        };
+        self.lines = match std::fs::read_to_string(path) {
+            // `lines` also takes care of "\r\n" line endings.
+            Ok(text) => text.lines().map(str::to_string).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("can't read {}: {}", path, e)),
+        };
+        Ok(self.lines.len())
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+}
+
+// The welcome banner followed by the message of the day.
+pub fn welcome(state: &State) -> Vec<Frame> {
+    let mut frames = vec![Frame::Welcome {
+        server: state.name.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        users: state.clients.len(),
+    }];
+    frames.extend(state.motd.lines().iter().cloned().map(Frame::Motd));
+    frames
+}
+
+// `/motd` shows the message of the day again.
+// `/motd reload` reads the file again - only server operators may do that.
+pub fn command(state: &mut State, addr: &str, args: &str) -> Vec<Frame> {
+    match args.trim() {
+        "" if state.motd.lines().is_empty() => {
+            vec![Frame::Info("there's no message of the day".to_string())]
+        }
+        "" => state.motd.lines().iter().cloned().map(Frame::Motd).collect(),
+        "reload" if !state.clients.get(addr).is_some_and(|c| c.operator) => {
+            vec![Frame::Error("only operators can reload the message of the day".to_string())]
+        }
+        "reload" => match state.motd.reload() {
+            Ok(lines) => {
+                println!("{} reloaded the message of the day", addr);
+                vec![Frame::Info(format!(
+                    "message of the day reloaded: {} {}",
+                    lines,
+                    if lines == 1 { "line" } else { "lines" }
+                ))]
+            }
+            Err(e) => vec![Frame::Error(e)],
+        },
+        _ => vec![Frame::Error("usage: /motd [reload]".to_string())],
+    }
+}
@@ -0,0 +1,20 @@
+// The echo bot: repeats anything said after `!echo`. The smallest possible
+// plugin - handy for checking that plugins work, or as a starting point.
+
+use chatty_rusty::protocol::ChatMessage;
+
+use super::{Context, Plugin};
+
+pub struct Echo;
+
+impl Plugin for Echo {
+    fn nick(&self) -> &str {
+        "echobot"
+    }
+
+    fn on_message(&mut self, ctx: &mut Context, msg: &ChatMessage) {
+        if let Some(text) = msg.text.strip_prefix("!echo ") {
+            ctx.say(&msg.room, text);
+        }
+    }
+}
@@ -0,0 +1,33 @@
+// The help bot: `/help` lists every command the server understands,
+// including the ones other plugins added.
+
+use super::{Call, Context, Plugin};
+use crate::commands::HELP;
+
+pub struct Help;
+
+impl Plugin for Help {
+    fn nick(&self) -> &str {
+        "helpbot"
+    }
+
+    fn commands(&self) -> Vec<(String, String)> {
+        vec![("/help".to_string(), "- list the commands".to_string())]
+    }
+
+    fn on_command(&mut self, ctx: &mut Context, call: &Call) {
+        // Collect the lines first: `ctx.commands()` borrows `ctx`.
+        let mut lines: Vec<String> = HELP
+            .iter()
+            .map(|(command, description)| format!("{} {}", command, description))
+            .collect();
+        lines.extend(
+            ctx.commands()
+                .iter()
+                .map(|(command, description)| format!("{} {}", command, description)),
+        );
+        for line in lines {
+            ctx.tell(&call.nick, line);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// Bots that live inside the server ("plugins").
+//
+// A plugin is an automated participant - a help bot, a reminder bot... - that
+// doesn't need its own TCP connection. It implements the `Plugin` trait below
+// and the server calls it when something happens:
+// - `filter`: someone is about to post a message - the plugin may change it
+//   or refuse it before anybody sees it
+// - `on_message`: a chat message was posted in any room
+// - `on_join` / `on_part`: someone joined or left a room
+// - `on_command`: someone typed one of the slash commands the plugin added
+// - `on_tick`: once a second, for plugins that do things on a timer
+// - `configure`: at startup and on every `/reload`, with the server's options
+//
+// Plugins don't touch the server's state directly. They get a `Context` and
+// ask it to say something in a room or tell one person something; the server
+// does it once the plugin returns. Whatever a plugin wants to remember it
+// keeps in its own fields, because every method gets `&mut self`.
+//
+// The built-in plugins each live in their own file and are only compiled when
+// their cargo feature is on (they all are by default):
+//
+//   cargo run --bin server --no-default-features --features plugin-help
+//
+// To add your own, implement `Plugin` and add it to `builtin()` - or, without
+// rebuilding the server, write a script (see `scripts.rs`).
+
+// With every plugin feature turned off, most of this file is never used.
+// This stops the compiler from warning about it in that case.
+#![cfg_attr(
+    not(any(
+        feature = "plugin-echo",
+        feature = "plugin-help",
+        feature = "plugin-remind",
+        feature = "scripting"
+    )),
+    allow(dead_code)
+)]
+
+#[cfg(feature = "plugin-echo")]
+mod echo;
+#[cfg(feature = "plugin-help")]
+mod help;
+#[cfg(feature = "plugin-remind")]
+mod remind;
+#[cfg(feature = "scripting")]
+mod scripts;
+
+use std::time::Duration;
+
+use chatty_rusty::protocol::{ChatMessage, Frame};
+
+use crate::config::Config;
+use crate::state::{Db, State};
+
+// How often `on_tick` is called.
+const TICK: Duration = Duration::from_secs(1);
+
+// `Send` is needed because the plugins live in the shared state, which moves
+// between tasks. The methods have empty default bodies, so a plugin only
+// writes the ones it cares about.
+pub trait Plugin: Send {
+    // The nick the plugin talks under, e.g. "helpbot".
+    fn nick(&self) -> &str;
+
+    // The slash commands the plugin adds, each with a short description
+    // for `/help`, e.g. ("/remind", "<minutes> <text> - remind you later").
+    fn commands(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    // Called before a person's message is posted. Change `msg.text` to
+    // rewrite it, or return an error to refuse it - the sender is shown the
+    // error and nobody else sees anything.
+    fn filter(&mut self, _ctx: &mut Context, _msg: &mut ChatMessage) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn on_message(&mut self, _ctx: &mut Context, _msg: &ChatMessage) {}
+    fn on_join(&mut self, _ctx: &mut Context, _nick: &str, _room: &str) {}
+    fn on_part(&mut self, _ctx: &mut Context, _nick: &str, _room: &str) {}
+    fn on_command(&mut self, _ctx: &mut Context, _call: &Call) {}
+    fn on_tick(&mut self, _ctx: &mut Context) {}
+
+    // Pick up the plugin's settings from the server's options. Returns a
+    // short description of them for `/reload` to show, if it has any.
+    fn configure(&mut self, _config: &Config) -> Option<String> {
+        None
+    }
+}
+
+// Someone used one of a plugin's commands.
+// - `nick`: who typed it
+// - `room`: their active room, if they're in one
+// - `command`: the command itself, e.g. "/remind"
+// - `args`: everything after the command
+pub struct Call {
+    pub nick: String,
+    pub room: Option<String>,
+    pub command: String,
+    pub args: String,
+}
+
+// What a plugin may ask the server to do.
+enum Action {
+    Say { room: String, text: String },
+    Tell { nick: String, text: String },
+}
+
+// Handed to every plugin method. It collects the plugin's actions so the
+// server can carry them out after the plugin returns.
+pub struct Context {
+    actions: Vec<Action>,
+    commands: Vec<(String, String)>,
+}
+
+impl Context {
+    // Post a chat message in `room`, under the plugin's nick.
+    pub fn say(&mut self, room: &str, text: impl Into<String>) {
+        self.actions.push(Action::Say {
+            room: room.to_string(),
+            text: text.into(),
+        });
+    }
+
+    // Send a notice only `nick` sees.
+    pub fn tell(&mut self, nick: &str, text: impl Into<String>) {
+        self.actions.push(Action::Tell {
+            nick: nick.to_string(),
+            text: text.into(),
+        });
+    }
+
+    // Every command added by any plugin, with its description.
+    pub fn commands(&self) -> &[(String, String)] {
+        &self.commands
+    }
+}
+
+// The plugins compiled into this server.
+// Each `push` may or may not be compiled in, so clippy's advice to use
+// `vec![...]` instead doesn't apply, and neither does the `unused_mut`
+// warning we'd get with every feature off.
+#[allow(unused_mut, clippy::vec_init_then_push)]
+pub fn builtin() -> Vec<Box<dyn Plugin>> {
+    let mut plugins: Vec<Box<dyn Plugin>> = Vec::new();
+    #[cfg(feature = "plugin-echo")]
+    plugins.push(Box::new(echo::Echo));
+    #[cfg(feature = "plugin-help")]
+    plugins.push(Box::new(help::Help));
+    #[cfg(feature = "plugin-remind")]
+    plugins.push(Box::new(remind::Remind::new()));
+    #[cfg(feature = "scripting")]
+    plugins.push(Box::new(scripts::Scripts::new()));
+    plugins
+}
+
+// Give every plugin the server's options. Returns what they said about
+// their settings.
+pub fn configure(state: &mut State) -> Vec<String> {
+    let config = &state.config;
+    state
+        .plugins
+        .iter_mut()
+        .filter_map(|plugin| plugin.configure(config))
+        .collect()
+}
+
+// Is `nick` the nick of a plugin? People can't take those.
+pub fn is_plugin(state: &State, nick: &str) -> bool {
+    state.plugins.iter().any(|p| p.nick() == nick)
+}
+
+// Let every plugin check a message before it's posted. The first plugin to
+// refuse it wins, and the plugins after it aren't asked.
+pub fn filter(state: &mut State, msg: &mut ChatMessage) -> Result<(), String> {
+    let mut result = Ok(());
+    dispatch(state, |plugin, ctx| {
+        if result.is_ok() {
+            result = plugin.filter(ctx, msg);
+        }
+    });
+    result
+}
+
+// Tell every plugin about a message.
+pub fn message(state: &mut State, msg: &ChatMessage) {
+    dispatch(state, |plugin, ctx| plugin.on_message(ctx, msg));
+}
+
+// Tell every plugin someone joined a room.
+pub fn joined(state: &mut State, nick: &str, room: &str) {
+    dispatch(state, |plugin, ctx| plugin.on_join(ctx, nick, room));
+}
+
+// Tell every plugin someone left a room.
+pub fn left(state: &mut State, nick: &str, room: &str) {
+    dispatch(state, |plugin, ctx| plugin.on_part(ctx, nick, room));
+}
+
+// Run `command` if a plugin added it. Returns false if none did.
+pub fn command(state: &mut State, addr: &str, command: &str, args: &str) -> bool {
+    if !state
+        .plugins
+        .iter()
+        .any(|p| p.commands().iter().any(|(name, _)| name == command))
+    {
+        return false;
+    }
+    let call = Call {
+        nick: state.nick(addr),
+        room: state.clients.get(addr).and_then(|c| c.room.clone()),
+        command: command.to_string(),
+        args: args.trim().to_string(),
+    };
+    dispatch(state, |plugin, ctx| {
+        if plugin.commands().iter().any(|(name, _)| name == command) {
+            plugin.on_command(ctx, &call);
+        }
+    });
+    true
+}
+
+// Call `on_tick` on every plugin once a second, for as long as the server runs.
+pub async fn tick(db: Db) {
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        let mut state = db.lock().await;
+        dispatch(&mut state, |plugin, ctx| plugin.on_tick(ctx));
+    }
+}
+
+// Call `event` for every plugin, then carry out what each one asked for.
+//
+// The plugins are taken out of the state while this runs. That's what lets
+// us hand `state` to `post` while we're still going through the plugins -
+// and it means a plugin's own messages aren't passed to the plugins again,
+// so two bots can never end up answering each other forever.
+fn dispatch(state: &mut State, mut event: impl FnMut(&mut dyn Plugin, &mut Context)) {
+    let mut plugins = std::mem::take(&mut state.plugins);
+    let commands: Vec<_> = plugins.iter().flat_map(|p| p.commands()).collect();
+    for plugin in plugins.iter_mut() {
+        let mut ctx = Context {
+            actions: Vec::new(),
+            commands: commands.clone(),
+        };
+        event(plugin.as_mut(), &mut ctx);
+        for action in ctx.actions {
+            match action {
+                // A plugin has no connection, so its nick stands in for
+                // the address `post` normally gets.
+                Action::Say { room, text } => {
+                    state.post(plugin.nick(), &room, &text, None);
+                }
+                Action::Tell { nick, text } => {
+                    if let Some(addr) = state.find_nick(&nick) {
+                        state.send_to(&addr, Frame::Info(format!("[{}] {}", plugin.nick(), text)));
+                    }
+                }
+            }
+        }
+    }
+    state.plugins = plugins;
+}
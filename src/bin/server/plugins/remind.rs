@@ -0,0 +1,85 @@
+// The reminder bot: `/remind <minutes> <text>` says `<text>` back to you in
+// your active room once the time is up. Shows a plugin keeping state between
+// calls and using `on_tick`.
+
+use std::time::{Duration, Instant};
+
+use super::{Call, Context, Plugin};
+
+// No reminders further away than a week.
+const MAX_MINUTES: u64 = 7 * 24 * 60;
+
+struct Reminder {
+    due: Instant,
+    nick: String,
+    room: String,
+    text: String,
+}
+
+pub struct Remind {
+    reminders: Vec<Reminder>,
+}
+
+impl Remind {
+    pub fn new() -> Self {
+        Remind {
+            reminders: Vec::new(),
+        }
+    }
+}
+
+impl Plugin for Remind {
+    fn nick(&self) -> &str {
+        "remindbot"
+    }
+
+    fn commands(&self) -> Vec<(String, String)> {
+        vec![(
+            "/remind".to_string(),
+            "<minutes> <text> - remind you in your room later".to_string(),
+        )]
+    }
+
+    fn on_command(&mut self, ctx: &mut Context, call: &Call) {
+        let (minutes, text) = call.args.split_once(' ').unwrap_or((&call.args, ""));
+        let minutes = match minutes.parse::<u64>() {
+            Ok(minutes) if (1..=MAX_MINUTES).contains(&minutes) && !text.trim().is_empty() => {
+                minutes
+            }
+            _ => {
+                ctx.tell(
+                    &call.nick,
+                    format!("usage: {} <minutes, 1 to {}> <text>", call.command, MAX_MINUTES),
+                );
+                return;
+            }
+        };
+        let Some(room) = call.room.clone() else {
+            ctx.tell(&call.nick, "join a room first - that's where I'll remind you");
+            return;
+        };
+        self.reminders.push(Reminder {
+            due: Instant::now() + Duration::from_secs(minutes * 60),
+            nick: call.nick.clone(),
+            room,
+            text: text.trim().to_string(),
+        });
+        ctx.tell(&call.nick, format!("ok, I'll remind you in {} min", minutes));
+    }
+
+    fn on_tick(&mut self, ctx: &mut Context) {
+        let now = Instant::now();
+        // `retain` keeps the reminders for which the closure returns true,
+        // so the ones that are due get said and dropped in one go.
+        self.reminders.retain(|reminder| {
+            if reminder.due > now {
+                return true;
+            }
+            ctx.say(
+                &reminder.room,
+                format!("{}: reminder - {}", reminder.nick, reminder.text),
+            );
+            false
+        });
+    }
+}
@@ -0,0 +1,371 @@
+// Hooks written as scripts, so small things - auto-moderation, keyword
+// alerts, custom commands - can be added without rebuilding the server.
+//
+// Every `*.rhai` file in the scripts folder (`--scripts DIR`, or else the folder
+// in CHATTY_SCRIPTS, or else `scripts` next to where the server runs) is
+// loaded at startup. The folder is
+// checked again every second: changed files are reloaded, new ones loaded and
+// deleted ones dropped, all while the server keeps running. A script that
+// doesn't compile is reported and its previous version keeps running.
+//
+// Scripts are written in Rhai (https://rhai.rs), a small language that looks
+// a lot like Rust. A script defines whichever of these functions it needs:
+//
+//   fn on_message(msg)          before a message is posted. `msg` has
+//                               `sender`, `room` and `text`. Return a string
+//                               to replace the text, or `throw "reason"` to
+//                               refuse the message.
+//   fn on_join(nick, room)      someone joined a room
+//   fn on_part(nick, room)      someone left a room
+//   fn command_NAME(nick, room, args)
+//                               adds the slash command `/NAME`. A returned
+//                               string is shown to whoever typed it. `room`
+//                               is `()` if they aren't in one.
+//
+// and can call these:
+//
+//   say(room, text)                       post a message as "scriptbot"
+//   tell(nick, text)                      send one person a notice
+//   regex_match(text, pattern)            true if the regex matches
+//   regex_replace(text, pattern, with)    replace every match
+//   print(text)                           write to the server's output
+//
+// Scripts run while the server's state is locked, so each call is limited to
+// MAX_OPERATIONS steps - a script stuck in a loop is stopped, not the server.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use regex::Regex;
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+
+use chatty_rusty::protocol::ChatMessage;
+
+use super::{Call, Context, Plugin};
+use crate::config::Config;
+
+// The environment variable naming the scripts folder, and the folder we use
+// when it isn't set.
+const SCRIPTS_VAR: &str = "CHATTY_SCRIPTS";
+const DEFAULT_DIR: &str = "scripts";
+
+// How many steps one call into a script may take.
+const MAX_OPERATIONS: u64 = 100_000;
+
+// What a script asked for with `say` or `tell`. The functions we give Rhai
+// can't reach the `Context`, so they leave their requests here and we pass
+// them on after the script returns.
+enum Request {
+    Say { room: String, text: String },
+    Tell { nick: String, text: String },
+}
+
+// One loaded script file: its compiled form, and when the file was last
+// changed so we can tell when to reload it.
+struct Script {
+    ast: AST,
+    modified: SystemTime,
+}
+
+pub struct Scripts {
+    dir: PathBuf,
+    engine: Engine,
+    scripts: BTreeMap<PathBuf, Script>,
+    // Files that didn't compile, with the time they were changed, so we
+    // only report each broken version once.
+    broken: BTreeMap<PathBuf, SystemTime>,
+    // `Arc<Mutex<...>>` because the functions registered with Rhai keep
+    // their own handle to it.
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Scripts {
+    pub fn new() -> Self {
+        let dir = default_dir();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        // `move` gives each closure its own clone of the `Arc`.
+        let queue = requests.clone();
+        engine.register_fn("say", move |room: &str, text: &str| {
+            queue.lock().unwrap().push(Request::Say {
+                room: room.to_string(),
+                text: one_line(text),
+            });
+        });
+        let queue = requests.clone();
+        engine.register_fn("tell", move |nick: &str, text: &str| {
+            queue.lock().unwrap().push(Request::Tell {
+                nick: nick.to_string(),
+                text: one_line(text),
+            });
+        });
+        // A bad pattern becomes a script error instead of a crash.
+        engine.register_fn(
+            "regex_match",
+            |text: &str, pattern: &str| -> Result<bool, Box<EvalAltResult>> {
+                let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+                Ok(regex.is_match(text))
+            },
+        );
+        engine.register_fn(
+            "regex_replace",
+            |text: &str, pattern: &str, with: &str| -> Result<String, Box<EvalAltResult>> {
+                let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+                Ok(regex.replace_all(text, with).into_owned())
+            },
+        );
+
+        let mut scripts = Scripts {
+            dir: PathBuf::from(dir),
+            engine,
+            scripts: BTreeMap::new(),
+            broken: BTreeMap::new(),
+            requests,
+        };
+        scripts.reload();
+        scripts
+    }
+
+    // Bring the loaded scripts in line with the files in the folder.
+    fn reload(&mut self) {
+        // A missing folder simply means no scripts.
+        let files: Vec<(PathBuf, SystemTime)> = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "rhai"))
+                .filter_map(|path| {
+                    let modified = std::fs::metadata(&path).ok()?.modified().ok()?;
+                    Some((path, modified))
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        // Forget scripts whose file is gone.
+        self.scripts.retain(|path, _| {
+            let exists = files.iter().any(|(file, _)| file == path);
+            if !exists {
+                println!("Unloaded script {}", path.display());
+            }
+            exists
+        });
+        self.broken
+            .retain(|path, _| files.iter().any(|(file, _)| file == path));
+
+        for (path, modified) in files {
+            let loaded = self.scripts.get(&path).map(|script| script.modified);
+            if loaded == Some(modified) || self.broken.get(&path) == Some(&modified) {
+                continue;
+            }
+            match self.engine.compile_file(path.clone()) {
+                Ok(ast) => {
+                    println!("Loaded script {}", path.display());
+                    self.broken.remove(&path);
+                    self.scripts.insert(path, Script { ast, modified });
+                }
+                Err(e) => {
+                    // If an older version is loaded, it keeps running.
+                    println!("Script {} has an error: {}", path.display(), e);
+                    self.broken.insert(path, modified);
+                }
+            }
+        }
+    }
+
+    // Call `name` in every script that defines it with `arity` parameters.
+    // `handle` gets each script's result; it returns false to stop there.
+    fn call_all(
+        &self,
+        ctx: &mut Context,
+        name: &str,
+        arity: usize,
+        args: impl Fn() -> Vec<Dynamic>,
+        mut handle: impl FnMut(&Path, Result<Dynamic, Box<EvalAltResult>>) -> bool,
+    ) {
+        for (path, script) in &self.scripts {
+            if !defines(&script.ast, name, arity) {
+                continue;
+            }
+            let result =
+                self.engine
+                    .call_fn::<Dynamic>(&mut Scope::new(), &script.ast, name, args());
+            self.pass_on_requests(ctx);
+            if !handle(path, result) {
+                break;
+            }
+        }
+    }
+
+    // Hand whatever the scripts asked for with `say` and `tell` to the server.
+    fn pass_on_requests(&self, ctx: &mut Context) {
+        for request in self.requests.lock().unwrap().drain(..) {
+            match request {
+                Request::Say { room, text } => ctx.say(&room, text),
+                Request::Tell { nick, text } => ctx.tell(&nick, text),
+            }
+        }
+    }
+}
+
+impl Plugin for Scripts {
+    fn nick(&self) -> &str {
+        "scriptbot"
+    }
+
+    // Every `command_NAME(nick, room, args)` in a script becomes `/NAME`.
+    fn commands(&self) -> Vec<(String, String)> {
+        let mut commands = Vec::new();
+        for (path, script) in &self.scripts {
+            for function in script.ast.iter_functions() {
+                if let Some(name) = function.name.strip_prefix("command_")
+                    && function.params.len() == 3
+                {
+                    let file = path.file_name().unwrap_or_default().to_string_lossy();
+                    commands.push((format!("/{}", name), format!("- from script {}", file)));
+                }
+            }
+        }
+        commands
+    }
+
+    fn filter(&mut self, ctx: &mut Context, msg: &mut ChatMessage) -> Result<(), String> {
+        let mut refused = None;
+        // Each script sees the text as the scripts before it left it.
+        let text = Mutex::new(msg.text.clone());
+        let (sender, room) = (msg.sender.clone(), msg.room.clone());
+        let args = || {
+            let mut map = Map::new();
+            map.insert("sender".into(), sender.clone().into());
+            map.insert("room".into(), room.clone().into());
+            map.insert("text".into(), text.lock().unwrap().clone().into());
+            vec![Dynamic::from_map(map)]
+        };
+        self.call_all(ctx, "on_message", 1, args, |path, result| match result {
+            Ok(value) => {
+                if let Ok(new_text) = value.into_string() {
+                    *text.lock().unwrap() = one_line(&new_text);
+                }
+                true
+            }
+            Err(e) => match thrown(&e) {
+                Some(reason) => {
+                    refused = Some(reason);
+                    false
+                }
+                None => {
+                    // A bug in the script shouldn't stop people talking.
+                    println!("Script {} failed: {}", path.display(), e);
+                    true
+                }
+            },
+        });
+        match refused {
+            Some(reason) => Err(reason),
+            None => {
+                msg.text = text.into_inner().unwrap();
+                Ok(())
+            }
+        }
+    }
+
+    fn on_join(&mut self, ctx: &mut Context, nick: &str, room: &str) {
+        let args = || vec![nick.into(), room.into()];
+        self.call_all(ctx, "on_join", 2, args, report_errors);
+    }
+
+    fn on_part(&mut self, ctx: &mut Context, nick: &str, room: &str) {
+        let args = || vec![nick.into(), room.into()];
+        self.call_all(ctx, "on_part", 2, args, report_errors);
+    }
+
+    fn on_command(&mut self, ctx: &mut Context, call: &Call) {
+        let name = format!("command_{}", call.command.trim_start_matches('/'));
+        let args = || {
+            let room = match &call.room {
+                Some(room) => room.into(),
+                None => Dynamic::UNIT,
+            };
+            vec![call.nick.as_str().into(), room, call.args.as_str().into()]
+        };
+        let mut answers = Vec::new();
+        self.call_all(ctx, &name, 3, args, |path, result| {
+            match result {
+                Ok(value) => {
+                    if let Ok(answer) = value.into_string() {
+                        answers.push(answer);
+                    }
+                }
+                Err(e) => answers.push(
+                    thrown(&e).unwrap_or_else(|| format!("script {} failed", path.display())),
+                ),
+            }
+            true
+        });
+        for answer in answers {
+            ctx.tell(&call.nick, one_line(&answer));
+        }
+    }
+
+    // Once a second: pick up script changes.
+    fn on_tick(&mut self, _ctx: &mut Context) {
+        self.reload();
+    }
+
+    // `--scripts` may name another folder. Moving to it unloads the scripts
+    // of the old folder and loads the new folder's.
+    fn configure(&mut self, config: &Config) -> Option<String> {
+        let dir = PathBuf::from(config.scripts.clone().unwrap_or_else(default_dir));
+        if dir != self.dir {
+            println!("Scripts now come from {}", dir.display());
+            self.dir = dir;
+            self.reload();
+        }
+        Some(format!(
+            "scripts: {} from {}",
+            self.scripts.len(),
+            self.dir.display()
+        ))
+    }
+}
+
+// The scripts folder when `--scripts` doesn't name one.
+fn default_dir() -> String {
+    std::env::var(SCRIPTS_VAR).unwrap_or_else(|_| DEFAULT_DIR.to_string())
+}
+
+// Does the script define a function `name` taking `arity` parameters?
+fn defines(ast: &AST, name: &str, arity: usize) -> bool {
+    ast.iter_functions()
+        .any(|f| f.name == name && f.params.len() == arity)
+}
+
+// The `handle` for hooks that don't return anything useful.
+fn report_errors(path: &Path, result: Result<Dynamic, Box<EvalAltResult>>) -> bool {
+    if let Err(e) = result {
+        println!("Script {} failed: {}", path.display(), e);
+    }
+    true
+}
+
+// If the script stopped because of `throw "..."`, the thrown text.
+// Any other error (a typo, too many operations...) gives None.
+fn thrown(error: &EvalAltResult) -> Option<String> {
+    match error {
+        EvalAltResult::ErrorRuntime(value, _) => Some(value.to_string()),
+        // A throw inside a function the hook called comes wrapped up.
+        EvalAltResult::ErrorInFunctionCall(_, _, inner, _) => thrown(inner),
+        _ => None,
+    }
+}
+
+// Our protocol is one line per message, so text from a script can't
+// contain line breaks.
+fn one_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
@@ -0,0 +1,230 @@
+// Changing the server's settings without restarting it.
+//
+// Send the server a SIGHUP (`kill -HUP <pid>`), or type `/reload` as a server
+// operator, and it reads its options again - the `--config` file and the
+// command line - and applies everything it can while it keeps running:
+// - the message of the day (`--motd`)
+// - room modes, owners and bans, read back from `--rooms` - handy after
+//   editing the file by hand. Copies of the server on the same message bus
+//   get them too (see `rooms.rs`).
+// - the webhooks (`--webhooks`)
+// - the HTTP tokens and their rate limits (`--http-tokens`)
+// - the plugins' settings, e.g. the scripts folder (`--scripts`)
+// - the limits: `--history-limit` and `--max-file-size`
+//
+// Anything about which sockets are open or who we're linked with - the
+// listen addresses, the server's name, peers and the bus - can only change
+// with a restart. Those keep their old values, and the reload says which of
+// them were changed in the file.
+//
+// A reload is all or nothing: every file is read first, and if any of them
+// has a mistake, nothing is changed and the mistake is reported. Reading
+// happens without holding the lock on the state, so the chat carries on
+// while we wait for the disk - see `reload`.
+
+use chatty_rusty::protocol::Frame;
+
+use crate::config::Config;
+use crate::inbound;
+use crate::motd::Motd;
+use crate::plugins;
+use crate::rooms::Rooms;
+use crate::state::{Db, State};
+use crate::webhooks::Webhooks;
+
+// Reload whenever the server gets a SIGHUP, for as long as it runs. Signals
+// only exist on Unix - elsewhere there's just `/reload`.
+#[cfg(unix)]
+pub async fn on_hangup(db: Db) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            println!("Can't listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        println!("SIGHUP: reloading the configuration");
+        for line in report(reload(&db).await) {
+            println!("{}", line);
+        }
+    }
+}
+
+// `/reload`: the same as a SIGHUP, for server operators, who are shown
+// what happened. Unlike other commands, it's given `db` rather than the
+// state, and takes the lock only when it needs it.
+pub async fn command(db: &Db, addr: &str) -> Vec<Frame> {
+    if !db.lock().await.clients.get(addr).is_some_and(|c| c.operator) {
+        return vec![Frame::Error("only operators can reload the configuration".to_string())];
+    }
+    println!("{} is reloading the configuration", addr);
+    let result = reload(db).await;
+    let failed = result.is_err();
+    report(result)
+        .into_iter()
+        .map(|line| {
+            println!("{}", line);
+            if failed {
+                Frame::Error(line)
+            } else {
+                Frame::Info(line)
+            }
+        })
+        .collect()
+}
+
+// Turn the outcome of `reload` into lines to show.
+fn report(result: Result<Vec<String>, String>) -> Vec<String> {
+    match result {
+        Ok(lines) => lines,
+        Err(e) => vec![format!("reload failed, nothing changed: {}", e)],
+    }
+}
+
+// What a reload read, ready to be used.
+// - `restart`: the settings that changed but need a restart
+// - `lines`: what the server will be running with, to report
+struct Loaded {
+    config: Config,
+    motd: Motd,
+    rooms: Rooms,
+    webhooks: Webhooks,
+    restart: Vec<&'static str>,
+    lines: Vec<String>,
+}
+
+// Read the options and their files again and apply what can be applied.
+// Returns what the server is now running with, one line per setting.
+async fn reload(db: &Db) -> Result<Vec<String>, String> {
+    // A copy of what we need, so the lock is let go straight away. The
+    // tokens are shared with the HTTP listener (an `Arc`), so the copy is
+    // the same tokens.
+    let (old, tokens) = {
+        let state = db.lock().await;
+        (state.config.clone(), state.http_tokens.clone())
+    };
+    // `spawn_blocking` runs the reading on one of tokio's threads for work
+    // that waits, instead of holding up the ones that run our tasks. The
+    // first `?` is for the thread panicking, the second for a mistake in a
+    // file.
+    let loaded = tokio::task::spawn_blocking(move || read(&old, tokens.as_ref()))
+        .await
+        .map_err(|e| e.to_string())??;
+    let mut state = db.lock().await;
+    Ok(apply(&mut state, loaded))
+}
+
+// Read everything a reload needs, without touching the state.
+fn read(old: &Config, tokens: Option<&inbound::Tokens>) -> Result<Loaded, String> {
+    let mut config = Config::from_args()?;
+
+    // Read every file before changing anything.
+    let motd = Motd::load(&config.motd)?;
+    let rooms = Rooms::load(&config.rooms)?;
+    let webhooks = match &config.webhooks {
+        Some(path) => Webhooks::load(path)?,
+        None => Webhooks::default(),
+    };
+
+    // What only a restart can change keeps its old value.
+    let mut restart = Vec::new();
+    if config.listen != old.listen {
+        restart.push("listen");
+    }
+    if config.name != old.name {
+        restart.push("name");
+    }
+    if config.link_listen != old.link_listen {
+        restart.push("link-listen");
+    }
+    if config.peers != old.peers {
+        restart.push("peer");
+    }
+    if config.bus != old.bus {
+        restart.push("bus");
+    }
+    if config.irc_listen != old.irc_listen {
+        restart.push("irc-listen");
+    }
+    if config.http_listen != old.http_listen {
+        restart.push("http-listen");
+    }
+    config.listen = old.listen.clone();
+    config.name = old.name.clone();
+    config.link_listen = old.link_listen.clone();
+    config.peers = old.peers.clone();
+    config.bus = old.bus.clone();
+    config.irc_listen = old.irc_listen.clone();
+    config.http_listen = old.http_listen.clone();
+
+    // The tokens only matter while HTTP is on, and turning it on or off
+    // needs a restart anyway.
+    let tokens = match (tokens, &config.http_tokens) {
+        (Some(tokens), Some(path)) => Some(inbound::reload_tokens(tokens, path)?),
+        _ => {
+            config.http_tokens = old.http_tokens.clone();
+            None
+        }
+    };
+
+    // Everything was read fine: use it.
+    let mut lines = vec![match &config.file {
+        Some(path) => format!("reloaded {}", path),
+        None => "reloaded the command line options (there's no --config file)".to_string(),
+    }];
+    lines.push(format!(
+        "message of the day: {} line(s) from {}",
+        motd.lines().len(),
+        config.motd
+    ));
+    lines.push(format!(
+        "rooms: {} with settings, from {}",
+        rooms.len(),
+        config.rooms
+    ));
+    lines.push(format!("webhooks: {}", webhooks.len()));
+    if let Some(tokens) = tokens {
+        lines.push(format!("HTTP tokens: {}", tokens));
+    }
+    lines.push(format!(
+        "limits: {} messages of history, files up to {} bytes",
+        config.history_limit, config.max_file_size
+    ));
+    Ok(Loaded {
+        config,
+        motd,
+        rooms,
+        webhooks,
+        restart,
+        lines,
+    })
+}
+
+// Use what `read` read. Returns the lines to report.
+fn apply(state: &mut State, loaded: Loaded) -> Vec<String> {
+    let Loaded {
+        config,
+        motd,
+        rooms,
+        webhooks,
+        restart,
+        mut lines,
+    } = loaded;
+    state.motd = motd;
+    state.rooms.reread(rooms);
+    // The file may give roles to nicks nobody is using (see `rooms.rs`).
+    state.forget_free_nicks();
+    state.webhooks = webhooks;
+    state.config = config;
+    lines.extend(plugins::configure(state));
+    if !restart.is_empty() {
+        lines.push(format!(
+            "changed, but only applied after a restart: {}",
+            restart.join(", ")
+        ));
+    }
+    lines
+}
@@ -0,0 +1,845 @@
+// Who runs a room, and who may come in and talk.
+//
+// Whoever joins a new, empty room becomes its owner. The owner and the room
+// operators they pick can set "modes" on it with `/mode`, like on IRC:
+// - `+i`: invite only - you can only join after an `/invite`
+// - `+k <password>`: you need the password: `/join #room <password>`
+// - `+m`: moderated - only operators and voiced people (`+v`) can talk
+// - `+t`: only operators can change the topic
+// - `+o <nick>` / `+v <nick>`: make someone an operator / give them a voice
+// - `+b <nick>`: ban someone - they're thrown out and can't come back
+// A `-` instead of the `+` takes it away again. `/topic` shows or sets the
+// topic, and `/invite <nick> <#room>` lets someone in.
+//
+// Server operators (`/oper`) may do all of this in every room, and nothing
+// keeps them out. #general has no owner, so only they can change it.
+//
+// Roles belong to nicks, and nicks aren't accounts - anyone can pick a free
+// one. So roles only outlive a connection if the nick is registered:
+// `/register <password>` ties the nick to a password, and from then on only
+// `/nick <name> <password>` (or PASS on IRC) can take it. The roles of a nick
+// that isn't registered are dropped as soon as nobody is using it any more,
+// and they can't be given to such a nick while it's free. Otherwise whoever
+// picked the owner's nick while the owner was away would own the room.
+//
+// Bans are only by nick, too. A banned person can come back under another
+// nick - bans keep out someone who plays by the rules, not someone set on
+// getting in. Make the room `+i` to keep everyone out but the people
+// you invite.
+//
+// Everything - the rooms under "rooms", the registered nicks under "nicks" -
+// is saved to the rooms file (`--rooms FILE`) whenever it changes, and read
+// back when the server starts. Writing a file can take a while, so it doesn't happen while
+// we hold the lock on the state: we turn the settings into text, and one of
+// tokio's threads for blocking work writes it (see `Rooms::save`). If that
+// fails, the change still counts until the server stops - the log says so.
+// Linked servers don't know our modes, so nothing that happens in an
+// invite-only or password room is relayed to them, and nothing they relay
+// about it is taken (see `restricted` in `federation.rs`).
+//
+// Copies of the server on one message bus (see `bus.rs`) are one server, so
+// they share all of this: every change is published on the bus as a
+// `Shared`, and the other copies apply it and save it to their own rooms
+// file. A copy joining the bus takes the settings of the copies already on
+// it.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde_json::{Map, Value, json};
+
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use sha2::Sha256;
+use tokio::sync::MutexGuard;
+
+use chatty_rusty::protocol::{Frame, base64_decode, base64_encode};
+
+use crate::bus::Bus;
+use crate::commands::{bad_room, same_secret, valid_room};
+use crate::federation;
+use crate::state::{DEFAULT_ROOM, Db, State};
+
+// The settings of one room.
+// - `owner`: who created it. Can't be taken off the operators.
+// - `invited`: nicks invited in. An invitation is used up by joining.
+// - `banned`: nicks that may not come in, invited or not.
+// `PartialEq` lets `Rooms::update` tell which rooms it changed.
+#[derive(Default, Clone, PartialEq)]
+pub struct Room {
+    pub topic: String,
+    pub invite_only: bool,
+    pub password: Option<String>,
+    pub moderated: bool,
+    pub topic_locked: bool,
+    pub owner: Option<String>,
+    pub operators: BTreeSet<String>,
+    pub voiced: BTreeSet<String>,
+    pub invited: BTreeSet<String>,
+    pub banned: BTreeSet<String>,
+}
+
+impl Room {
+    // Is `nick` one of the people running the room?
+    fn is_operator(&self, nick: &str) -> bool {
+        self.owner.as_deref() == Some(nick) || self.operators.contains(nick)
+    }
+
+    // The modes as IRC writes them, e.g. "+imt", or "" for none.
+    // The password itself is only shown to operators.
+    pub fn modes(&self) -> String {
+        let mut modes = String::new();
+        for (on, letter) in [
+            (self.invite_only, 'i'),
+            (self.password.is_some(), 'k'),
+            (self.moderated, 'm'),
+            (self.topic_locked, 't'),
+        ] {
+            if on {
+                modes.push(letter);
+            }
+        }
+        if modes.is_empty() {
+            modes
+        } else {
+            format!("+{}", modes)
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "topic": self.topic,
+            "invite_only": self.invite_only,
+            "password": self.password,
+            "moderated": self.moderated,
+            "topic_locked": self.topic_locked,
+            "owner": self.owner,
+            "operators": self.operators,
+            "voiced": self.voiced,
+            "invited": self.invited,
+            "banned": self.banned,
+        })
+    }
+
+    // The opposite of `to_json`. Anything missing gets its default, so a
+    // file written by hand only needs what it changes.
+    pub fn from_json(value: &Value) -> Room {
+        let text = |key: &str| value[key].as_str().map(str::to_string);
+        let flag = |key: &str| value[key].as_bool().unwrap_or(false);
+        let nicks = |key: &str| -> BTreeSet<String> {
+            value[key]
+                .as_array()
+                .map(|nicks| {
+                    nicks
+                        .iter()
+                        .filter_map(|n| n.as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        Room {
+            topic: text("topic").unwrap_or_default(),
+            invite_only: flag("invite_only"),
+            password: text("password"),
+            moderated: flag("moderated"),
+            topic_locked: flag("topic_locked"),
+            owner: text("owner"),
+            operators: nicks("operators"),
+            voiced: nicks("voiced"),
+            invited: nicks("invited"),
+            banned: nicks("banned"),
+        }
+    }
+}
+
+// The keys the rooms file keeps the rooms and the registered nicks under:
+// `{"rooms": {"#room": {...}}, "nicks": {"alice": "<hash>"}}`.
+const ROOMS: &str = "rooms";
+const NICKS: &str = "nicks";
+
+// How many times a nick password is hashed before it's stored (PBKDF2). The
+// more rounds, the longer it takes to try a guess against a stolen rooms
+// file - and the longer `/register` and `/nick` take. That's too long to hold
+// the lock on the state for, or anyone could stall the whole server by
+// guessing passwords, so hashing happens on a thread for blocking work while
+// the chat carries on (see `check_nick_password` and `hash_in_background`).
+const ROUNDS: u32 = 20_000;
+
+// Every save gets the next number from SAVES. WRITTEN holds the number of
+// the newest one on disk, and is locked while writing, so only one save
+// writes at a time and an older one never replaces a newer one.
+static SAVES: AtomicU64 = AtomicU64::new(0);
+static WRITTEN: Mutex<u64> = Mutex::new(0);
+
+// A change the other copies of the server on the message bus need to hear
+// about: a room's new settings, or a nick registered with a password hash.
+pub enum Shared {
+    Room(String, Room),
+    Nick(String, String),
+}
+
+// The settings of every room, the registered nicks with their hashed
+// passwords, the file they're saved in (None: don't save) and where changes
+// are shared with other copies of the server (None: there are none).
+#[derive(Default)]
+pub struct Rooms {
+    rooms: BTreeMap<String, Room>,
+    nicks: BTreeMap<String, String>,
+    path: Option<String>,
+    share: Option<Box<dyn Bus<Shared>>>,
+}
+
+impl Rooms {
+    // Read the rooms file. It not being there yet is fine - nothing has
+    // been saved so far.
+    pub fn load(path: &str) -> Result<Rooms, String> {
+        let mut rooms = Rooms {
+            rooms: BTreeMap::new(),
+            nicks: BTreeMap::new(),
+            path: Some(path.to_string()),
+            share: None,
+        };
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(rooms),
+            Err(e) => return Err(format!("can't read {}: {}", path, e)),
+        };
+        let value: Value = serde_json::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+        let Some(map) = value.as_object() else {
+            return Err(format!("{}: expected an object", path));
+        };
+        // Files from older versions have the rooms at the top, next to
+        // "nicks". Anything that isn't a room name is skipped.
+        let saved = match map.get(ROOMS) {
+            Some(saved) => saved
+                .as_object()
+                .ok_or_else(|| format!("{}: expected an object of rooms", path))?,
+            None => map,
+        };
+        for (name, room) in saved {
+            if valid_room(name) {
+                rooms.rooms.insert(name.clone(), Room::from_json(room));
+            }
+        }
+        for (nick, hash) in map.get(NICKS).and_then(Value::as_object).into_iter().flatten() {
+            if let Some(hash) = hash.as_str() {
+                rooms.nicks.insert(nick.clone(), hash.to_string());
+            }
+        }
+        Ok(rooms)
+    }
+
+    // Change the rooms, share the rooms that changed and save them. Returns
+    // whatever `change` returned.
+    fn update<T>(&mut self, change: impl FnOnce(&mut BTreeMap<String, Room>) -> T) -> T {
+        let before = self.share.is_some().then(|| self.rooms.clone());
+        let result = change(&mut self.rooms);
+        if let (Some(share), Some(before)) = (&self.share, before) {
+            for (name, settings) in &self.rooms {
+                if before.get(name) != Some(settings) {
+                    share.publish(Shared::Room(name.clone(), settings.clone()));
+                }
+            }
+        }
+        self.save();
+        result
+    }
+
+    // From now on, publish every change on `share` (see `bus.rs`).
+    pub fn share_with(&mut self, share: Box<dyn Bus<Shared>>) {
+        self.share = Some(share);
+    }
+
+    // Use `rooms`, just read from the rooms file again, instead: keep
+    // sharing where we did, and tell the other copies what the file says.
+    pub fn reread(&mut self, rooms: Rooms) {
+        let share = self.share.take();
+        *self = Rooms { share, ..rooms };
+        self.share_everything();
+    }
+
+    // Publish every room and registered nick, for a copy of the server that
+    // just joined the message bus.
+    pub fn share_everything(&self) {
+        let Some(share) = &self.share else {
+            return;
+        };
+        for (name, settings) in &self.rooms {
+            share.publish(Shared::Room(name.clone(), settings.clone()));
+        }
+        for (nick, hash) in &self.nicks {
+            share.publish(Shared::Nick(nick.clone(), hash.clone()));
+        }
+    }
+
+    // Apply a change another copy of the server shared, without sharing it
+    // again - every copy heard it already.
+    pub fn apply(&mut self, change: Shared) {
+        match change {
+            Shared::Room(name, settings) => {
+                self.rooms.insert(name, settings);
+            }
+            Shared::Nick(nick, hash) => {
+                self.nicks.insert(nick, hash);
+            }
+        }
+        self.save();
+    }
+
+    // Save everything to the rooms file in the background. The text is made
+    // now, so later changes can't sneak into it; `spawn_blocking` runs the
+    // writing on a thread where waiting for the disk doesn't hold anyone up.
+    fn save(&self) {
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+        let text = to_text(&self.rooms, &self.nicks);
+        let number = SAVES.fetch_add(1, Ordering::SeqCst) + 1;
+        tokio::task::spawn_blocking(move || save_as(&path, &text, number));
+    }
+
+    // Save everything right now and wait until it's done - for when the
+    // server is about to stop, and a save in the background might never
+    // happen.
+    pub fn save_now(&self) {
+        if let Some(path) = &self.path {
+            let number = SAVES.fetch_add(1, Ordering::SeqCst) + 1;
+            save_as(path, &to_text(&self.rooms, &self.nicks), number);
+        }
+    }
+
+    // Is `nick` registered with a password?
+    pub fn is_registered(&self, nick: &str) -> bool {
+        self.nicks.contains_key(nick)
+    }
+
+    // Register `nick` with the password `hash` (from `hash_in_background`),
+    // or change its password. The caller has checked that it's the nick's
+    // owner asking.
+    pub fn register(&mut self, nick: &str, hash: String) {
+        self.nicks.insert(nick.to_string(), hash.clone());
+        if let Some(share) = &self.share {
+            share.publish(Shared::Nick(nick.to_string(), hash));
+        }
+        self.save();
+    }
+
+    // Nobody is using `nick` any more. If it isn't registered, its roles go:
+    // the next person to pick it mustn't get them.
+    pub fn released(&mut self, nick: &str) {
+        self.forget_free(|other| other == nick)
+    }
+
+    // Drop the roles of every nick that isn't registered and isn't in
+    // `online` - after starting up or reading the rooms file again, when
+    // nicks may have been given up while we weren't looking.
+    pub fn keep_online(&mut self, online: &BTreeSet<String>) {
+        self.forget_free(|nick| !online.contains(nick))
+    }
+
+    // Take the owner, operator, voice and invitation of every unregistered
+    // nick for which `gone` says yes. Bans stay: they keep the nick out no
+    // matter who picks it.
+    fn forget_free(&mut self, gone: impl Fn(&str) -> bool) {
+        let free = |nick: &str| gone(nick) && !self.nicks.contains_key(nick);
+        let has_free = |settings: &Room| {
+            settings.owner.as_deref().is_some_and(free)
+                || [&settings.operators, &settings.voiced, &settings.invited]
+                    .iter()
+                    .any(|nicks| nicks.iter().any(|nick| free(nick)))
+        };
+        if !self.rooms.values().any(has_free) {
+            return;
+        }
+        let free: BTreeSet<String> = self
+            .rooms
+            .values()
+            .flat_map(|settings| {
+                settings
+                    .owner
+                    .iter()
+                    .chain(&settings.operators)
+                    .chain(&settings.voiced)
+                    .chain(&settings.invited)
+            })
+            .filter(|nick| free(nick))
+            .cloned()
+            .collect();
+        self.update(|rooms| {
+            for settings in rooms.values_mut() {
+                if settings.owner.as_ref().is_some_and(|owner| free.contains(owner)) {
+                    settings.owner = None;
+                }
+                for nicks in [
+                    &mut settings.operators,
+                    &mut settings.voiced,
+                    &mut settings.invited,
+                ] {
+                    nicks.retain(|nick| !free.contains(nick));
+                }
+            }
+        })
+    }
+
+    // How many rooms have settings.
+    pub fn len(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn get(&self, room: &str) -> Option<&Room> {
+        self.rooms.get(room)
+    }
+
+    // `nick` joined `room`. If it's a room we've never seen, they own it now -
+    // `empty` says whether anybody was already in it. An invitation is used up.
+    pub fn joined(&mut self, room: &str, nick: &str, empty: bool) {
+        let changes = match self.rooms.get(room) {
+            Some(settings) => settings.invited.contains(nick),
+            None => empty && room != DEFAULT_ROOM,
+        };
+        if !changes {
+            return;
+        }
+        self.update(|rooms| match rooms.get_mut(room) {
+            Some(settings) => {
+                settings.invited.remove(nick);
+            }
+            None => {
+                let settings = Room {
+                    owner: Some(nick.to_string()),
+                    ..Room::default()
+                };
+                rooms.insert(room.to_string(), settings);
+            }
+        })
+    }
+
+    // May `nick` join `room` with this password? `operator` means a server
+    // operator, who may always come in.
+    pub fn check_join(
+        &self,
+        room: &str,
+        nick: &str,
+        password: &str,
+        operator: bool,
+    ) -> Result<(), String> {
+        let Some(settings) = self.rooms.get(room) else {
+            return Ok(());
+        };
+        if operator {
+            return Ok(());
+        }
+        if settings.banned.contains(nick) {
+            return Err(format!("you're banned from {}", room));
+        }
+        // Operators and invited people don't need the password either.
+        if settings.is_operator(nick) || settings.invited.contains(nick) {
+            return Ok(());
+        }
+        if settings.invite_only {
+            return Err(format!(
+                "{} is invite only - ask one of its operators for an /invite",
+                room
+            ));
+        }
+        match &settings.password {
+            Some(wanted) if !same_secret(password, wanted) => Err(format!(
+                "{} needs a password: /join {} <password>",
+                room, room
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // May `nick` talk in `room`?
+    pub fn may_speak(&self, room: &str, nick: &str, operator: bool) -> bool {
+        match self.rooms.get(room) {
+            Some(settings) if settings.moderated => {
+                operator || settings.is_operator(nick) || settings.voiced.contains(nick)
+            }
+            _ => true,
+        }
+    }
+
+    // A nick that was only an address (before `/nick`) gets a real name: it
+    // keeps the rooms it created. Other nick changes keep nothing: the roles
+    // stay with the old nick if it's registered, for when its owner comes
+    // back, and go otherwise (see `released`).
+    pub fn renamed(&mut self, old: &str, new: &str) {
+        let owns = |settings: &Room| settings.owner.as_deref() == Some(old);
+        if !self.rooms.values().any(owns) {
+            return;
+        }
+        self.update(|rooms| {
+            for settings in rooms.values_mut().filter(|settings| owns(settings)) {
+                settings.owner = Some(new.to_string());
+            }
+        })
+    }
+
+    // The topic to show someone joining `room`, if it has one.
+    pub fn topic(&self, room: &str) -> Option<&str> {
+        self.rooms
+            .get(room)
+            .map(|settings| settings.topic.as_str())
+            .filter(|topic| !topic.is_empty())
+    }
+}
+
+// The rooms file's text for `rooms` and `nicks`.
+fn to_text(rooms: &BTreeMap<String, Room>, nicks: &BTreeMap<String, String>) -> String {
+    let rooms: Map<String, Value> = rooms
+        .iter()
+        .map(|(name, room)| (name.clone(), room.to_json()))
+        .collect();
+    let file = json!({ ROOMS: rooms, NICKS: nicks });
+    serde_json::to_string_pretty(&file).unwrap_or_default() + "\n"
+}
+
+// Write save number `number` to the rooms file, unless a newer one is
+// already there. This waits for the disk, so it must not run on one of the
+// threads that run our tasks.
+fn save_as(path: &str, text: &str, number: u64) {
+    // `into_inner` on a poisoned lock: a save that panicked doesn't stop
+    // the ones after it.
+    let mut written = WRITTEN.lock().unwrap_or_else(|e| e.into_inner());
+    if *written > number {
+        return;
+    }
+    match write(path, text) {
+        Ok(()) => *written = number,
+        Err(e) => println!("{} - the change only lasts until the server stops", e),
+    }
+}
+
+// Write `text` to the rooms file. We write a new file next to it, make sure
+// it's really on the disk (`sync_all`), and then rename it over the old one:
+// a rename happens all at once, so a crash halfway through can't leave half
+// a file behind - it's either the old rooms or the new ones. The file holds
+// room passwords and hashed nick passwords, so only we may read it.
+fn write(path: &str, text: &str) -> Result<(), String> {
+    let temporary = format!("{}.new", path);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // On Unix, readable by us alone (`chmod 600`).
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let written = options.open(&temporary).and_then(|mut file| {
+        file.write_all(text.as_bytes())?;
+        file.sync_all()
+    });
+    written
+        .and_then(|_| std::fs::rename(&temporary, path))
+        .map_err(|e| format!("can't save {}: {}", path, e))
+}
+
+// Hash a nick password to store it: "pbkdf2-sha256$<rounds>$<salt>$<hash>".
+// The salt is random, so two people with the same password get different
+// hashes, and a list of hashes made in advance for common passwords is no
+// use.
+fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let mut hash = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, ROUNDS, &mut hash);
+    format!(
+        "pbkdf2-sha256${}${}${}",
+        ROUNDS,
+        base64_encode(&salt),
+        base64_encode(&hash)
+    )
+}
+
+// Does `password` give the `stored` hash? Anything we can't read matches
+// nothing.
+fn check_password(password: &str, stored: &str) -> bool {
+    let parts: Vec<&str> = stored.split('$').collect();
+    let ["pbkdf2-sha256", rounds, salt, wanted] = parts.as_slice() else {
+        return false;
+    };
+    let (Ok(rounds), Some(salt), Some(wanted)) =
+        (rounds.parse(), base64_decode(salt), base64_decode(wanted))
+    else {
+        return false;
+    };
+    let mut hash = vec![0u8; wanted.len()];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, rounds, &mut hash);
+    // Compare every byte, so how long it takes says nothing about how much
+    // of the hash was right.
+    hash.iter().zip(&wanted).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0 && !wanted.is_empty()
+}
+
+// May someone take `nick` with `password`? Any password will do for a nick
+// that isn't registered. `state` is the locked state; for a registered nick
+// we copy its hash, unlock, check the password on a thread for blocking work
+// and lock again. We return the state locked again with the answer. While it
+// was unlocked, someone may have taken the nick, so the caller checks that
+// again - a new password for it makes the answer no.
+pub async fn check_nick_password<'a>(
+    db: &'a Db,
+    state: MutexGuard<'a, State>,
+    nick: &str,
+    password: &str,
+) -> (MutexGuard<'a, State>, bool) {
+    let Some(stored) = state.rooms.nicks.get(nick).cloned() else {
+        return (state, true);
+    };
+    drop(state);
+    let (password, hash) = (password.to_string(), stored.clone());
+    // A panic while checking counts as a wrong password.
+    let right = tokio::task::spawn_blocking(move || check_password(&password, &hash))
+        .await
+        .unwrap_or(false);
+    let state = db.lock().await;
+    let unchanged = state.rooms.nicks.get(nick) == Some(&stored);
+    (state, right && unchanged)
+}
+
+// Hash a new nick password for `/register`, on a thread for blocking work
+// so we don't hold anyone up. None if that thread panicked.
+pub async fn hash_in_background(password: &str) -> Option<String> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || hash_password(&password))
+        .await
+        .ok()
+}
+
+// Is the client at `addr` allowed to run `room`? Returns their nick if so.
+fn operator_of(state: &State, addr: &str, room: &str) -> Result<String, String> {
+    let client = state.clients.get(addr).ok_or("you're gone")?;
+    let runs_it = state
+        .rooms
+        .get(room)
+        .is_some_and(|settings| settings.is_operator(&client.nick));
+    if client.operator || runs_it {
+        Ok(client.nick.clone())
+    } else {
+        Err(format!("you're not an operator of {}", room))
+    }
+}
+
+// `/mode <#room>` shows a room's modes and who runs it.
+// `/mode <#room> <+|-><i|k|m|t|o|v|b> [password|nick]` changes one of them.
+pub fn mode(state: &mut State, addr: &str, args: &str) -> Vec<Frame> {
+    const USAGE: &str = "usage: /mode <#room> [+|-][i|k|m|t|o|v|b] [password|nick]";
+    let mut words = args.split_whitespace();
+    let (Some(room), change, value) = (words.next(), words.next(), words.next()) else {
+        return vec![Frame::Error(USAGE.to_string())];
+    };
+    if !valid_room(room) {
+        return vec![bad_room()];
+    }
+    let Some(change) = change else {
+        return vec![Frame::Info(describe(state, addr, room))];
+    };
+    let nick = match operator_of(state, addr, room) {
+        Ok(nick) => nick,
+        Err(e) => return vec![Frame::Error(e)],
+    };
+    let (on, letter) = match (change.chars().next(), change.chars().nth(1), change.len()) {
+        (Some('+'), Some(letter), 2) => (true, letter),
+        (Some('-'), Some(letter), 2) => (false, letter),
+        _ => return vec![Frame::Error(USAGE.to_string())],
+    };
+
+    // Work on a copy and only keep it if the change makes sense.
+    let mut settings = state.rooms.get(room).cloned().unwrap_or_default();
+    match (letter, value) {
+        ('i', None) => settings.invite_only = on,
+        ('m', None) => settings.moderated = on,
+        ('t', None) => settings.topic_locked = on,
+        ('k', Some(password)) if on => settings.password = Some(password.to_string()),
+        ('k', None) if !on => settings.password = None,
+        ('o', Some(who)) if !on && settings.owner.as_deref() == Some(who) => {
+            return vec![Frame::Error(format!(
+                "{} owns {} - they stay an operator",
+                who, room
+            ))];
+        }
+        ('b', Some(who)) if on && settings.owner.as_deref() == Some(who) => {
+            return vec![Frame::Error(format!("{} owns {} - they can't be banned", who, room))];
+        }
+        // A role given to a free nick would go to whoever picks it next.
+        ('o' | 'v', Some(who))
+            if on
+                && state.find_nick(who).is_none()
+                && !federation::taken_on_bus(state, who)
+                && !state.rooms.is_registered(who) =>
+        {
+            return vec![Frame::Error(format!(
+                "{} isn't here and hasn't registered their nick - they can only get a role while they're here",
+                who
+            ))];
+        }
+        ('o', Some(who)) => change_set(&mut settings.operators, who, on),
+        ('v', Some(who)) => change_set(&mut settings.voiced, who, on),
+        ('b', Some(who)) => change_set(&mut settings.banned, who, on),
+        _ => return vec![Frame::Error(USAGE.to_string())],
+    }
+    state.rooms.update(|rooms| rooms.insert(room.to_string(), settings));
+
+    // Tell the room - but not the password.
+    let shown = match (letter, value) {
+        ('k', _) => change.to_string(),
+        (_, Some(value)) => format!("{} {}", change, value),
+        _ => change.to_string(),
+    };
+    println!("{} set {} {}", nick, room, shown);
+    state.send_to_room(
+        room,
+        &Frame::Info(format!("{} set {} {}", nick, room, shown)),
+    );
+    // Someone who was just banned has to go - unless they're a server
+    // operator, whom nothing keeps out.
+    if let ('b', true, Some(who)) = (letter, on, value)
+        && let Some(banned) = state.find_nick(who)
+        && state
+            .clients
+            .get(&banned)
+            .is_some_and(|c| c.rooms.contains(room) && !c.operator)
+    {
+        state.part_because(&banned, room, &format!("banned by {}", nick));
+    }
+    // Whoever changed it may not be in the room.
+    if state
+        .clients
+        .get(addr)
+        .is_some_and(|c| c.rooms.contains(room))
+    {
+        Vec::new()
+    } else {
+        vec![Frame::Info(format!("{} is now {}", room, shown))]
+    }
+}
+
+// Add `nick` to `set` or take them out.
+fn change_set(set: &mut BTreeSet<String>, nick: &str, on: bool) {
+    if on {
+        set.insert(nick.to_string());
+    } else {
+        set.remove(nick);
+    }
+}
+
+// "#ops: +imt, owner alice, operators bob, voiced carol, topic: deploys"
+fn describe(state: &State, addr: &str, room: &str) -> String {
+    let Some(settings) = state.rooms.get(room) else {
+        return format!("{}: no modes, nobody runs it", room);
+    };
+    let mut parts = vec![match settings.modes().as_str() {
+        "" => "no modes".to_string(),
+        modes => modes.to_string(),
+    }];
+    if let Some(password) = &settings.password
+        && operator_of(state, addr, room).is_ok()
+    {
+        parts.push(format!("password {}", password));
+    }
+    if let Some(owner) = &settings.owner {
+        parts.push(format!("owner {}", owner));
+    }
+    for (label, nicks) in [
+        ("operators", &settings.operators),
+        ("voiced", &settings.voiced),
+        ("banned", &settings.banned),
+    ] {
+        if !nicks.is_empty() {
+            let nicks: Vec<&str> = nicks.iter().map(String::as_str).collect();
+            parts.push(format!("{} {}", label, nicks.join(" ")));
+        }
+    }
+    if !settings.topic.is_empty() {
+        parts.push(format!("topic: {}", settings.topic));
+    }
+    format!("{}: {}", room, parts.join(", "))
+}
+
+// `/invite <nick> <#room>` lets `nick` into a room, past `+i` and `+k`.
+// In an invite-only room only operators may invite; in others anyone in it can.
+pub fn invite(state: &mut State, addr: &str, args: &str) -> Vec<Frame> {
+    let mut words = args.split_whitespace();
+    let (Some(nick), Some(room), None) = (words.next(), words.next(), words.next()) else {
+        return vec![Frame::Error("usage: /invite <nick> <#room>".to_string())];
+    };
+    if !valid_room(room) {
+        return vec![bad_room()];
+    }
+    // People on other copies of the server on the bus can be invited too,
+    // since the copies share their rooms. Only the ones here are told.
+    let here = state.find_nick(nick);
+    if here.is_none() && !federation::taken_on_bus(state, nick) {
+        return vec![Frame::Error(format!("{} isn't on this server", nick))];
+    }
+    let in_room = state
+        .clients
+        .get(addr)
+        .is_some_and(|c| c.rooms.contains(room));
+    let invite_only = state
+        .rooms
+        .get(room)
+        .is_some_and(|settings| settings.invite_only);
+    if (invite_only || !in_room)
+        && let Err(e) = operator_of(state, addr, room)
+    {
+        return vec![Frame::Error(e)];
+    }
+    let from = state.nick(addr);
+    state.rooms.update(|rooms| {
+        let settings = rooms.entry(room.to_string()).or_default();
+        settings.invited.insert(nick.to_string());
+    });
+    if let Some(who) = here {
+        state.send_to(
+            &who,
+            Frame::Info(format!("{} invited you to {} - /join {}", from, room, room)),
+        );
+    }
+    vec![Frame::Info(format!("invited {} to {}", nick, room))]
+}
+
+// `/topic <#room>` shows the topic, `/topic <#room> <text>` changes it and
+// `/topic <#room> -` clears it. With `+t` only operators may change it.
+pub fn topic(state: &mut State, addr: &str, args: &str) -> Vec<Frame> {
+    let (room, text) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+    let text = text.trim();
+    if !room.starts_with('#') {
+        return vec![Frame::Error("usage: /topic <#room> [text|-]".to_string())];
+    }
+    if text.is_empty() {
+        return vec![Frame::Info(match state.rooms.get(room) {
+            Some(settings) if !settings.topic.is_empty() => {
+                format!("topic of {}: {}", room, settings.topic)
+            }
+            _ => format!("{} has no topic", room),
+        })];
+    }
+    if !state
+        .clients
+        .get(addr)
+        .is_some_and(|c| c.rooms.contains(room))
+    {
+        return vec![Frame::Error("you're not in that room".to_string())];
+    }
+    if state
+        .rooms
+        .get(room)
+        .is_some_and(|settings| settings.topic_locked)
+        && let Err(e) = operator_of(state, addr, room)
+    {
+        return vec![Frame::Error(format!("{} - the topic is locked (+t)", e))];
+    }
+    let topic = if text == "-" { "" } else { text };
+    state.rooms.update(|rooms| {
+        rooms.entry(room.to_string()).or_default().topic = topic.to_string();
+    });
+    let nick = state.nick(addr);
+    let notice = if topic.is_empty() {
+        format!("{} cleared the topic of {}", nick, room)
+    } else {
+        format!("{} changed the topic of {} to: {}", nick, room, topic)
+    };
+    state.send_to_room(room, &Frame::Info(notice));
+    Vec::new()
+}
@@ -0,0 +1,261 @@
+// Full-text search over the message history:
+//
+//   /search <words> [#room] [from:nick] [since:date] [page:N]
+//
+// e.g. `/search deploy link #ops from:alice since:7d`. A message matches when
+// it has every one of the words - or a word starting with it, so "deploy" also
+// finds "deployed". Upper and lower case don't matter. The filters narrow
+// it down further:
+// - `#room`: only that room
+// - `from:nick`: only messages from that nick
+// - `since:2026-10-11` or `since:7d`: only messages from that day on, or
+//   from the last 7 days
+// - `page:N`: which page of results to show
+//
+// Only rooms the searcher is in are searched, just like `/thread` only shows
+// messages from rooms you're in. Results come newest first, PAGE_SIZE at a
+// time, each with its ID so `/reply`, `/thread` or `/react` can use it.
+//
+// Looking through every message for every search would be slow with a long
+// history, so we keep an "inverted index": for each word, the IDs of the
+// messages containing it. Searching is then a few lookups. The index changes
+// whenever the history does - a new message, an edit, a deletion, or an old
+// message falling out of the history.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chatty_rusty::protocol::{ChatMessage, Frame, format_rfc3339};
+
+use crate::state::State;
+
+// How many results one page shows.
+const PAGE_SIZE: usize = 10;
+
+// How to use it, for mistakes.
+const USAGE: &str = "usage: /search <words> [#room] [from:nick] [since:date] [page:N]";
+
+// Each word (in lower case) and the IDs of the messages containing it.
+// A BTreeMap keeps the words sorted, so all the words starting with "deploy"
+// sit next to each other and `range` finds them quickly.
+#[derive(Default)]
+pub struct Index {
+    words: BTreeMap<String, BTreeSet<u64>>,
+}
+
+impl Index {
+    // Add the message `id` with this text.
+    pub fn add(&mut self, id: u64, text: &str) {
+        for word in words(text) {
+            self.words.entry(word).or_default().insert(id);
+        }
+    }
+
+    // Take the message `id` out again. `text` must be what it was added with.
+    pub fn remove(&mut self, id: u64, text: &str) {
+        for word in words(text) {
+            if let Some(ids) = self.words.get_mut(&word) {
+                ids.remove(&id);
+                // Don't keep words nobody uses any more.
+                if ids.is_empty() {
+                    self.words.remove(&word);
+                }
+            }
+        }
+    }
+
+    // The IDs of the messages that have, for every term, a word starting with it.
+    fn lookup(&self, terms: &[String]) -> BTreeSet<u64> {
+        let mut found: Option<BTreeSet<u64>> = None;
+        for term in terms {
+            // Every message with a word starting with `term`.
+            let mut ids = BTreeSet::new();
+            for (_, with_word) in self
+                .words
+                .range(term.clone()..)
+                .take_while(|(word, _)| word.starts_with(term.as_str()))
+            {
+                ids.extend(with_word);
+            }
+            // Keep only the messages that matched the terms before, too.
+            found = Some(match found {
+                Some(before) => before.intersection(&ids).copied().collect(),
+                None => ids,
+            });
+        }
+        found.unwrap_or_default()
+    }
+}
+
+// Split a text into its words, in lower case: "Here's the LINK: https://x.io"
+// gives "here", "s", "the", "link", "https", "x", "io".
+fn words(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// A `/search` taken apart.
+// - `text`: the words as typed, to repeat them back
+// - `since`: the earliest timestamp wanted, e.g. "2026-10-11"
+struct Query {
+    text: String,
+    terms: Vec<String>,
+    room: Option<String>,
+    from: Option<String>,
+    since: Option<String>,
+    page: usize,
+}
+
+fn parse(args: &str) -> Result<Query, String> {
+    let mut query = Query {
+        text: String::new(),
+        terms: Vec::new(),
+        room: None,
+        from: None,
+        since: None,
+        page: 1,
+    };
+    let mut text = Vec::new();
+    for arg in args.split_whitespace() {
+        if arg.starts_with('#') {
+            query.room = Some(arg.to_string());
+        } else if let Some(nick) = arg.strip_prefix("from:") {
+            query.from = Some(nick.to_string());
+        } else if let Some(date) = arg.strip_prefix("since:") {
+            query.since = Some(since(date)?);
+        } else if let Some(page) = arg.strip_prefix("page:") {
+            query.page = match page.parse() {
+                Ok(page) if page > 0 => page,
+                _ => return Err(format!("page:{} isn't a page number", page)),
+            };
+        } else {
+            text.push(arg);
+        }
+    }
+    query.text = text.join(" ");
+    query.terms = words(&query.text).into_iter().collect();
+    if query.terms.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    Ok(query)
+}
+
+// Turn `since:` into the earliest timestamp wanted. Timestamps are RFC 3339,
+// which sort in time order when compared as text, so "2026-10-11" comes
+// before every timestamp on that day and after every one the day before.
+fn since(date: &str) -> Result<String, String> {
+    // `7d`: seven days ago.
+    if let Some(days) = date.strip_suffix('d')
+        && let Ok(days) = days.parse::<u64>()
+    {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        return Ok(format_rfc3339(now.saturating_sub(days.saturating_mul(86_400))));
+    }
+    // `2026-10-11`
+    let looks_right = date.len() == 10
+        && date.char_indices().all(|(i, c)| match i {
+            4 | 7 => c == '-',
+            _ => c.is_ascii_digit(),
+        });
+    if looks_right {
+        Ok(date.to_string())
+    } else {
+        Err(format!(
+            "since:{} - use a date like 2026-10-11, or 7d for the last 7 days",
+            date
+        ))
+    }
+}
+
+// Run `/search <args>` for the client at `addr` and return what to tell them.
+pub fn search(state: &State, addr: &str, args: &str) -> Vec<Frame> {
+    let query = match parse(args) {
+        Ok(query) => query,
+        Err(e) => return vec![Frame::Error(e)],
+    };
+    let Some(client) = state.clients.get(addr) else {
+        return Vec::new();
+    };
+    if let Some(room) = &query.room
+        && !client.rooms.contains(room)
+    {
+        return vec![Frame::Error(format!("you're not in {}", room))];
+    }
+
+    // The index finds the messages with the right words; then we look each
+    // one up and apply the filters. IDs go up over time, so going through
+    // them backwards gives the newest first - and we only ever touch the
+    // messages that matched, not the whole history.
+    let ids = state.index.lookup(&query.terms);
+    let matches: Vec<_> = ids
+        .iter()
+        .rev()
+        .filter_map(|&id| state.position(id))
+        .map(|index| &state.history[index].msg)
+        .filter(|msg| match &query.room {
+            Some(room) => msg.room == *room,
+            None => client.rooms.contains(&msg.room),
+        })
+        .filter(|msg| query.from.as_ref().is_none_or(|nick| msg.sender == *nick))
+        .filter(|msg| query.since.as_ref().is_none_or(|since| msg.time >= *since))
+        .collect();
+
+    if matches.is_empty() {
+        return vec![Frame::Info(format!("no messages match \"{}\"", query.text))];
+    }
+    // `div_ceil` rounds up: 23 results are 3 pages of 10.
+    let pages = matches.len().div_ceil(PAGE_SIZE);
+    if query.page > pages {
+        return vec![Frame::Error(format!("there are only {} pages", pages))];
+    }
+
+    let noun = if matches.len() == 1 {
+        "result"
+    } else {
+        "results"
+    };
+    let mut replies = vec![Frame::Info(format!(
+        "{} {} for \"{}\", page {} of {}, newest first",
+        matches.len(),
+        noun,
+        query.text,
+        query.page,
+        pages
+    ))];
+    for msg in matches
+        .iter()
+        .skip((query.page - 1) * PAGE_SIZE)
+        .take(PAGE_SIZE)
+    {
+        replies.push(Frame::Info(listing(msg)));
+    }
+    if query.page < pages {
+        // The same search with the next page number, ready to type.
+        let mut next: Vec<&str> = args
+            .split_whitespace()
+            .filter(|arg| !arg.starts_with("page:"))
+            .collect();
+        let page = format!("page:{}", query.page + 1);
+        next.push(&page);
+        replies.push(Frame::Info(format!("more: /search {}", next.join(" "))));
+    }
+    replies
+}
+
+// One message as a line of a list, with the day it was sent - `/search` and
+// `/mentions` show older messages, where the time alone isn't enough:
+// "#412 [#general] 2026-10-11 14:02 alice: here's the link"
+pub fn listing(msg: &ChatMessage) -> String {
+    let sender = match &msg.origin {
+        Some(origin) => format!("{}@{}", msg.sender, origin),
+        None => msg.sender.clone(),
+    };
+    // "2026-10-11T14:02:09Z" -> "2026-10-11 14:02"
+    let when = msg.time.get(..16).unwrap_or(&msg.time).replace('T', " ");
+    format!("#{} [{}] {} {}: {}", msg.id, msg.room, when, sender, msg.text)
+}
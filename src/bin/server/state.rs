@@ -0,0 +1,791 @@
+// Everything the server's tasks share lives in this file:
+// the connected clients, the rooms they're in and the message history.
+
+// `HashMap` is a key-value store - we use it to store all connected clients.
+// `VecDeque` is a "double ended queue" - a list where adding to the back and
+// removing from the front are both cheap. Perfect for a history of recent
+// messages where the oldest ones fall off the front once we have too many.
+// `BTreeMap` and `BTreeSet` are a sorted map and a sorted set. We use them
+// where we want things listed in the same order for every client.
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+// `Arc` stands for "Atomically Reference Counted" - it lets multiple parts of
+// your program share ownership of the same data safely.
+use std::sync::Arc;
+
+// `Mutex` ensures only one task can access the shared data at a time.
+// `mpsc` stands for "multi-producer, single-consumer": a channel that many
+// tasks can send values into and exactly one task receives them from.
+use tokio::sync::{mpsc, Mutex};
+
+// The protocol module lives in our own library (`src/protocol.rs`) so the
+// client can use exactly the same definitions to read what we send.
+use chatty_rusty::protocol::{mentions, now_rfc3339, ChatMessage, Frame};
+
+// `crate::` means "starting from the root of this binary", i.e. `main.rs`.
+use crate::bus::{Broadcast, Bus, LocalBus};
+use crate::commands::valid_nick;
+use crate::config::Config;
+use crate::federation::{self, Event, Link, Remote};
+use crate::inbound::Tokens;
+use crate::motd::Motd;
+use crate::plugins::{self, Plugin};
+use crate::rooms::Rooms;
+use crate::search::Index;
+use crate::transfer::Transfer;
+use crate::unread::ReadMarkers;
+use crate::webhooks::Webhooks;
+
+// Every client joins this room when it connects.
+pub const DEFAULT_ROOM: &str = "#general";
+
+// The reactions on one message: each emoji maps to the set of clients who
+// reacted with it. Storing WHO reacted (not just a count) lets us stop a
+// client from reacting with the same emoji twice, and lets them take it back.
+pub type Reactions = BTreeMap<String, BTreeSet<String>>;
+
+// Everything the server knows about one connected client.
+// - `nick`: the name other people see. Until the client picks one with
+//   `/nick` it's their address.
+// - `operator`: `true` once the client proved it knows the operator password.
+// - `rooms`: every room the client has joined.
+// - `room`: the room plain chat lines go to - the last one they joined.
+// - `bulk`: a channel for file chunks to this client's writer task. Chat
+//   frames reach the writer through `State::local` (see `bus.rs`) on a
+//   channel of their own, which it always empties first, so a big download
+//   never delays chat messages.
+// - `key`: the public key the client published with `/setkey`, so others can
+//   send it encrypted private messages. It goes with the connection, so
+//   changing nick keeps it and leaving forgets it.
+// - `fd`: the number the operating system knows the client's socket by, so
+//   `/restart` can pass it on (see `handoff.rs`). None for IRC clients and
+//   where sockets can't be passed on.
+pub struct Client {
+    pub nick: String,
+    pub operator: bool,
+    pub rooms: BTreeSet<String>,
+    pub room: Option<String>,
+    pub bulk: mpsc::Sender<Frame>,
+    pub key: Option<String>,
+    pub fd: Option<i32>,
+}
+
+// A message in the history, plus what only the server needs to know about it:
+// - `author`: the address of the connection that sent it. We check this
+//   (not the nick, which can change) before allowing an edit or delete.
+// - `reactions`: who reacted with what.
+pub struct Stored {
+    pub msg: ChatMessage,
+    pub author: String,
+    pub reactions: Reactions,
+}
+
+// `State` bundles together everything the tasks need to share:
+// - `clients`: maps a client's address (as text) to their `Client` entry.
+// - `local`: the bus every frame for them goes through (see `bus.rs`).
+// - `next_id`: the ID the next accepted message will get.
+// - `history`: the most recent messages, oldest first.
+// - `index`: the words in `history`, for `/search`.
+// - `unseen`: for each nick, the messages mentioning them that they weren't
+//   in the room to see, for `/mentions`.
+// - `read`: how far each nick has read in each room.
+// - `transfers`: uploaded files waiting for their recipients, by transfer ID.
+// - `next_xfer`: the ID the next file transfer will get.
+// - `stored_bytes`: the size of the files being uploaded or waiting for
+//   their recipients, so there's a limit on how much disk they take.
+// - `name`: this server's name, shown to linked servers.
+// - `links`: the other servers we're linked to, by name.
+// - `remote`: what we know about the servers at the other end of them.
+// - `plugins`: the bots running inside the server.
+// - `webhooks`: the web addresses told about messages, edits, joins and leaves.
+// - `rooms`: who runs each room and who may join and talk (see `rooms.rs`).
+// - `motd`: the message of the day, shown to everyone who connects.
+// - `http_tokens`: who may post over HTTP, if that's on. `inbound.rs` has
+//   its own handle to them; this one is for `/reload`.
+// - `config`: the options the server is running with. `/reload` changes
+//   some of them while it runs (see `reload.rs`).
+// - `restart`: where `/restart` asks for a handoff to a new server, with who
+//   asked (see `handoff.rs`).
+// Keeping all of them behind ONE lock is what gives us our ordering guarantee:
+// a message gets its ID and is queued for every client while the lock is held,
+// so no other message can sneak in between. Every client therefore sees
+// messages in exactly the same order, and that order matches the IDs.
+pub struct State {
+    pub clients: HashMap<String, Client>,
+    pub local: LocalBus,
+    pub next_id: u64,
+    pub history: VecDeque<Stored>,
+    pub index: Index,
+    pub unseen: HashMap<String, BTreeSet<u64>>,
+    pub read: ReadMarkers,
+    pub transfers: HashMap<u64, Transfer>,
+    pub next_xfer: u64,
+    pub stored_bytes: u64,
+    pub name: String,
+    pub links: HashMap<String, Link>,
+    pub remote: Remote,
+    pub plugins: Vec<Box<dyn Plugin>>,
+    pub webhooks: Webhooks,
+    pub rooms: Rooms,
+    pub motd: Motd,
+    pub http_tokens: Option<Tokens>,
+    pub config: Config,
+    pub restart: Option<mpsc::UnboundedSender<Option<String>>>,
+}
+
+// We define a type alias called `Db` to avoid writing this long type everywhere.
+// Breaking it down from the inside out:
+// - `State`: the struct above holding the clients and the message history
+// - `Mutex<...>`: wraps the State so only one task can access it at a time
+// - `Arc<...>`: allows multiple tasks to share ownership of the Mutex
+// Together, Arc<Mutex<...>> is the classic Rust pattern for shared mutable state.
+pub type Db = Arc<Mutex<State>>;
+
+impl State {
+    // Create the empty shared state.
+    // Message IDs start at 1 so a client can use 0 to mean "nothing seen yet".
+    pub fn new(config: Config) -> Self {
+        State {
+            clients: HashMap::new(),
+            local: LocalBus::default(),
+            next_id: 1,
+            history: VecDeque::new(),
+            index: Index::default(),
+            unseen: HashMap::new(),
+            read: ReadMarkers::default(),
+            transfers: HashMap::new(),
+            next_xfer: 1,
+            stored_bytes: 0,
+            name: config.name.clone(),
+            links: HashMap::new(),
+            remote: Remote::default(),
+            plugins: plugins::builtin(),
+            webhooks: Webhooks::default(),
+            rooms: Rooms::default(),
+            motd: Motd::default(),
+            http_tokens: None,
+            config,
+            restart: None,
+        }
+    }
+
+    // Add a client that just connected. Frames for them go into `tx`, the
+    // sending end of a channel to their writer task: anything sent into it
+    // is written to their socket, in order. Sending never waits, so we can
+    // do it while holding the lock without one slow client holding up
+    // everybody else.
+    pub fn connect(&mut self, addr: &str, client: Client, tx: mpsc::UnboundedSender<Frame>) {
+        self.clients.insert(addr.to_string(), client);
+        self.local.connect(addr, tx);
+    }
+
+    // Queue a frame for the clients at the addresses in `to`, through the
+    // bus. Every other way of sending a client something ends up here.
+    pub fn broadcast(&self, to: Vec<String>, frame: Frame) {
+        if !to.is_empty() {
+            self.local.publish(Broadcast { to, frame });
+        }
+    }
+
+    // Queue a frame for one client. If the client has already gone away
+    // there's nobody to tell, so we quietly ignore it.
+    pub fn send_to(&self, addr: &str, frame: Frame) {
+        self.broadcast(vec![addr.to_string()], frame);
+    }
+
+    // Queue a frame for every client in `room`.
+    pub fn send_to_room(&self, room: &str, frame: &Frame) {
+        let to = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.rooms.contains(room))
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        self.broadcast(to, frame.clone());
+    }
+
+    // Queue a frame ONCE for every client sharing at least one of `rooms`,
+    // and for `also` (usually the client the frame is about) even if they're
+    // in no room at all.
+    pub fn send_to_rooms(&self, rooms: &BTreeSet<String>, also: Option<&str>, frame: &Frame) {
+        let to = self
+            .clients
+            .iter()
+            .filter(|(addr, client)| {
+                Some(addr.as_str()) == also || !client.rooms.is_disjoint(rooms)
+            })
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        self.broadcast(to, frame.clone());
+    }
+
+    pub fn nick(&self, addr: &str) -> String {
+        self.clients
+            .get(addr)
+            .map(|c| c.nick.clone())
+            .unwrap_or_else(|| addr.to_string())
+    }
+
+    // Find a connected client by nick and return their address.
+    pub fn find_nick(&self, nick: &str) -> Option<String> {
+        self.clients
+            .iter()
+            .find(|(_, c)| c.nick == nick)
+            .map(|(addr, _)| addr.clone())
+    }
+
+    // Like `join`, but only if the room lets the client in: it may be
+    // invite only or need a password (see `rooms.rs`). Switching to a room
+    // you're already in always works.
+    pub fn enter(&mut self, addr: &str, room: &str, password: &str) -> Result<(), String> {
+        if let Some(client) = self.clients.get(addr)
+            && !client.rooms.contains(room)
+        {
+            self.rooms.check_join(room, &client.nick, password, client.operator)?;
+        }
+        self.join(addr, room);
+        Ok(())
+    }
+
+    // Add the client at `addr` to `room`, make it their active room and tell
+    // everyone in the room.
+    pub fn join(&mut self, addr: &str, room: &str) {
+        let nick = self.nick(addr);
+        if let Some(client) = self.clients.get_mut(addr) {
+            client.room = Some(room.to_string());
+            // `insert` returns false if they were already in the room,
+            // in which case we only switched their active room.
+            if !client.rooms.insert(room.to_string()) {
+                self.send_to(addr, Frame::Info(format!("now talking in {}", room)));
+                return;
+            }
+        }
+        println!("{} joined {}", nick, room);
+        // The first one into a new room owns it - counting the people on
+        // other copies of the server, who share the room's settings.
+        let empty = !self
+            .clients
+            .iter()
+            .any(|(other, client)| other != addr && client.rooms.contains(room))
+            && !federation::bus_members(self).any(|(other, _)| other == room);
+        self.rooms.joined(room, &nick, empty);
+        let latest = self.next_id - 1;
+        self.read.start(&nick, room, latest);
+        self.send_to_room(
+            room,
+            &Frame::Join {
+                nick: nick.clone(),
+                room: room.to_string(),
+            },
+        );
+        // Right after the JOIN, so clients show it where you came in.
+        if let Some(topic) = self.rooms.topic(room) {
+            let frame = Frame::Topic {
+                room: room.to_string(),
+                topic: topic.to_string(),
+            };
+            self.send_to(addr, frame);
+        }
+        plugins::joined(self, &nick, room);
+        self.webhooks.joined(&nick, room);
+        federation::relay_local(
+            self,
+            Event::Join {
+                nick,
+                room: room.to_string(),
+            },
+        );
+    }
+
+    // Remove the client at `addr` from `room` and tell everyone who's left.
+    pub fn part(&mut self, addr: &str, room: &str) {
+        self.part_because(addr, room, "");
+    }
+
+    // Like `part`, but with a reason everyone sees, e.g. when they're banned.
+    pub fn part_because(&mut self, addr: &str, room: &str, reason: &str) {
+        let nick = self.nick(addr);
+        // Tell the room BEFORE removing them, so they see it too.
+        self.send_to_room(
+            room,
+            &Frame::Part {
+                nick: nick.clone(),
+                room: room.to_string(),
+                reason: reason.to_string(),
+            },
+        );
+        if let Some(client) = self.clients.get_mut(addr) {
+            client.rooms.remove(room);
+            // If that was their active room, fall back to any other room
+            // they're still in (or none at all).
+            if client.room.as_deref() == Some(room) {
+                client.room = client.rooms.iter().next_back().cloned();
+            }
+        }
+        println!("{} left {}", nick, room);
+        plugins::left(self, &nick, room);
+        self.webhooks.left(&nick, room);
+        federation::relay_local(
+            self,
+            Event::Part {
+                nick,
+                room: room.to_string(),
+            },
+        );
+    }
+
+    // Change the nick of the client at `addr` and tell everyone who shares a
+    // room with them. The caller has already checked the new nick.
+    pub fn rename(&mut self, addr: &str, nick: &str) {
+        let Some(client) = self.clients.get_mut(addr) else {
+            return;
+        };
+        let old = std::mem::replace(&mut client.nick, nick.to_string());
+        let rooms = client.rooms.clone();
+        // Read markers under an address were only ever meant for this
+        // connection. The new nick may have markers from an earlier visit.
+        if old == addr {
+            self.read.forget(&old);
+            // Rooms created before picking a nick belong to the new one.
+            self.rooms.renamed(&old, nick);
+        } else {
+            // Nobody has the old nick now: it loses its roles, unless it's
+            // registered (see `rooms.rs`).
+            self.rooms.released(&old);
+        }
+        let latest = self.next_id - 1;
+        for room in &rooms {
+            self.read.start(nick, room, latest);
+        }
+        let frame = Frame::Nick {
+            old: old.clone(),
+            new: nick.to_string(),
+        };
+        self.send_to_rooms(&rooms, Some(addr), &frame);
+        println!("{} is now known as {}", old, nick);
+        federation::relay_local(
+            self,
+            Event::Nick {
+                old,
+                new: nick.to_string(),
+            },
+        );
+    }
+
+    // The client at `addr` has gone: leave every room and forget them.
+    // Leaving the bus also drops `tx`, which tells the writer task to stop.
+    pub fn disconnect(&mut self, addr: &str) {
+        let rooms = self
+            .clients
+            .get(addr)
+            .map(|c| c.rooms.clone())
+            .unwrap_or_default();
+        for room in rooms {
+            self.part(addr, &room);
+        }
+        // Without a nick, nobody can come back for these.
+        self.read.forget(addr);
+        self.local.disconnect(addr);
+        if let Some(client) = self.clients.remove(addr) {
+            self.rooms.released(&client.nick);
+        }
+    }
+
+    // Drop the roles of unregistered nicks nobody is using - after the
+    // rooms file was read, since people may have left while we weren't
+    // running (see `rooms.rs`). Nicks in use on other copies of the server
+    // on the bus count as used: those copies share the rooms with us.
+    pub fn forget_free_nicks(&mut self) {
+        let mut online: BTreeSet<String> = self.clients.values().map(|c| c.nick.clone()).collect();
+        online.extend(federation::bus_members(self).map(|(_, nick)| nick.to_string()));
+        self.rooms.keep_online(&online)
+    }
+
+    // Give a new message an ID and a timestamp, remember it in the history and
+    // queue it for everyone in `room`. `reply_to` is the parent message if
+    // this is a reply. Returns the new message's ID, or None if a plugin
+    // refused the message - the author has been told why.
+    pub fn post(
+        &mut self,
+        author: &str,
+        room: &str,
+        text: &str,
+        reply_to: Option<u64>,
+    ) -> Option<u64> {
+        if let Err(reason) = self.may_speak(author, room) {
+            self.send_to(author, Frame::Error(reason));
+            return None;
+        }
+        let mut msg = ChatMessage {
+            // `store` fills in the real ID.
+            id: 0,
+            time: now_rfc3339(),
+            sender: self.nick(author),
+            room: room.to_string(),
+            text: text.to_string(),
+            edited: false,
+            reply_to,
+            origin: None,
+            // `store` works these out.
+            mentions: Vec::new(),
+        };
+        // Plugins (and scripts) get a look first, and may change or refuse it.
+        if let Err(reason) = plugins::filter(self, &mut msg) {
+            self.send_to(author, Frame::Error(reason));
+            return None;
+        }
+        // Linked servers get it too, with the ID `store` is about to give it.
+        // The message it replies to is pointed at the way every server
+        // understands (see `federation.rs`).
+        let reply = reply_to.map(|parent| federation::msg_ref(self, parent));
+        federation::relay_local(
+            self,
+            Event::Msg {
+                id: self.next_id,
+                reply,
+                time: msg.time.clone(),
+                sender: msg.sender.clone(),
+                room: msg.room.clone(),
+                text: msg.text.clone(),
+            },
+        );
+        Some(self.store(msg, author))
+    }
+
+    // Send a private message from the client at `from` to `nick`. Both of
+    // them get it, so the sender sees it in their own window too. Private
+    // messages don't go through plugins or webhooks, and they aren't stored.
+    // For someone on a linked server, they go over the links - and so pass
+    // through any servers in between.
+    pub fn private(&mut self, from: &str, nick: &str, text: &str) -> Result<(), String> {
+        let frame = Frame::Private {
+            time: now_rfc3339(),
+            from: self.nick(from),
+            to: nick.to_string(),
+            text: text.to_string(),
+        };
+        if let Some(to) = self.find_nick(nick) {
+            self.send_to(&to, frame.clone());
+            if to != from {
+                self.send_to(from, frame);
+            }
+            return Ok(());
+        }
+        let (to, server) = federation::find_remote(self, nick)
+            .ok_or(format!("{} isn't on this server", nick))?;
+        let event = Event::Private {
+            server,
+            from: self.nick(from),
+            to,
+            text: text.to_string(),
+        };
+        federation::relay_local(self, event);
+        self.send_to(from, frame);
+        Ok(())
+    }
+
+    // In a moderated room only some people may talk (see `rooms.rs`).
+    // Plugins and programs posting over HTTP aren't clients, and always may.
+    fn may_speak(&self, author: &str, room: &str) -> Result<(), String> {
+        match self.clients.get(author) {
+            Some(client) if !self.rooms.may_speak(room, &client.nick, client.operator) => Err(
+                format!("{} is moderated - only people with a voice (+v) can talk", room),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    // Post `msg`, which arrived over a link from another server.
+    // It gets one of OUR IDs, so it fits into our ordering like any other, and
+    // we return that ID. It has no local author, so only an operator can edit
+    // or delete it here. It doesn't go through our plugins and scripts: its
+    // own server already ran its filters, and if ours could change or refuse
+    // it, linked servers would disagree about what was said.
+    pub fn post_remote(&mut self, msg: ChatMessage) -> u64 {
+        self.store(msg, "")
+    }
+
+    // The part of posting shared by local and remote messages: take the next ID,
+    // queue the message for the room and remember it. Returns the new ID.
+    fn store(&mut self, mut msg: ChatMessage, author: &str) -> u64 {
+        msg.id = self.next_id;
+        self.next_id += 1;
+        // Tag everyone it mentions, so their clients can highlight it.
+        // Done here, after the plugins had their say, so it matches the text
+        // that's actually sent.
+        msg.mentions = tagged_mentions(&msg.text);
+
+        // Notice we DON'T skip the sender: receiving their own message back
+        // is how a client learns which ID the server gave it.
+        self.send_to_room(&msg.room, &Frame::Msg(msg.clone()));
+
+        // Remember the message. If the history is full, forget the oldest one
+        // (its reactions are forgotten together with it) - or the oldest few,
+        // if `/reload` just made the limit smaller. `/search` only finds
+        // what's in the history, so the index follows along.
+        let id = msg.id;
+        self.index.add(id, &msg.text);
+        self.history.push_back(Stored {
+            msg: msg.clone(),
+            author: author.to_string(),
+            reactions: Reactions::new(),
+        });
+        while self.history.len() > self.config.history_limit
+            && let Some(oldest) = self.history.pop_front()
+        {
+            self.index.remove(oldest.msg.id, &oldest.msg.text);
+        }
+
+        self.note_unseen(&msg);
+        // Whoever wrote it has obviously read the room up to here.
+        if let Some(client) = self.clients.get(author) {
+            let nick = client.nick.clone();
+            self.read.mark(&nick, &msg.room, id);
+        }
+        self.webhooks.message(&msg);
+
+        // Let the plugins see it. Anything they say comes right after it.
+        plugins::message(self, &msg);
+        id
+    }
+
+    // Change the text of message `id` for the client at `addr`, for `/edit`.
+    // The new text is checked like a new message: the room's modes, then the
+    // plugins and scripts, which may change it or refuse it.
+    pub fn edit(&mut self, addr: &str, id: u64, text: &str) -> Result<(), String> {
+        let index = self.check_can_change(addr, id)?;
+        let mut msg = self.history[index].msg.clone();
+        self.may_speak(addr, &msg.room)?;
+        msg.text = text.to_string();
+        plugins::filter(self, &mut msg)?;
+        // Linked servers change their copy too - but only of a message posted
+        // here. An operator changing a message from another server only
+        // changes ours, since that server decides what its messages say.
+        if self.change_text(id, &msg.text) && !self.remote.is_remote(id) {
+            federation::relay_local(self, Event::Edit { id, text: msg.text });
+        }
+        Ok(())
+    }
+
+    // Delete message `id` for the client at `addr`, for `/delete`.
+    pub fn delete(&mut self, addr: &str, id: u64) -> Result<(), String> {
+        self.check_can_change(addr, id)?;
+        // Like edits, only deletions of messages posted here go to linked servers.
+        if self.remove_message(id) && !self.remote.is_remote(id) {
+            federation::relay_local(self, Event::Delete { id });
+        }
+        Ok(())
+    }
+
+    // Remove message `id` from the history and tell its room. Returns false
+    // if it was already gone.
+    pub fn remove_message(&mut self, id: u64) -> bool {
+        let Some(index) = self.position(id) else {
+            return false;
+        };
+        // Removed from the history, it will never be replayed again.
+        // `remove` gives the message back so we know its room.
+        let Some(stored) = self.history.remove(index) else {
+            return false;
+        };
+        self.index.remove(id, &stored.msg.text);
+        self.send_to_room(&stored.msg.room, &Frame::Delete { id });
+        true
+    }
+
+    // Add or take back the client at `addr`'s reaction `emoji` to message
+    // `id`, for `/react`: sending the same reaction again takes it back.
+    pub fn react(&mut self, addr: &str, id: u64, emoji: &str) -> Result<(), String> {
+        let index = self.find_visible(addr, id)?;
+        let on = !self.history[index]
+            .reactions
+            .get(emoji)
+            .is_some_and(|users| users.contains(addr));
+        self.set_reaction(id, addr, emoji, on);
+        // Linked servers count it too, under the nick.
+        let event = Event::React {
+            msg: federation::msg_ref(self, id),
+            nick: self.nick(addr),
+            on,
+            emoji: emoji.to_string(),
+        };
+        federation::relay_local(self, event);
+        Ok(())
+    }
+
+    // Turn `who`'s reaction `emoji` to message `id` on or off, and tell the
+    // room the new counts. `who` is a client's address, or `nick@server` for
+    // someone on a linked server.
+    pub fn set_reaction(&mut self, id: u64, who: &str, emoji: &str, on: bool) {
+        let Some(index) = self.position(id) else {
+            return;
+        };
+        // `entry(...).or_default()` gets the existing value or inserts an
+        // empty one first if there's none yet.
+        let users = self.history[index]
+            .reactions
+            .entry(emoji.to_string())
+            .or_default();
+        if on {
+            users.insert(who.to_string());
+        } else {
+            users.remove(who);
+        }
+        let frame = self.reaction_frame(index);
+        self.send_to_room(&self.history[index].msg.room, &frame);
+    }
+
+    // Replace the text of message `id` and tell its room and the webhooks.
+    // Returns false if there was nothing to change: the message is gone, or
+    // already says `text`.
+    pub fn change_text(&mut self, id: u64, text: &str) -> bool {
+        let Some(index) = self.position(id) else {
+            return false;
+        };
+        // Update the copy in the history so anyone who asks for `/since`
+        // later gets the new text too.
+        let msg = &mut self.history[index].msg;
+        if msg.text == text {
+            return false;
+        }
+        // `/search` should find the new words, not the old ones.
+        self.index.remove(id, &msg.text);
+        self.index.add(id, text);
+        msg.text = text.to_string();
+        msg.mentions = tagged_mentions(text);
+        msg.edited = true;
+        let msg = msg.clone();
+        let frame = Frame::Edit {
+            id,
+            text: text.to_string(),
+        };
+        self.send_to_room(&msg.room, &frame);
+        self.webhooks.edited(&msg);
+        true
+    }
+
+    // Remember the mentions in `msg` of people who aren't in its room to see
+    // it, for `/mentions`. Anything older than the history is dropped on the
+    // way, since `/mentions` couldn't show it anyway.
+    fn note_unseen(&mut self, msg: &ChatMessage) {
+        let oldest = self.history.front().map_or(0, |stored| stored.msg.id);
+        for nick in &msg.mentions {
+            let there = self
+                .find_nick(nick)
+                .and_then(|addr| self.clients.get(&addr))
+                .is_some_and(|client| client.rooms.contains(&msg.room));
+            if !there {
+                let ids = self.unseen.entry(nick.clone()).or_default();
+                ids.insert(msg.id);
+                ids.retain(|id| *id >= oldest);
+            }
+        }
+    }
+
+    // The unseen mentions of `nick` still in the history, oldest first.
+    // They count as seen from now on.
+    pub fn take_unseen(&mut self, nick: &str) -> Vec<ChatMessage> {
+        let ids = self.unseen.remove(nick).unwrap_or_default();
+        ids.into_iter()
+            .filter_map(|id| self.position(id))
+            .map(|index| self.history[index].msg.clone())
+            .collect()
+    }
+
+    // Find a message in the history by ID and return its position.
+    pub fn position(&self, id: u64) -> Option<usize> {
+        // Messages are stored in ID order, so we can binary search: look in
+        // the middle, then in the half the ID must be in, and so on. That
+        // takes about 17 steps for 100,000 messages instead of 100,000.
+        self.history
+            .binary_search_by_key(&id, |s| s.msg.id)
+            .ok()
+    }
+
+    // Find a message that the client at `addr` is allowed to see - one posted
+    // in a room they're in - and return its position in the history.
+    // On failure we return the error text to send back to the client.
+    pub fn find_visible(&self, addr: &str, id: u64) -> Result<usize, String> {
+        let not_found = format!("message #{} not found", id);
+        let index = self.position(id).ok_or(not_found.clone())?;
+        let room = &self.history[index].msg.room;
+        // Pretending the message doesn't exist doesn't leak what was said
+        // in rooms the client isn't part of.
+        match self.clients.get(addr) {
+            Some(client) if client.rooms.contains(room) => Ok(index),
+            _ => Err(not_found),
+        }
+    }
+
+    // Check that the client at `addr` may edit or delete message `id`.
+    // Only the author of a message, or an operator, may change it.
+    // On success we return the position of the message inside the history
+    // so the caller doesn't have to search for it again.
+    pub fn check_can_change(&self, addr: &str, id: u64) -> Result<usize, String> {
+        let index = self
+            .position(id)
+            .ok_or(format!("message #{} not found", id))?;
+
+        // `is_some_and` is `false` if the client isn't in the registry at all.
+        let is_operator = self.clients.get(addr).is_some_and(|c| c.operator);
+
+        if self.history[index].author == addr || is_operator {
+            Ok(index)
+        } else {
+            Err(format!("message #{} is not yours", id))
+        }
+    }
+
+    // Collect the thread that the message at `index` belongs to,
+    // oldest message first.
+    pub fn thread(&self, index: usize) -> Vec<ChatMessage> {
+        // A small helper closure that finds a message by ID.
+        let find = |id: u64| self.position(id).map(|i| &self.history[i].msg);
+
+        // Walk up from the message to the one that started the thread.
+        // If a parent has already fallen out of the history we stop there.
+        let mut root = &self.history[index].msg;
+        while let Some(parent) = root.reply_to.and_then(find) {
+            root = parent;
+        }
+
+        // A reply always has a bigger ID than its parent, so walking the history
+        // from the root onwards we meet every parent before its replies.
+        // That means one pass is enough: a message belongs to the thread if its
+        // parent is already in it.
+        let mut ids = BTreeSet::from([root.id]);
+        let mut messages = vec![root.clone()];
+        for stored in self.history.iter().filter(|s| s.msg.id > root.id) {
+            if stored.msg.reply_to.is_some_and(|parent| ids.contains(&parent)) {
+                ids.insert(stored.msg.id);
+                messages.push(stored.msg.clone());
+            }
+        }
+        messages
+    }
+
+    // Build the REACT frame listing every reaction on the message at `index`
+    // with its count. Emojis nobody is using anymore are left out.
+    pub fn reaction_frame(&self, index: usize) -> Frame {
+        let stored = &self.history[index];
+        let counts = stored
+            .reactions
+            .iter()
+            .filter(|(_, users)| !users.is_empty())
+            .map(|(emoji, users)| (emoji.clone(), users.len()))
+            .collect();
+        Frame::React {
+            id: stored.msg.id,
+            counts,
+        }
+    }
+}
+
+// The nicks a message mentions that could be somebody's nick: "@bob" is,
+// "@2pm" or "@bob@office2" aren't.
+fn tagged_mentions(text: &str) -> Vec<String> {
+    mentions(text)
+        .into_iter()
+        .filter(|nick| valid_nick(nick))
+        .collect()
+}
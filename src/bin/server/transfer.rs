@@ -0,0 +1,510 @@
+// File transfers.
+//
+// Files travel through the server in three steps:
+// 1. The sender uploads the file in pieces ("chunks") with
+//      /upload <token> <nick|#room> <size> <sha256> <name>
+//      /chunk <token> <base64>      (as many times as needed)
+//      /end <token>
+//    `<token>` is any number the sender picks to tell its uploads apart.
+//    The server writes the pieces to a temporary file and checks the
+//    SHA-256 fingerprint once the last piece has arrived. The temporary
+//    files live in a folder only this server process can open (see
+//    `staging_dir`), and there are limits on how many uploads one client may
+//    have going and on how much the server stores in all.
+// 2. Every recipient gets an OFFER and answers with `/accept` or `/decline`.
+// 3. For each `/accept` a download task streams the file back as CHUNK lines
+//    followed by DONE. The chunks go through the client's `bulk` channel,
+//    so chat messages are never stuck behind a big file.
+
+// `HashMap` and `HashSet` are the key-value map and set from the standard library.
+use std::collections::{HashMap, HashSet};
+
+// `PathBuf` is an owned file system path, like `String` is an owned `str`.
+use std::path::PathBuf;
+
+// A plain (not async) `Mutex`, for the staging folder - see `staging_dir`.
+use std::sync::Mutex;
+
+// `Duration` is a length of time, e.g. `Duration::from_secs(600)` is 10 minutes.
+use std::time::Duration;
+
+// Async versions of the file functions, so waiting for the disk doesn't
+// block the other tasks. `AsyncReadExt` gives us `read()`, `AsyncWriteExt`
+// gives us `write_all()`.
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+// `Sha256` computes a SHA-256 fingerprint: 32 bytes that change completely
+// if even one bit of the file changes. `Digest` is the trait that gives
+// it the `new()`, `update()` and `finalize()` methods.
+use sha2::{Digest, Sha256};
+
+use chatty_rusty::protocol::{base64_decode, base64_encode, Frame};
+
+use crate::state::{Db, State};
+
+// How many bytes go into each CHUNK line we send. Base64 makes that about
+// 22 KB of text per line.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// How long recipients have to answer an offer before the file is thrown away.
+const OFFER_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// How many uploads one client may have going at the same time.
+const MAX_UPLOADS: usize = 4;
+
+// How much the server stores at once - files being uploaded plus files
+// waiting for their recipients - counted in files of the biggest size
+// allowed (`--max-file-size`). With the default 10 MiB that's 1000 MiB.
+const MAX_STORED_FILES: u64 = 100;
+
+// The folder this server process keeps its files in, once it has made one.
+static STAGING: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+// A file that has been fully uploaded and is waiting for its recipients.
+// - `pending`: addresses of recipients who haven't accepted or declined yet
+// - `downloads`: how many downloads of it are running right now
+pub struct Transfer {
+    sender: String,
+    name: String,
+    size: u64,
+    path: PathBuf,
+    pending: HashSet<String>,
+    downloads: usize,
+}
+
+// An upload that is still arriving.
+struct Upload {
+    xfer: u64,
+    recipients: HashSet<String>,
+    name: String,
+    size: u64,
+    sha256: String,
+    received: u64,
+    path: PathBuf,
+    file: File,
+    hasher: Sha256,
+}
+
+// The uploads one client has in progress, by the token it picked.
+// Each `handle_client` task keeps its own `Uploads` - nobody else needs to see
+// half-finished files, and this way writing to disk never happens while
+// holding the shared lock.
+pub struct Uploads {
+    uploads: HashMap<u64, Upload>,
+}
+
+impl Uploads {
+    pub fn new() -> Self {
+        Uploads {
+            uploads: HashMap::new(),
+        }
+    }
+
+    // Is this line part of an upload? Those lines can be very long, so the
+    // caller uses this to skip printing them.
+    pub fn is_upload_line(text: &str) -> bool {
+        text.starts_with("/upload ") || text.starts_with("/chunk ") || text.starts_with("/end ")
+    }
+
+    // Handle one upload line from the client at `addr`.
+    pub async fn handle(&mut self, text: &str, addr: &str, db: &Db) {
+        let (command, args) = text.split_once(' ').unwrap_or((text, ""));
+        // Every upload line starts with the token. If even that is missing
+        // we can't tell the client which upload went wrong.
+        let (token, args) = args.split_once(' ').unwrap_or((args, ""));
+        let Ok(token) = token.parse::<u64>() else {
+            db.lock()
+                .await
+                .send_to(addr, Frame::Error(format!("usage: {} <token> ...", command)));
+            return;
+        };
+
+        let result = match command {
+            "/upload" => self.start(token, args, addr, db).await,
+            "/chunk" => self.chunk(token, args).await,
+            _ => self.end(token, addr, db).await,
+        };
+
+        // Anything that went wrong cancels the upload and tells the sender why,
+        // so they can stop sending chunks.
+        if let Err(reason) = result {
+            self.abort(token, db).await;
+            db.lock().await.send_to(addr, Frame::Reject { token, reason });
+        }
+    }
+
+    // `/upload <token> <nick|#room> <size> <sha256> <name>`
+    async fn start(&mut self, token: u64, args: &str, addr: &str, db: &Db) -> Result<(), String> {
+        let mut parts = args.splitn(4, ' ');
+        let (Some(target), Some(size), Some(sha256), Some(name)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("usage: /upload <token> <nick|#room> <size> <sha256> <name>".to_string());
+        };
+        let size: u64 = size.parse().map_err(|_| "invalid size".to_string())?;
+        // `--max-file-size`, which `/reload` may change.
+        let max_file_size = db.lock().await.config.max_file_size;
+        if size > max_file_size {
+            return Err(format!("files are limited to {} bytes", max_file_size));
+        }
+        // A SHA-256 written in hex is always 64 characters long.
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err("invalid sha256".to_string());
+        }
+        if self.uploads.contains_key(&token) {
+            return Err(format!("upload {} is already in progress", token));
+        }
+        if self.uploads.len() >= MAX_UPLOADS {
+            return Err(format!(
+                "you can send at most {} files at the same time",
+                MAX_UPLOADS
+            ));
+        }
+
+        // Work out who should get the file, make room for it and reserve a
+        // transfer ID. We hold the lock only for this short moment.
+        let (xfer, recipients) = {
+            let mut state = db.lock().await;
+            let recipients = recipients(&state, addr, target)?;
+            if state.stored_bytes + size > max_file_size * MAX_STORED_FILES {
+                return Err("the server is storing too many files - try again later".to_string());
+            }
+            state.stored_bytes += size;
+            let xfer = state.next_xfer;
+            state.next_xfer += 1;
+            (xfer, recipients)
+        };
+
+        // `create_new` refuses to open a file that's already there, so
+        // nothing anybody else put in our folder can be written over.
+        let file = match staging_dir() {
+            Ok(dir) => {
+                let path = dir.join(format!("xfer-{}", xfer));
+                let mut options = OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                options.mode(0o600);
+                options.open(&path).await.map(|file| (path, file))
+            }
+            Err(e) => Err(e),
+        };
+        let (path, file) = match file {
+            Ok(opened) => opened,
+            Err(e) => {
+                release(&mut *db.lock().await, size);
+                return Err(format!("server can't store files: {}", e));
+            }
+        };
+
+        self.uploads.insert(
+            token,
+            Upload {
+                xfer,
+                recipients,
+                name: name.to_string(),
+                size,
+                sha256: sha256.to_ascii_lowercase(),
+                received: 0,
+                path,
+                file,
+                hasher: Sha256::new(),
+            },
+        );
+        Ok(())
+    }
+
+    // `/chunk <token> <base64>`
+    async fn chunk(&mut self, token: u64, data: &str) -> Result<(), String> {
+        let upload = self
+            .uploads
+            .get_mut(&token)
+            .ok_or(format!("no upload {} in progress", token))?;
+        let bytes = base64_decode(data.trim()).ok_or("invalid base64".to_string())?;
+        upload.received += bytes.len() as u64;
+        if upload.received > upload.size {
+            return Err("more data than announced".to_string());
+        }
+        // The fingerprint is built up piece by piece as the data arrives,
+        // so we never need the whole file in memory.
+        upload.hasher.update(&bytes);
+        upload
+            .file
+            .write_all(&bytes)
+            .await
+            .map_err(|e| format!("server can't store files: {}", e))
+    }
+
+    // `/end <token>` - the upload is complete. Check it and offer it around.
+    async fn end(&mut self, token: u64, addr: &str, db: &Db) -> Result<(), String> {
+        // `remove` takes the upload out of the map - it's finished either way.
+        let mut upload = self
+            .uploads
+            .remove(&token)
+            .ok_or(format!("no upload {} in progress", token))?;
+        // Make sure everything we wrote has actually reached the disk.
+        let _ = upload.file.flush().await;
+
+        // `{:x}` prints the fingerprint as lowercase hex.
+        let sha256 = format!("{:x}", upload.hasher.clone().finalize());
+        let problem = if upload.received != upload.size {
+            Some(format!("got {} of {} bytes", upload.received, upload.size))
+        } else if sha256 != upload.sha256 {
+            Some("sha256 doesn't match, the file was damaged on the way".to_string())
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            let _ = tokio::fs::remove_file(&upload.path).await;
+            release(&mut *db.lock().await, upload.size);
+            return Err(problem);
+        }
+
+        let mut state = db.lock().await;
+        let sender = state.nick(addr);
+        let offer = Frame::Offer {
+            xfer: upload.xfer,
+            sender: sender.clone(),
+            size: upload.size,
+            sha256,
+            name: upload.name.clone(),
+        };
+        // Only offer it to recipients who are still connected.
+        upload.recipients.retain(|r| state.clients.contains_key(r));
+        for recipient in &upload.recipients {
+            state.send_to(recipient, offer.clone());
+        }
+        state.send_to(
+            addr,
+            Frame::Uploaded {
+                token,
+                xfer: upload.xfer,
+            },
+        );
+        println!(
+            "{} uploaded {} ({} bytes) for {} recipient(s)",
+            sender,
+            upload.name,
+            upload.size,
+            upload.recipients.len()
+        );
+        state.transfers.insert(
+            upload.xfer,
+            Transfer {
+                sender,
+                name: upload.name,
+                size: upload.size,
+                path: upload.path,
+                pending: upload.recipients,
+                downloads: 0,
+            },
+        );
+        // Maybe nobody was left to offer it to.
+        cleanup(&mut state, upload.xfer).await;
+        drop(state);
+
+        // Throw the file away if recipients take too long to answer.
+        let db = db.clone();
+        let xfer = upload.xfer;
+        tokio::spawn(async move {
+            tokio::time::sleep(OFFER_TIMEOUT).await;
+            let mut state = db.lock().await;
+            if let Some(transfer) = state.transfers.get_mut(&xfer) {
+                transfer.pending.clear();
+                cleanup(&mut state, xfer).await;
+            }
+        });
+        Ok(())
+    }
+
+    // Forget an upload and delete what we'd stored of it.
+    async fn abort(&mut self, token: u64, db: &Db) {
+        if let Some(upload) = self.uploads.remove(&token) {
+            // Close the file before deleting it (required on Windows).
+            drop(upload.file);
+            let _ = tokio::fs::remove_file(&upload.path).await;
+            release(&mut *db.lock().await, upload.size);
+        }
+    }
+
+    // Called when the client disconnects: throw away any half-finished uploads.
+    pub async fn abort_all(&mut self, db: &Db) {
+        // Collect the tokens first, because `abort` changes the map.
+        let tokens: Vec<u64> = self.uploads.keys().copied().collect();
+        for token in tokens {
+            self.abort(token, db).await;
+        }
+    }
+}
+
+// Work out the addresses a file for `target` should go to.
+// `target` is a nick or a room the sender is in.
+fn recipients(state: &State, addr: &str, target: &str) -> Result<HashSet<String>, String> {
+    if target.starts_with('#') {
+        if !state.clients.get(addr).is_some_and(|c| c.rooms.contains(target)) {
+            return Err(format!("you're not in {}", target));
+        }
+        // Everyone in the room except the sender.
+        Ok(state
+            .clients
+            .iter()
+            .filter(|(a, c)| a.as_str() != addr && c.rooms.contains(target))
+            .map(|(a, _)| a.clone())
+            .collect())
+    } else {
+        match state.find_nick(target) {
+            Some(recipient) if recipient != addr => Ok(HashSet::from([recipient])),
+            Some(_) => Err("you can't send a file to yourself".to_string()),
+            None => Err(format!("no one called {} is online", target)),
+        }
+    }
+}
+
+// `/accept <xfer>` (when `accept` is true) or `/decline <xfer>`.
+// `db` is only needed so the download task can lock the state again later.
+pub fn answer(state: &mut State, db: &Db, addr: &str, xfer: u64, accept: bool) -> Result<(), String> {
+    let transfer = state
+        .transfers
+        .get_mut(&xfer)
+        .filter(|t| t.pending.contains(addr))
+        .ok_or(format!("no file #{} waiting for you", xfer))?;
+    transfer.pending.remove(addr);
+
+    let sender = transfer.sender.clone();
+    let name = transfer.name.clone();
+    let nick = state.nick(addr);
+    // Let the sender know what happened to their file.
+    if let Some(sender_addr) = state.find_nick(&sender) {
+        let verb = if accept { "accepted" } else { "declined" };
+        state.send_to(&sender_addr, Frame::Info(format!("{} {} {}", nick, verb, name)));
+    }
+
+    if !accept {
+        // The file might not be needed anymore. Deleting it is async, and we
+        // don't want to wait for the disk while holding the lock, so we let
+        // a separate task do it.
+        if let Some(path) = finished(state, xfer) {
+            tokio::spawn(tokio::fs::remove_file(path));
+        }
+        return Ok(());
+    }
+
+    // We checked above that the transfer exists.
+    let Some(transfer) = state.transfers.get_mut(&xfer) else {
+        return Ok(());
+    };
+    transfer.downloads += 1;
+    let path = transfer.path.clone();
+    let Some(bulk) = state.clients.get(addr).map(|c| c.bulk.clone()) else {
+        return Ok(());
+    };
+    // Stream the file in its own task so the lock is released right away.
+    tokio::spawn(download(xfer, path, bulk, db.clone()));
+    Ok(())
+}
+
+// Send a stored file to one recipient through their `bulk` channel.
+async fn download(xfer: u64, path: PathBuf, bulk: mpsc::Sender<Frame>, db: Db) {
+    if let Err(e) = stream(xfer, &path, &bulk).await {
+        println!("Error sending file #{}: {}", xfer, e);
+    }
+    let mut state = db.lock().await;
+    if let Some(transfer) = state.transfers.get_mut(&xfer) {
+        transfer.downloads -= 1;
+    }
+    cleanup(&mut state, xfer).await;
+}
+
+// Read the file in CHUNK_SIZE pieces and send each one as a CHUNK frame.
+async fn stream(xfer: u64, path: &std::path::Path, bulk: &mpsc::Sender<Frame>) -> std::io::Result<()> {
+    let mut file = File::open(path).await?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        let frame = Frame::Chunk {
+            xfer,
+            data: base64_encode(&buf[..n]),
+        };
+        // `send` waits while the channel is full - that's how a slow
+        // recipient slows the download down instead of filling our memory.
+        // It only fails if the client has disconnected.
+        if bulk.send(frame).await.is_err() {
+            return Ok(());
+        }
+    }
+    let _ = bulk.send(Frame::Done { xfer }).await;
+    Ok(())
+}
+
+// If nobody is waiting for or downloading `xfer` anymore, forget it and
+// delete its file.
+pub async fn cleanup(state: &mut State, xfer: u64) {
+    if let Some(path) = finished(state, xfer) {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+}
+
+// The non-async half of `cleanup`: remove the transfer if it's finished and
+// return the path of the file that should be deleted.
+fn finished(state: &mut State, xfer: u64) -> Option<PathBuf> {
+    let transfer = state.transfers.get(&xfer)?;
+    if transfer.pending.is_empty() && transfer.downloads == 0 {
+        let transfer = state.transfers.remove(&xfer)?;
+        release(state, transfer.size);
+        Some(transfer.path)
+    } else {
+        None
+    }
+}
+
+// `bytes` of files are no longer stored.
+fn release(state: &mut State, bytes: u64) {
+    state.stored_bytes = state.stored_bytes.saturating_sub(bytes);
+}
+
+// The folder for this server's files, made the first time it's needed.
+// The system's temporary folder (e.g. /tmp on Linux) is shared with every
+// other user and program, so we don't simply use a fixed name in it: someone
+// could have put a file or link there already, and two servers on the same
+// machine would write over each other's files. Instead each server makes a
+// folder of its own, with its process ID in the name, that only the user
+// running it may open (mode 0700 on Unix). `create_dir` fails if the folder
+// already exists, so we never use one somebody else made - we try the next
+// name instead.
+fn staging_dir() -> std::io::Result<PathBuf> {
+    let mut staging = STAGING.lock().unwrap();
+    if let Some(dir) = &*staging {
+        return Ok(dir.clone());
+    }
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    for attempt in 0.. {
+        let name = format!("chatty_rusty-{}-{}", std::process::id(), attempt);
+        let dir = std::env::temp_dir().join(name);
+        match builder.create(&dir) {
+            Ok(()) => {
+                *staging = Some(dir.clone());
+                return Ok(dir);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("we keep trying names until one works")
+}
+
+// Delete this server's folder and everything still in it, e.g. just before
+// handing over to a new server that won't know about the files.
+pub fn remove_staging_dir() {
+    if let Some(dir) = STAGING.lock().unwrap().take() {
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,113 @@
+// What each person has read: a "read marker" per room, the ID of the last
+// message they've seen there. Everything after it is unread.
+//
+// - The client moves the marker with `/read <#room> <id>` as it shows messages.
+// - Posting moves it too - your own messages don't need reading.
+// - Joining a room for the first time puts it at the latest message, so only
+//   what's said from then on can be unread.
+// - `/rooms` shows how many messages are unread in each room.
+// - `/unread` sends the unread messages of every room you're in again, so a
+//   client can pick up where its user left off.
+//
+// Markers belong to a nick rather than a connection, so they're still there
+// after reconnecting. Until a client picks a nick it's known by its address,
+// which changes with every connection, so those markers are dropped once the
+// nick changes or the client leaves.
+
+use std::collections::{HashMap, VecDeque};
+
+use serde_json::{Value, json};
+
+use chatty_rusty::protocol::ChatMessage;
+
+use crate::state::Stored;
+
+// For each nick, the last message read in each room.
+#[derive(Default)]
+pub struct ReadMarkers {
+    by_nick: HashMap<String, HashMap<String, u64>>,
+}
+
+impl ReadMarkers {
+    // `nick` is in `room`: start a marker at `latest` unless there's one already.
+    pub fn start(&mut self, nick: &str, room: &str, latest: u64) {
+        self.by_nick
+            .entry(nick.to_string())
+            .or_default()
+            .entry(room.to_string())
+            .or_insert(latest);
+    }
+
+    // `nick` has read up to `id` in `room`. Markers only ever move forwards,
+    // so a marker arriving late can't make old messages unread again.
+    pub fn mark(&mut self, nick: &str, room: &str, id: u64) {
+        let marker = self
+            .by_nick
+            .entry(nick.to_string())
+            .or_default()
+            .entry(room.to_string())
+            .or_insert(0);
+        *marker = (*marker).max(id);
+    }
+
+    // Forget every marker of `nick`.
+    pub fn forget(&mut self, nick: &str) {
+        self.by_nick.remove(nick);
+    }
+
+    // All the markers as JSON, to hand them over on a restart (see `handoff.rs`).
+    pub fn to_json(&self) -> Value {
+        json!(self.by_nick)
+    }
+
+    // The markers `to_json` wrote. Anything that doesn't look right is skipped.
+    pub fn from_json(value: &Value) -> ReadMarkers {
+        let mut markers = ReadMarkers::default();
+        for (nick, rooms) in value.as_object().into_iter().flatten() {
+            for (room, id) in rooms.as_object().into_iter().flatten() {
+                if let Some(id) = id.as_u64() {
+                    markers.mark(nick, room, id);
+                }
+            }
+        }
+        markers
+    }
+
+    // The rooms `nick` has a marker in, with how many messages in the
+    // history are unread there.
+    pub fn counts(&self, nick: &str, history: &VecDeque<Stored>) -> HashMap<String, usize> {
+        let Some(markers) = self.by_nick.get(nick) else {
+            return HashMap::new();
+        };
+        let mut counts: HashMap<String, usize> =
+            markers.keys().map(|room| (room.clone(), 0)).collect();
+        for stored in history {
+            if let Some(marker) = markers.get(&stored.msg.room)
+                && stored.msg.id > *marker
+            {
+                *counts.entry(stored.msg.room.clone()).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    // The unread messages of `nick` in the rooms for which `wanted` says yes,
+    // oldest first.
+    pub fn messages(
+        &self,
+        nick: &str,
+        history: &VecDeque<Stored>,
+        wanted: impl Fn(&str) -> bool,
+    ) -> Vec<ChatMessage> {
+        let Some(markers) = self.by_nick.get(nick) else {
+            return Vec::new();
+        };
+        history
+            .iter()
+            .map(|stored| &stored.msg)
+            .filter(|msg| wanted(&msg.room))
+            .filter(|msg| markers.get(&msg.room).is_some_and(|marker| msg.id > *marker))
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,382 @@
+// Telling other programs what happens in the chat ("webhooks").
+//
+// A webhook is a web address the server POSTs a small JSON document to
+// whenever something happens - a bot, a logger or a notification service
+// listening there can then react to it. The hooks are listed in a file given
+// with `--webhooks FILE`, one per line:
+//
+//   URL [EVENTS] [ROOMS]
+//
+//   http://127.0.0.1:9000/everything
+//   http://127.0.0.1:9000/pager       mention
+//   http://127.0.0.1:9000/ops         join,leave   #ops,#admin
+//
+// EVENTS and ROOMS are comma separated lists, and `*` (or leaving them out)
+// means all of them. The events are:
+// - `message`: a chat message was posted
+// - `mention`: a chat message mentions someone with `@nick`
+// - `edit`: the text of a chat message was changed
+// - `join` / `leave`: someone on this server joined or left a room
+// Empty lines and lines starting with `//` are ignored.
+//
+// Every request carries the event in an `X-Chatty-Event` header, and the
+// time it was sent (seconds since 1970) in `X-Chatty-Timestamp`. If
+// CHATTY_WEBHOOK_SECRET is set, it also carries
+//
+//   X-Chatty-Signature: sha256=<HMAC-SHA256 of "<timestamp>.<body>", in hex>
+//
+// so the receiver can check the request really came from us: it computes the
+// same HMAC with the same secret and compares. The timestamp is signed too,
+// so the receiver can also refuse requests that are more than a few minutes
+// old - someone who recorded a request can't send it again later.
+//
+// A hook that doesn't answer with a 2xx status is tried again after 1 second,
+// then 2, 4 and 8. If the 5th attempt fails too - or the hook answers with a
+// 4xx status, meaning trying again won't help - the event is written to
+// DEAD_LETTERS so nothing is lost without a trace. Each hook is sent its events
+// in order, one at a time, by a task of its own, so a slow hook never holds up
+// the chat or the other hooks. A hook's queue holds at most QUEUE_LIMIT
+// events: while it's full - the hook is down and a busy chat keeps talking -
+// new events go straight to DEAD_LETTERS instead of piling up in memory.
+
+use std::collections::BTreeSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use serde_json::{Value, json};
+use sha2::Sha256;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use chatty_rusty::protocol::{ChatMessage, now_rfc3339};
+
+use crate::commands::valid_room;
+use crate::http::{self, Url};
+
+// Every event a hook can ask for.
+const EVENTS: &[&str] = &["message", "mention", "edit", "join", "leave"];
+
+// The environment variable holding the secret requests are signed with.
+const SECRET_VAR: &str = "CHATTY_WEBHOOK_SECRET";
+
+// Where events that couldn't be delivered are written, one JSON object per line.
+const DEAD_LETTERS: &str = "webhook-dead-letters.log";
+
+// How many times we try to deliver one event, and how long we wait before
+// the first retry. Each retry waits twice as long as the one before.
+const MAX_ATTEMPTS: u32 = 5;
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+
+// How many events may wait for one hook, and for the dead letters file.
+const QUEUE_LIMIT: usize = 1000;
+
+// The events or rooms a hook wants. None means all of them.
+type Filter = Option<BTreeSet<String>>;
+
+// One event on its way to a hook.
+struct Delivery {
+    event: &'static str,
+    payload: Value,
+}
+
+// One event that couldn't be delivered, on its way to DEAD_LETTERS.
+struct DeadLetter {
+    address: String,
+    delivery: Delivery,
+    attempts: u32,
+    error: String,
+}
+
+// One line of the webhooks file.
+// - `address`: the hook's URL, for the log and the dead letters.
+// - `events` / `rooms`: what the hook wants.
+// - `tx`: the queue of the hook's delivery task.
+// - `dead`: the queue of the task writing DEAD_LETTERS.
+struct Hook {
+    address: String,
+    events: Filter,
+    rooms: Filter,
+    tx: mpsc::Sender<Delivery>,
+    dead: mpsc::Sender<DeadLetter>,
+}
+
+impl Hook {
+    fn wants(&self, event: &str, room: &str) -> bool {
+        self.events
+            .as_ref()
+            .is_none_or(|events| events.contains(event))
+            && self.rooms.as_ref().is_none_or(|rooms| rooms.contains(room))
+    }
+}
+
+// All the hooks. `Default` gives us "no hooks at all", for when there's
+// no `--webhooks` file.
+#[derive(Default)]
+pub struct Webhooks {
+    hooks: Vec<Hook>,
+}
+
+impl Webhooks {
+    // Read the webhooks file and start a delivery task for every hook in it.
+    pub fn load(path: &str) -> Result<Webhooks, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+        let secret = std::env::var(SECRET_VAR).ok();
+        let (dead, dead_rx) = mpsc::channel(QUEUE_LIMIT);
+        tokio::spawn(write_dead_letters(dead_rx));
+        let mut hooks = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            let (url, events, rooms) =
+                parse_line(line).map_err(|e| format!("{} line {}: {}", path, number + 1, e))?;
+            let address = format!("http://{}:{}{}", url.host, url.port, url.path);
+            let (tx, rx) = mpsc::channel(QUEUE_LIMIT);
+            tokio::spawn(deliver(url, secret.clone(), rx, dead.clone()));
+            hooks.push(Hook {
+                address,
+                events,
+                rooms,
+                tx,
+                dead: dead.clone(),
+            });
+        }
+        println!("Loaded {} webhook(s) from {}", hooks.len(), path);
+        Ok(Webhooks { hooks })
+    }
+
+    pub fn len(&self) -> usize {
+        self.hooks.len()
+    }
+
+    // A chat message was posted: the `message` event, and `mention` too if
+    // it mentions anybody.
+    pub fn message(&self, msg: &ChatMessage) {
+        let payload = || {
+            json!({
+                "id": msg.id,
+                "time": msg.time,
+                "room": msg.room,
+                "sender": msg.sender,
+                "origin": msg.origin,
+                "text": msg.text,
+                "reply_to": msg.reply_to,
+            })
+        };
+        self.emit("message", &msg.room, payload);
+        if !msg.mentions.is_empty() {
+            self.emit("mention", &msg.room, || {
+                let mut payload = payload();
+                payload["mentioned"] = json!(msg.mentions);
+                payload
+            });
+        }
+    }
+
+    // The text of a message was changed. `msg` is the message as it is now,
+    // and the event's time is when it was changed.
+    pub fn edited(&self, msg: &ChatMessage) {
+        self.emit("edit", &msg.room, || {
+            json!({
+                "id": msg.id,
+                "time": now_rfc3339(),
+                "room": msg.room,
+                "sender": msg.sender,
+                "origin": msg.origin,
+                "text": msg.text,
+            })
+        });
+    }
+
+    // Someone joined a room.
+    pub fn joined(&self, nick: &str, room: &str) {
+        self.emit(
+            "join",
+            room,
+            || json!({ "time": now_rfc3339(), "room": room, "nick": nick }),
+        );
+    }
+
+    // Someone left a room.
+    pub fn left(&self, nick: &str, room: &str) {
+        self.emit(
+            "leave",
+            room,
+            || json!({ "time": now_rfc3339(), "room": room, "nick": nick }),
+        );
+    }
+
+    // Queue `event` for every hook that wants it. The payload is only built
+    // if at least one does, and it gets an `event` field naming the event.
+    fn emit(&self, event: &'static str, room: &str, payload: impl FnOnce() -> Value) {
+        let mut hooks = self
+            .hooks
+            .iter()
+            .filter(|hook| hook.wants(event, room))
+            .peekable();
+        if hooks.peek().is_none() {
+            return;
+        }
+        let mut payload = payload();
+        payload["event"] = json!(event);
+        for hook in hooks {
+            let delivery = Delivery {
+                event,
+                payload: payload.clone(),
+            };
+            // `try_send` doesn't wait: if the queue is full, we get the
+            // event back. (It's never closed - the delivery task runs as
+            // long as the hook exists.)
+            if let Err(TrySendError::Full(delivery)) = hook.tx.try_send(delivery) {
+                let letter = DeadLetter {
+                    address: hook.address.clone(),
+                    delivery,
+                    attempts: 0,
+                    error: format!("{} events were already waiting", QUEUE_LIMIT),
+                };
+                if hook.dead.try_send(letter).is_err() {
+                    println!("Webhook {} is too far behind - dropped a {} event", hook.address, event);
+                }
+            }
+        }
+    }
+}
+
+// Read one `URL [EVENTS] [ROOMS]` line.
+fn parse_line(line: &str) -> Result<(Url, Filter, Filter), String> {
+    let mut words = line.split_whitespace();
+    let url = Url::parse(words.next().unwrap_or_default())?;
+    let events = parse_list(words.next(), |event| EVENTS.contains(&event), "event")?;
+    let rooms = parse_list(words.next(), valid_room, "room")?;
+    if words.next().is_some() {
+        return Err("too many words - expected URL [EVENTS] [ROOMS]".to_string());
+    }
+    Ok((url, events, rooms))
+}
+
+// Read a comma separated list, checking each item with `valid`.
+// A missing list or `*` means "everything" and gives None.
+fn parse_list(
+    list: Option<&str>,
+    valid: impl Fn(&str) -> bool,
+    what: &str,
+) -> Result<Filter, String> {
+    match list {
+        None | Some("*") => Ok(None),
+        Some(list) => list
+            .split(',')
+            .map(|item| match valid(item) {
+                true => Ok(item.to_string()),
+                false => Err(format!("unknown {} {}", what, item)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Some),
+    }
+}
+
+// A hook's delivery task: send it every queued event, in order.
+async fn deliver(
+    url: Url,
+    secret: Option<String>,
+    mut rx: mpsc::Receiver<Delivery>,
+    dead: mpsc::Sender<DeadLetter>,
+) {
+    let address = format!("http://{}:{}{}", url.host, url.port, url.path);
+    while let Some(delivery) = rx.recv().await {
+        let body = delivery.payload.to_string();
+
+        let mut delay = FIRST_RETRY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            // Every attempt is signed with the time it's sent at.
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+                .to_string();
+            let mut headers = vec![
+                ("X-Chatty-Event", delivery.event.to_string()),
+                ("X-Chatty-Timestamp", timestamp.clone()),
+            ];
+            if let Some(secret) = &secret {
+                headers.push(("X-Chatty-Signature", sign(secret, &timestamp, &body)));
+            }
+            let (error, retry) = match http::post(&url, &headers, &body).await {
+                Ok(status) if (200..300).contains(&status) => break,
+                // 408 (timeout) and 429 (too many requests) are worth another try.
+                Ok(status) => (
+                    format!("answered {}", status),
+                    !(400..500).contains(&status) || status == 408 || status == 429,
+                ),
+                Err(e) => (e, true),
+            };
+            if !retry || attempt == MAX_ATTEMPTS {
+                println!(
+                    "Webhook {} gave up on a {} event: {}",
+                    address, delivery.event, error
+                );
+                // Waits for room in the queue if need be: this hook's
+                // events can wait, the chat isn't held up by it.
+                let _ = dead
+                    .send(DeadLetter {
+                        address: address.clone(),
+                        delivery,
+                        attempts: attempt,
+                        error,
+                    })
+                    .await;
+                break;
+            }
+            println!(
+                "Webhook {} {} - trying again in {}s",
+                address,
+                error,
+                delay.as_secs()
+            );
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+}
+
+// The HMAC-SHA256 of "<timestamp>.<body>" with `secret`, as `sha256=<hex>`.
+fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    // An HMAC key can be any length, so this never fails.
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key length works");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+// Write the events we couldn't deliver to the end of DEAD_LETTERS, one at a
+// time, so two lines never get mixed up.
+async fn write_dead_letters(mut rx: mpsc::Receiver<DeadLetter>) {
+    while let Some(letter) = rx.recv().await {
+        dead_letter(letter).await;
+    }
+}
+
+// Write one event we couldn't deliver to the end of DEAD_LETTERS.
+async fn dead_letter(letter: DeadLetter) {
+    let line = json!({
+        "failed_at": now_rfc3339(),
+        "url": letter.address,
+        "attempts": letter.attempts,
+        "error": letter.error,
+        "payload": letter.delivery.payload,
+    });
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(DEAD_LETTERS)
+        .await;
+    let result = match file {
+        Ok(mut file) => file.write_all(format!("{}\n", line).as_bytes()).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        println!("Can't write to {}: {}", DEAD_LETTERS, e);
+    }
+}
@@ -0,0 +1,9 @@
+// This is the library part of Chatty Rusty.
+// Both binaries (`server` and `client`) live in `src/bin/`, and anything
+// they BOTH need goes in here so we only write it once.
+// A binary can use this code with `use chatty_rusty::...;` - Cargo
+// automatically makes the library available to every binary in the package.
+
+// `pub mod` declares a module and makes it visible outside the library.
+// Rust looks for the module's code in `src/protocol.rs`.
+pub mod protocol;
@@ -0,0 +1,755 @@
+// The protocol is the set of rules the server and the client agree on,
+// so that each side understands the lines of text the other one sends.
+//
+// Everything still travels as one line of text ending in `\n`, but the
+// server no longer sends "free form" text. Each line starts with a KIND word
+// that tells the client what the rest of the line means:
+//
+//   MSG <id> <timestamp> <sender> <tags> <text>   a chat message accepted by the server
+//   EDIT <id> <text>                              message <id> now says <text>
+//   DEL <id>                                      message <id> was deleted
+//   REACT <id> <emoji>=<count> ...                the reactions on message <id> are now these
+//   THREAD <root> <count>                         the next <count> MSG lines are thread <root>
+//   OFFER <xfer> <sender> <size> <sha256> <name>  someone wants to send you a file
+//   CHUNK <xfer> <base64>                         the next piece of a file you accepted
+//   DONE <xfer>                                   the whole file has been sent
+//   UPLOADED <token> <xfer>                       your upload <token> arrived safely
+//   REJECT <token> <reason>                       your upload <token> was refused
+//   JOIN <nick> <room>                            someone joined a room you're in
+//   PART <nick> <room> <reason>                   someone left it (the reason is optional)
+//   NICK <old> <new>                              someone you share a room with changed nick
+//   INFO <text>                                   a notice from the server itself
+//   ERR <text>                                    a command failed
+//   PONG <token>                                  the answer to `/ping <token>`
+//   PRIV <timestamp> <from> <to> <text>           a private message, sent to both people
+//   TYPING <nick> <room>                          someone in <room> is typing (never stored)
+//   KEY <nick> <key>                              the answer to `/key <nick>`: their public key, or `-`
+//   TOPIC <room> <text>                           the topic of a room you just joined
+//   WELCOME <server> <version> <users>            the first line after connecting
+//   MOTD <text>                                   a line of the message of the day
+//
+// The free text always comes LAST, so it can safely contain spaces.
+//
+// `<tags>` carries extra facts about a message as a comma separated list,
+// e.g. "room=#general,edited,reply=12,origin=office2,mention=bob". When there are none
+// it is a single `-` so the number of fields never changes and older fields
+// never move around.
+
+// `SystemTime` is the wall clock time of the machine and `UNIX_EPOCH` is
+// the 1st of January 1970 - the moment computers traditionally count from.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A single chat message as the server stored it.
+// `#[derive(Clone, Debug)]` asks the compiler to write two traits for us:
+// - `Clone` lets us make a full copy with `.clone()` (we keep one copy in
+//   the history and send another one to the clients)
+// - `Debug` lets us print it with `{:?}` which is handy while learning
+#[derive(Clone, Debug)]
+pub struct ChatMessage {
+    // A number assigned by the server. Every new message gets the previous
+    // number + 1, so IDs always increase and never repeat.
+    pub id: u64,
+    // When the server accepted the message, in RFC 3339 format
+    // e.g. "2026-10-18T09:41:07Z". The `Z` at the end means UTC.
+    pub time: String,
+    // Who sent it.
+    pub sender: String,
+    // The room it was said in, e.g. "#general".
+    pub room: String,
+    // What they said, without the trailing newline.
+    pub text: String,
+    // `true` once the author (or an operator) has changed the text.
+    pub edited: bool,
+    // The ID of the message this one answers, if it's a reply.
+    // `Option` means there may or may not be a value: `Some(12)` or `None`.
+    pub reply_to: Option<u64>,
+    // The name of the server the sender is connected to, if that's not the
+    // server we're talking to - i.e. the message came over a server link.
+    pub origin: Option<String>,
+    // The nicks it mentions with `@nick`, worked out by the server - one
+    // `mention=<nick>` tag each.
+    pub mentions: Vec<String>,
+}
+
+impl ChatMessage {
+    // Build the `<tags>` field for this message.
+    fn tags(&self) -> String {
+        let mut tags = Vec::new();
+        if !self.room.is_empty() {
+            tags.push(format!("room={}", self.room));
+        }
+        if self.edited {
+            tags.push("edited".to_string());
+        }
+        if let Some(parent) = self.reply_to {
+            tags.push(format!("reply={}", parent));
+        }
+        if let Some(origin) = &self.origin {
+            tags.push(format!("origin={}", origin));
+        }
+        for nick in &self.mentions {
+            tags.push(format!("mention={}", nick));
+        }
+        // `join` glues the pieces together with a comma in between.
+        if tags.is_empty() { "-".to_string() } else { tags.join(",") }
+    }
+}
+
+// An `enum` is a type that can be exactly ONE of several variants.
+// Every line the server sends is one of these.
+#[derive(Clone, Debug)]
+pub enum Frame {
+    Msg(ChatMessage),
+    // The fields are named so it's clear which is which.
+    Edit { id: u64, text: String },
+    Delete { id: u64 },
+    // Always carries the FULL list of reactions on the message, not just the
+    // one that changed. That way a client can simply replace what it had.
+    React { id: u64, counts: Vec<(String, usize)> },
+    // Announces that the next `count` MSG frames are a whole thread,
+    // sent because the client asked for it with `/thread`.
+    Thread { root: u64, count: usize },
+    // File transfers. `xfer` is the ID the server gives a stored file,
+    // `token` is the number the uploading client picked for its upload.
+    Offer {
+        xfer: u64,
+        sender: String,
+        size: u64,
+        sha256: String,
+        name: String,
+    },
+    Chunk { xfer: u64, data: String },
+    Done { xfer: u64 },
+    Uploaded { token: u64, xfer: u64 },
+    Reject { token: u64, reason: String },
+    // Who's in which room. Clients can keep a member list from these.
+    // `reason` is empty when somebody simply left.
+    Join { nick: String, room: String },
+    Part { nick: String, room: String, reason: String },
+    Nick { old: String, new: String },
+    Info(String),
+    Error(String),
+    // Sent once everything the client sent before `/ping <token>` is done.
+    Pong(String),
+    // A private message between two people. It isn't part of any room, so it
+    // has no ID and isn't kept in the history.
+    Private {
+        time: String,
+        from: String,
+        to: String,
+        text: String,
+    },
+    // `nick` is typing something for `room`. It only says "right now", so
+    // like `Private` it's never stored - a client forgets it after a while.
+    Typing { nick: String, room: String },
+    // The public key `nick` published for end-to-end encrypted private
+    // messages, in base64 - None if they have none (e.g. an IRC user).
+    Key { nick: String, key: Option<String> },
+    // What `room` is about, sent to you when you join it (if it has a topic).
+    Topic { room: String, topic: String },
+    // Sent first thing after connecting: the server's name and version, and
+    // how many people are connected to it (you included).
+    Welcome {
+        server: String,
+        version: String,
+        users: usize,
+    },
+    // One line of the message of the day. It follows the welcome.
+    Motd(String),
+}
+
+impl Frame {
+    // Turn a frame into the exact line we write to the socket,
+    // including the `\n` that marks the end of the line.
+    pub fn to_line(&self) -> String {
+        // `match` on `self` picks the branch for whichever variant this is.
+        match self {
+            Frame::Msg(m) => format!(
+                "MSG {} {} {} {} {}\n",
+                m.id,
+                m.time,
+                m.sender,
+                m.tags(),
+                m.text
+            ),
+            Frame::Edit { id, text } => format!("EDIT {} {}\n", id, text),
+            Frame::Delete { id } => format!("DEL {}\n", id),
+            Frame::React { id, counts } => {
+                // Start with "REACT <id>" and add one " <emoji>=<count>" per reaction.
+                let mut line = format!("REACT {}", id);
+                for (emoji, count) in counts {
+                    line.push_str(&format!(" {}={}", emoji, count));
+                }
+                line.push('\n');
+                line
+            }
+            Frame::Thread { root, count } => format!("THREAD {} {}\n", root, count),
+            Frame::Offer {
+                xfer,
+                sender,
+                size,
+                sha256,
+                name,
+            } => format!("OFFER {} {} {} {} {}\n", xfer, sender, size, sha256, name),
+            Frame::Chunk { xfer, data } => format!("CHUNK {} {}\n", xfer, data),
+            Frame::Done { xfer } => format!("DONE {}\n", xfer),
+            Frame::Uploaded { token, xfer } => format!("UPLOADED {} {}\n", token, xfer),
+            Frame::Reject { token, reason } => format!("REJECT {} {}\n", token, reason),
+            Frame::Join { nick, room } => format!("JOIN {} {}\n", nick, room),
+            // `trim_end` drops the space before an empty reason.
+            Frame::Part { nick, room, reason } => {
+                format!("{}\n", format!("PART {} {} {}", nick, room, reason).trim_end())
+            }
+            Frame::Nick { old, new } => format!("NICK {} {}\n", old, new),
+            Frame::Info(text) => format!("INFO {}\n", text),
+            Frame::Error(text) => format!("ERR {}\n", text),
+            Frame::Pong(token) => format!("PONG {}\n", token),
+            Frame::Private {
+                time,
+                from,
+                to,
+                text,
+            } => format!("PRIV {} {} {} {}\n", time, from, to, text),
+            Frame::Typing { nick, room } => format!("TYPING {} {}\n", nick, room),
+            Frame::Key { nick, key } => {
+                format!("KEY {} {}\n", nick, key.as_deref().unwrap_or("-"))
+            }
+            Frame::Topic { room, topic } => format!("TOPIC {} {}\n", room, topic),
+            Frame::Welcome {
+                server,
+                version,
+                users,
+            } => format!("WELCOME {} {} {}\n", server, version, users),
+            Frame::Motd(text) => format!("MOTD {}\n", text),
+        }
+    }
+
+    // The opposite of `to_line` - read a line received from the server.
+    // We return `Option<Frame>` because the line might be garbage:
+    // `Some(frame)` if we understood it, `None` if we didn't.
+    pub fn parse(line: &str) -> Option<Frame> {
+        // Remove the trailing "\n" (and "\r\n" on Windows).
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        // `split_once(' ')` cuts the line at the first space into two parts.
+        // If there is no space at all the whole line is the kind.
+        let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+
+        match kind {
+            "MSG" => {
+                // `splitn(5, ' ')` splits on spaces but stops after 5 pieces,
+                // so all the spaces inside the text stay where they are.
+                let mut parts = rest.splitn(5, ' ');
+                // The `?` operator means "if this is None, return None right away".
+                // `.parse().ok()?` turns the id text into a number or gives up.
+                let id = parts.next()?.parse().ok()?;
+                let time = parts.next()?.to_string();
+                let sender = parts.next()?.to_string();
+                let tags = parts.next()?;
+                let text = parts.next().unwrap_or("").to_string();
+                let mut msg = ChatMessage {
+                    id,
+                    time,
+                    sender,
+                    room: String::new(),
+                    text,
+                    edited: false,
+                    reply_to: None,
+                    origin: None,
+                    mentions: Vec::new(),
+                };
+                // Each tag is either a plain word or `key=value`.
+                // Unknown tags are simply ignored, so a newer server can add
+                // tags without breaking an older client.
+                for tag in tags.split(',') {
+                    match tag.split_once('=') {
+                        None if tag == "edited" => msg.edited = true,
+                        Some(("room", room)) => msg.room = room.to_string(),
+                        Some(("reply", parent)) => msg.reply_to = parent.parse().ok(),
+                        Some(("origin", origin)) => msg.origin = Some(origin.to_string()),
+                        Some(("mention", nick)) => msg.mentions.push(nick.to_string()),
+                        _ => {}
+                    }
+                }
+                Some(Frame::Msg(msg))
+            }
+            "EDIT" => {
+                let (id, text) = rest.split_once(' ').unwrap_or((rest, ""));
+                Some(Frame::Edit { id: id.parse().ok()?, text: text.to_string() })
+            }
+            "DEL" => Some(Frame::Delete { id: rest.parse().ok()? }),
+            "REACT" => {
+                // `split_whitespace` splits on any run of spaces.
+                let mut parts = rest.split_whitespace();
+                let id = parts.next()?.parse().ok()?;
+                let mut counts = Vec::new();
+                for part in parts {
+                    // `rsplit_once` cuts at the LAST `=`, so the emoji part
+                    // is everything before it.
+                    let (emoji, count) = part.rsplit_once('=')?;
+                    counts.push((emoji.to_string(), count.parse().ok()?));
+                }
+                Some(Frame::React { id, counts })
+            }
+            "THREAD" => {
+                let (root, count) = rest.split_once(' ')?;
+                Some(Frame::Thread {
+                    root: root.parse().ok()?,
+                    count: count.parse().ok()?,
+                })
+            }
+            "OFFER" => {
+                let mut parts = rest.splitn(5, ' ');
+                Some(Frame::Offer {
+                    xfer: parts.next()?.parse().ok()?,
+                    sender: parts.next()?.to_string(),
+                    size: parts.next()?.parse().ok()?,
+                    sha256: parts.next()?.to_string(),
+                    name: parts.next()?.to_string(),
+                })
+            }
+            "CHUNK" => {
+                let (xfer, data) = rest.split_once(' ')?;
+                Some(Frame::Chunk {
+                    xfer: xfer.parse().ok()?,
+                    data: data.to_string(),
+                })
+            }
+            "DONE" => Some(Frame::Done { xfer: rest.parse().ok()? }),
+            "UPLOADED" => {
+                let (token, xfer) = rest.split_once(' ')?;
+                Some(Frame::Uploaded {
+                    token: token.parse().ok()?,
+                    xfer: xfer.parse().ok()?,
+                })
+            }
+            "REJECT" => {
+                let (token, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+                Some(Frame::Reject {
+                    token: token.parse().ok()?,
+                    reason: reason.to_string(),
+                })
+            }
+            "JOIN" => {
+                let (nick, room) = rest.split_once(' ')?;
+                Some(Frame::Join {
+                    nick: nick.to_string(),
+                    room: room.to_string(),
+                })
+            }
+            "PART" => {
+                let mut parts = rest.splitn(3, ' ');
+                Some(Frame::Part {
+                    nick: parts.next()?.to_string(),
+                    room: parts.next()?.to_string(),
+                    reason: parts.next().unwrap_or("").to_string(),
+                })
+            }
+            "NICK" => {
+                let (old, new) = rest.split_once(' ')?;
+                Some(Frame::Nick {
+                    old: old.to_string(),
+                    new: new.to_string(),
+                })
+            }
+            "INFO" => Some(Frame::Info(rest.to_string())),
+            "ERR" => Some(Frame::Error(rest.to_string())),
+            "PONG" => Some(Frame::Pong(rest.to_string())),
+            "PRIV" => {
+                let mut parts = rest.splitn(4, ' ');
+                Some(Frame::Private {
+                    time: parts.next()?.to_string(),
+                    from: parts.next()?.to_string(),
+                    to: parts.next()?.to_string(),
+                    text: parts.next().unwrap_or("").to_string(),
+                })
+            }
+            "TYPING" => {
+                let (nick, room) = rest.split_once(' ')?;
+                Some(Frame::Typing {
+                    nick: nick.to_string(),
+                    room: room.to_string(),
+                })
+            }
+            "KEY" => {
+                let (nick, key) = rest.split_once(' ')?;
+                Some(Frame::Key {
+                    nick: nick.to_string(),
+                    key: (key != "-").then(|| key.to_string()),
+                })
+            }
+            "WELCOME" => {
+                let mut parts = rest.splitn(3, ' ');
+                Some(Frame::Welcome {
+                    server: parts.next()?.to_string(),
+                    version: parts.next()?.to_string(),
+                    users: parts.next()?.parse().ok()?,
+                })
+            }
+            // An empty line of the message of the day is just "MOTD".
+            "MOTD" => Some(Frame::Motd(rest.to_string())),
+            "TOPIC" => {
+                let (room, topic) = rest.split_once(' ')?;
+                Some(Frame::Topic {
+                    room: room.to_string(),
+                    topic: topic.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+// The nicks a message mentions with `@nick`, in the order they first appear.
+// Punctuation right after the nick doesn't count: "thanks @bob!" mentions "bob".
+pub fn mentions(text: &str) -> Vec<String> {
+    let mut nicks: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        let Some(nick) = word.strip_prefix('@') else {
+            continue;
+        };
+        let nick = nick.trim_end_matches(|c: char| !c.is_alphanumeric() && c != '_' && c != '-');
+        if !nick.is_empty() && !nicks.iter().any(|n| n == nick) {
+            nicks.push(nick.to_string());
+        }
+    }
+    nicks
+}
+
+// The 64 characters base64 uses, in order. Each one stands for 6 bits.
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Files are made of raw bytes, but our protocol can only carry lines of text.
+// Base64 turns any bytes into plain letters, digits, `+` and `/` by taking
+// 3 bytes (24 bits) at a time and writing them as 4 characters of 6 bits each.
+// The result is about a third bigger than the input, but it's safe to send.
+pub fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    // `chunks(3)` walks the bytes 3 at a time; the last piece may be shorter.
+    for chunk in bytes.chunks(3) {
+        // Glue up to 3 bytes into one 24 bit number, missing bytes count as 0.
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        // Cut it into four 6 bit pieces. `=` fills in for missing bytes.
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (n >> (18 - 6 * i)) & 0b11_1111;
+                out.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// The opposite of `base64_encode`. Returns None if the text isn't valid base64.
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    // Every 4 characters make 3 bytes, and a last group of 2 or 3 characters
+    // makes 1 or 2. A single character left over can't be a whole byte.
+    if text.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    // We collect bits in `buffer` and take a byte out every time we have 8.
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in text.bytes() {
+        // `position` finds where this character is in the alphabet - that's its value.
+        let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+        buffer = buffer << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+// Return the current time as an RFC 3339 string, e.g. "2026-10-18T09:41:07Z".
+pub fn now_rfc3339() -> String {
+    // `duration_since` can only fail if the clock is set before 1970.
+    // In that unlikely case we just fall back to 0 instead of crashing.
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format_rfc3339(secs)
+}
+
+// Format a number of seconds since 1970 as an RFC 3339 UTC timestamp.
+// The standard library doesn't know about calendars, so we convert the
+// seconds into a year, month and day ourselves.
+pub fn format_rfc3339(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+// Check that `time` is an RFC 3339 UTC timestamp written the way
+// `format_rfc3339` writes them, e.g. "2026-10-18T09:41:07Z".
+pub fn is_rfc3339(time: &str) -> bool {
+    let bytes = time.as_bytes();
+    // Read the digits from `from` up to (not including) `to` as a number.
+    let number = |from: usize, to: usize| -> Option<u32> {
+        let digits = time.get(from..to)?;
+        if digits.bytes().all(|b| b.is_ascii_digit()) {
+            digits.parse().ok()
+        } else {
+            None
+        }
+    };
+    let in_range =
+        |from, to, low, high| number(from, to).is_some_and(|n| (low..=high).contains(&n));
+    bytes.len() == 20
+        && bytes[4] == b'-'
+        && bytes[7] == b'-'
+        && bytes[10] == b'T'
+        && bytes[13] == b':'
+        && bytes[16] == b':'
+        && bytes[19] == b'Z'
+        && number(0, 4).is_some()
+        && in_range(5, 7, 1, 12)
+        && in_range(8, 10, 1, 31)
+        && in_range(11, 13, 0, 23)
+        && in_range(14, 16, 0, 59)
+        && in_range(17, 19, 0, 60)
+}
+
+// Convert "days since 1970-01-01" into (year, month, day).
+// This is Howard Hinnant's well known `civil_from_days` algorithm.
+// It works in 400 year "eras" because the Gregorian calendar repeats
+// itself exactly every 400 years - leap years included.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Tests for the hand-written parts of the protocol. Run them with `cargo test`.
+// `#[cfg(test)]` means this module is only compiled for the tests.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A message with every field filled in.
+    fn message() -> ChatMessage {
+        ChatMessage {
+            id: 42,
+            time: "2026-10-18T09:41:07Z".to_string(),
+            sender: "alice".to_string(),
+            room: "#general".to_string(),
+            text: "hello @bob, how are you?".to_string(),
+            edited: true,
+            reply_to: Some(12),
+            origin: Some("office2".to_string()),
+            mentions: vec!["bob".to_string()],
+        }
+    }
+
+    // One frame of every kind.
+    fn every_frame() -> Vec<Frame> {
+        let text = |t: &str| t.to_string();
+        vec![
+            Frame::Msg(message()),
+            Frame::Edit { id: 7, text: text("new words") },
+            Frame::Delete { id: 7 },
+            Frame::React { id: 7, counts: vec![(text("👍"), 2), (text(":+1:"), 1)] },
+            Frame::React { id: 8, counts: Vec::new() },
+            Frame::Thread { root: 3, count: 5 },
+            Frame::Offer {
+                xfer: 1,
+                sender: text("alice"),
+                size: 1024,
+                sha256: "ab".repeat(32),
+                name: text("holiday photo.jpg"),
+            },
+            Frame::Chunk { xfer: 1, data: text("aGVsbG8=") },
+            Frame::Done { xfer: 1 },
+            Frame::Uploaded { token: 9, xfer: 1 },
+            Frame::Reject { token: 9, reason: text("too big") },
+            Frame::Join { nick: text("bob"), room: text("#dev") },
+            Frame::Part { nick: text("bob"), room: text("#dev"), reason: String::new() },
+            Frame::Part { nick: text("bob"), room: text("#dev"), reason: text("banned by alice") },
+            Frame::Nick { old: text("bob"), new: text("robert") },
+            Frame::Info(text("now talking in #dev")),
+            Frame::Error(text("unknown command /nope")),
+            Frame::Pong(text("abc")),
+            Frame::Private {
+                time: text("2026-10-18T09:41:07Z"),
+                from: text("alice"),
+                to: text("bob"),
+                text: text("psst, over here"),
+            },
+            Frame::Typing { nick: text("bob"), room: text("#dev") },
+            Frame::Key { nick: text("bob"), key: Some(text("S2V5")) },
+            Frame::Key { nick: text("bob"), key: None },
+            Frame::Topic { room: text("#dev"), topic: text("release on friday") },
+            Frame::Welcome { server: text("office1"), version: text("0.1.0"), users: 3 },
+            Frame::Motd(text("be nice")),
+            Frame::Motd(String::new()),
+        ]
+    }
+
+    #[test]
+    fn every_frame_survives_to_line_and_parse() {
+        for frame in every_frame() {
+            let line = frame.to_line();
+            assert!(line.ends_with('\n'), "{:?}", line);
+            assert_eq!(line.matches('\n').count(), 1, "{:?}", line);
+            let parsed = Frame::parse(&line).unwrap_or_else(|| panic!("can't parse {:?}", line));
+            assert_eq!(parsed.to_line(), line);
+        }
+    }
+
+    #[test]
+    fn message_fields_come_back() {
+        let Some(Frame::Msg(msg)) = Frame::parse(&Frame::Msg(message()).to_line()) else {
+            panic!("not a MSG");
+        };
+        let expected = message();
+        assert_eq!(msg.id, expected.id);
+        assert_eq!(msg.time, expected.time);
+        assert_eq!(msg.sender, expected.sender);
+        assert_eq!(msg.room, expected.room);
+        assert_eq!(msg.text, expected.text);
+        assert!(msg.edited);
+        assert_eq!(msg.reply_to, Some(12));
+        assert_eq!(msg.origin.as_deref(), Some("office2"));
+        assert_eq!(msg.mentions, ["bob"]);
+    }
+
+    #[test]
+    fn message_without_tags_uses_a_dash() {
+        let mut msg = message();
+        msg.room.clear();
+        msg.edited = false;
+        msg.reply_to = None;
+        msg.origin = None;
+        msg.mentions.clear();
+        assert_eq!(
+            Frame::Msg(msg).to_line(),
+            "MSG 42 2026-10-18T09:41:07Z alice - hello @bob, how are you?\n"
+        );
+    }
+
+    #[test]
+    fn windows_line_endings_are_accepted() {
+        assert!(matches!(Frame::parse("DEL 5\r\n"), Some(Frame::Delete { id: 5 })));
+    }
+
+    #[test]
+    fn garbage_is_refused() {
+        for line in [
+            "",
+            "HELLO there",
+            "MSG",
+            "MSG x 2026-10-18T09:41:07Z alice - hi",
+            "DEL seven",
+            "REACT 1 thumbs",
+            "THREAD 1",
+            "OFFER 1 alice big sha name",
+            "JOIN bob",
+            "WELCOME office1 0.1.0 many",
+        ] {
+            assert!(Frame::parse(line).is_none(), "{:?} was accepted", line);
+        }
+    }
+
+    #[test]
+    fn base64_matches_the_standard() {
+        // The examples from RFC 4648, which cover every amount of padding.
+        let examples = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (plain, encoded) in examples {
+            assert_eq!(base64_encode(plain.as_bytes()), encoded);
+            assert_eq!(base64_decode(encoded).as_deref(), Some(plain.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn base64_round_trips_every_byte() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for length in 0..bytes.len() {
+            let encoded = base64_encode(&bytes[..length]);
+            assert_eq!(encoded.len() % 4, 0);
+            assert_eq!(base64_decode(&encoded).as_deref(), Some(&bytes[..length]));
+        }
+    }
+
+    #[test]
+    fn base64_refuses_invalid_input() {
+        for text in ["Zm9v!", "Zm 9v", "Zg==Zg==", "Z", "Zm9vY", "é"] {
+            assert_eq!(base64_decode(text), None, "{:?} was accepted", text);
+        }
+    }
+
+    #[test]
+    fn dates_around_the_epoch() {
+        assert_eq!(format_rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_rfc3339(59), "1970-01-01T00:00:59Z");
+        assert_eq!(format_rfc3339(86_399), "1970-01-01T23:59:59Z");
+        assert_eq!(format_rfc3339(86_400), "1970-01-02T00:00:00Z");
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn dates_in_leap_years() {
+        // 2024 is a leap year: February has 29 days.
+        assert_eq!(format_rfc3339(1_709_164_800), "2024-02-29T00:00:00Z");
+        assert_eq!(format_rfc3339(1_709_251_200), "2024-03-01T00:00:00Z");
+        // 2000 is divisible by 400, so it's a leap year too...
+        assert_eq!(format_rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        // ...but 2100 is divisible by 100 and not 400, so it isn't.
+        assert_eq!(format_rfc3339(4_107_456_000), "2100-02-28T00:00:00Z");
+        assert_eq!(format_rfc3339(4_107_542_400), "2100-03-01T00:00:00Z");
+        // The last second of a leap year is day 366.
+        assert_eq!(format_rfc3339(1_735_689_599), "2024-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn only_our_own_timestamps_are_rfc3339() {
+        assert!(is_rfc3339("2026-10-18T09:41:07Z"));
+        assert!(is_rfc3339(&format_rfc3339(0)));
+        assert!(!is_rfc3339("2026-10-18 09:41:07Z"));
+        assert!(!is_rfc3339("2026-13-18T09:41:07Z"));
+        assert!(!is_rfc3339("2026-10-18T09:41:07"));
+        assert!(!is_rfc3339("yesterday"));
+    }
+
+    #[test]
+    fn mentions_skip_punctuation_and_repeats() {
+        assert_eq!(mentions("thanks @bob! and @carol, @bob again"), ["bob", "carol"]);
+        assert!(mentions("email me at bob@example.com or @ alone").is_empty());
+    }
+}